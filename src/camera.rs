@@ -0,0 +1,154 @@
+use crate::geometry::{Mat3, Vec3};
+
+// Pinhole camera. In its own axes, the camera looks along +Y, with +X on the right of the screen
+// and +Z at the top, so that the identity orientation looks along the world Y axis with Z up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Camera {
+    pub position: Vec3,
+    // Rotation from the camera axes to the world axes
+    pub orientation: Mat3,
+    // Distance from the eye to the screen, in pixels
+    pub focal_length: f64,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Camera {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            position: Vec3::ZERO,
+            orientation: Mat3::IDENTITY,
+            focal_length: width as f64,
+            width,
+            height,
+        }
+    }
+
+    // (right, up, depth) of the point in the camera axes. Computed in floats, as the integer
+    // rotation would truncate nearby points.
+    pub fn view_position(&self, world: &Vec3) -> (f64, f64, f64) {
+        let gap = world.sub(&self.position);
+        let m = |i: usize| self.orientation.values[i] as f64 / self.orientation.divider as f64;
+        // The transposed orientation reverts the rotation
+        let axis = |column: usize| {
+            gap.x as f64 * m(column) + gap.y as f64 * m(3 + column) + gap.z as f64 * m(6 + column)
+        };
+        (axis(0), axis(2), axis(1))
+    }
+
+    // Pixel (column, row) of the point, row 0 being the top of the screen. None when the point is
+    // behind the camera or out of the screen.
+    pub fn project(&self, world: Vec3) -> Option<(usize, usize)> {
+        let (right, up, depth) = self.view_position(&world);
+        if depth <= 0.0 {
+            return None;
+        }
+        let x = self.width as f64 / 2.0 + right * self.focal_length / depth;
+        let y = self.height as f64 / 2.0 - up * self.focal_length / depth;
+        if x < 0.0 || y < 0.0 || x >= self.width as f64 || y >= self.height as f64 {
+            return None;
+        }
+        Some((x as usize, y as usize))
+    }
+
+    // Indices of the (id, world position) points in front of the camera, farthest first, for the
+    // nearest ones to be drawn over the others. Equally deep points are ordered by id, so that they
+    // do not flicker from a frame to the next.
+    pub fn depth_order(&self, points: &[(u64, Vec3)]) -> Vec<usize> {
+        let mut visible: Vec<(f64, u64, usize)> = points
+            .iter()
+            .enumerate()
+            .map(|(i, (id, pos))| (self.view_position(pos).2, *id, i))
+            .filter(|(depth, _, _)| *depth > 0.0)
+            .collect();
+        visible.sort_by(|(a_depth, a_id, _), (b_depth, b_id, _)| {
+            b_depth.partial_cmp(a_depth).unwrap().then(a_id.cmp(b_id))
+        });
+        visible.into_iter().map(|(_, _, i)| i).collect()
+    }
+
+    // On screen radius of a sphere at the given depth, in pixels
+    pub fn projected_radius(&self, radius: i64, depth: f64) -> f64 {
+        radius as f64 * self.focal_length / depth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Axis;
+
+    #[test]
+    fn point_straight_ahead_lands_at_the_screen_center() {
+        let mut camera = Camera::new(500, 400);
+        camera.position = Vec3 {
+            x: 100,
+            y: 100,
+            z: 100,
+        };
+        let ahead = Vec3 {
+            x: 100,
+            y: 5000,
+            z: 100,
+        };
+        assert_eq!(camera.project(ahead), Some((250, 200)));
+        let behind = Vec3 {
+            x: 100,
+            y: -5000,
+            z: 100,
+        };
+        assert_eq!(camera.project(behind), None);
+        // x goes right, z goes up the screen
+        let (x, y) = camera
+            .project(Vec3 {
+                x: 600,
+                y: 5000,
+                z: 600,
+            })
+            .unwrap();
+        assert!(x > 250 && y < 200);
+
+        camera.orientation = Mat3::rotation_from_degrees(Axis::Z, 90.0);
+        let turned_ahead = Vec3 {
+            x: -5000,
+            y: 100,
+            z: 100,
+        };
+        assert_eq!(camera.project(turned_ahead), Some((250, 200)));
+    }
+
+    #[test]
+    fn depth_order_draws_far_points_first() {
+        let camera = Camera::new(500, 400);
+        let points = [
+            (5, Vec3 { x: 0, y: 100, z: 0 }),
+            (
+                3,
+                Vec3 {
+                    x: 0,
+                    y: 1000,
+                    z: 0,
+                },
+            ),
+            (
+                9,
+                Vec3 {
+                    x: 0,
+                    y: -1000,
+                    z: 0,
+                },
+            ),
+            (
+                2,
+                Vec3 {
+                    x: 50,
+                    y: 100,
+                    z: 0,
+                },
+            ),
+            (1, Vec3 { x: 0, y: 500, z: 7 }),
+        ];
+        // The point behind the camera is left out, the equally deep ones are ordered by id
+        assert_eq!(camera.depth_order(&points), vec![1, 4, 3, 0]);
+    }
+}
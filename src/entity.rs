@@ -1,26 +1,95 @@
 use crate::{
-    geometry::{Cube, FineDirection, Sphere, Vec3, NB_QUADRANTS},
+    geometry::{
+        ContactManifold, Cube, FineDirection, Mat3, OrientedBox, Quadrant, Sphere, Vec3,
+        NB_QUADRANTS,
+    },
     matter_tree::CellPart,
+    physics::Integrable,
     player::{self, Player},
+    serialization::{Decoder, DeserializeError, Encoder},
+    voxel_grid::{GridShape, MaterialTable, VoxelGridSpace, VoxelType, VOXEL_SIZE},
 };
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_ENTITY_ID: AtomicU64 = AtomicU64::new(1);
+
+// Fixed point precision of the angular velocities
+pub const ANGLE_UNITS_PER_RADIAN: i64 = 1_000_000;
+
+#[cfg(test)]
+thread_local! {
+    // Number of collision checks run by the thread, for the tests measuring the collision work
+    pub static NB_COLLISION_CHECKS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+pub fn next_entity_id() -> u64 {
+    NEXT_ENTITY_ID.fetch_add(1, Ordering::Relaxed)
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum EntityData {
     // TODO
     Player(Rc<RefCell<Player>>),
-    Voxels(Box<crate::voxel_grid::VoxelGridSpace>),
+    Voxels(Box<VoxelGridSpace>),
+}
+
+// Radial force applied by an entity to the other entities within radius
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ForceField {
+    pub radius: i64,
+    // Positive values push entities away, negative ones pull them in
+    pub strength: f64,
+}
+
+// Last world positions of an entity, oldest first
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trajectory {
+    capacity: usize,
+    positions: VecDeque<Vec3>,
+}
+
+impl Trajectory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            positions: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn record(&mut self, pos: Vec3) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.positions.len() == self.capacity {
+            self.positions.pop_front();
+        }
+        self.positions.push_back(pos);
+        // Only moves the positions once the ring wraps, so that positions can return one slice
+        self.positions.make_contiguous();
+    }
+
+    pub fn positions(&self) -> &[Vec3] {
+        self.positions.as_slices().0
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Entity {
+    pub id: u64,
+
     // This position is relative to the quadrant containing the center of the sphere
     // TODO Build the algorithm allowing comparing entities from different scales (iteratively
     // reconstructing the distance between the 2 entities origin quadrant gap, without overflowing
     // the temporary i64s
     pub bounding_sphere: Sphere,
     pub speed: Vec3,
+    // Rotation axis, scaled by the rotation speed in ANGLE_UNITS_PER_RADIAN per tick
+    pub angular_velocity: Vec3,
+    // Rotation around the bounding sphere center, mirrored on the voxel grid orientation
+    pub orientation: Mat3,
 
     // TODO This might be a bit limited for astronomical entity if it is in kg (stars and black
     // holes...).
@@ -29,33 +98,164 @@ pub struct Entity {
     // TODO Keep bounding sphere and mass in sync with entity changes (mass changes & size changes
     // => Voxel tree growing / shrinking => changing sphere center & radius)
     pub entity: EntityData,
+    // Shape of the voxel grid, refreshed with the voxels by refresh_voxel_grid
+    grid_shape: Option<GridShape>,
+
+    pub force_field: Option<ForceField>,
+
+    // Frozen entities are left out of the physics step
+    pub frozen: bool,
+    // Static entities are part of the scenery: they never move
+    pub is_static: bool,
+    // Sleeping entities skip the movement step until a contact wakes them up
+    pub sleeping: bool,
+
+    // Contacts involving higher priority entities (heavy or structural ones) are resolved first
+    // within a tick, so that lighter entities settle against them afterwards
+    pub collision_priority: u8,
+
+    // Overrides the color the renderer gives to this type of entity
+    pub render_color: Option<u32>,
 
     // Temporary values
+    // Forces of the current tick. Every subsystem (control, force fields, gravity, code running
+    // between two ticks...) only adds to them through accumulate_force. The movement step is the
+    // only place consuming them, and resets them right after, which starts the next tick.
     pub external_forces: Vec3,
+
+    // Debugging: number of cell changes since the entity last moved significantly, and the position
+    // it was at back then
+    pub nb_migrations: u32,
+    pub migration_origin: Vec3,
+    // Opt-in record of the world positions at the end of the last ticks
+    pub trajectory: Option<Trajectory>,
 }
 
 impl Entity {
     pub fn new(bounding_sphere: Sphere, entity: EntityData) -> Self {
+        let (orientation, grid_shape) = match &entity {
+            EntityData::Voxels(grid) => (grid.orientation, Some(grid.shape())),
+            _ => (Mat3::IDENTITY, None),
+        };
         // TODO Get the entity mass
         Self {
+            id: next_entity_id(),
             bounding_sphere,
             speed: Vec3::ZERO,
+            angular_velocity: Vec3::ZERO,
+            orientation,
             mass: 0.0,
             entity,
+            grid_shape,
+            force_field: None,
+            frozen: false,
+            is_static: false,
+            sleeping: false,
+            collision_priority: 0,
+            render_color: None,
             external_forces: Vec3::ZERO,
+            nb_migrations: 0,
+            migration_origin: bounding_sphere.center,
+            trajectory: None,
         }
     }
 
+    // pos is the position of the grid origin
+    pub fn new_voxels(pos: Vec3, mut grid: VoxelGridSpace, materials: &MaterialTable) -> Self {
+        let bounding_sphere = grid.tight_bounding_sphere().add_to_center(&pos);
+        let mass = grid.mass(materials);
+        grid.mass_dirty = false;
+        let mut entity = Self::new(bounding_sphere, EntityData::Voxels(Box::new(grid)));
+        entity.mass = mass;
+        entity
+    }
+
     pub fn new_player(pos: Vec3, player: Rc<RefCell<Player>>) -> Self {
         Self {
+            id: next_entity_id(),
             bounding_sphere: Sphere {
                 center: pos,
                 radius: player::RADIUS,
             },
             speed: Vec3::ZERO,
+            angular_velocity: Vec3::ZERO,
+            orientation: Mat3::IDENTITY,
             mass: player::MASS,
             entity: EntityData::Player(player),
+            grid_shape: None,
+            force_field: None,
+            frozen: false,
+            is_static: false,
+            sleeping: false,
+            collision_priority: 0,
+            render_color: None,
             external_forces: Vec3::ZERO,
+            nb_migrations: 0,
+            migration_origin: pos,
+            trajectory: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityBuilder {
+    pos: Vec3,
+    radius: i64,
+    mass: f64,
+    speed: Vec3,
+    grid: VoxelGridSpace,
+}
+
+impl EntityBuilder {
+    pub fn at(mut self, pos: Vec3) -> Self {
+        self.pos = pos;
+        self
+    }
+
+    pub fn radius(mut self, radius: i64) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    pub fn mass(mut self, mass: f64) -> Self {
+        self.mass = mass;
+        self
+    }
+
+    pub fn speed(mut self, speed: Vec3) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn voxels(mut self, grid: VoxelGridSpace) -> Self {
+        self.grid = grid;
+        self
+    }
+
+    // The given mass is taken as is, whatever the voxels
+    pub fn build(mut self) -> Entity {
+        self.grid.mass_dirty = false;
+        let mut entity = Entity::new(
+            Sphere {
+                center: self.pos,
+                radius: self.radius,
+            },
+            EntityData::Voxels(Box::new(self.grid)),
+        );
+        entity.mass = self.mass;
+        entity.speed = self.speed;
+        entity
+    }
+}
+
+impl Entity {
+    pub fn builder() -> EntityBuilder {
+        EntityBuilder {
+            pos: Vec3::ZERO,
+            radius: 1,
+            mass: 0.0,
+            speed: Vec3::ZERO,
+            grid: VoxelGridSpace::new(),
         }
     }
 }
@@ -72,36 +272,58 @@ impl Entity {
         let relative_sphere_center = self.bounding_sphere.center.sub(&area_center);
         let radius = self.bounding_sphere.radius;
         // Early exit
-        if relative_sphere_center.is_inside_centered_cube(area_size - radius) {
+        if relative_sphere_center.is_inside_centered_cube(area_size - 2 * radius) {
             return vec![];
         }
 
-        // TODO
-        vec![]
+        // Check the sphere against each of the 26 neighbour cells, of the same size as the area
+        let mut ret = vec![];
+        for x in 0..3 {
+            for y in 0..3 {
+                for z in 0..3 {
+                    if (x, y, z) == (1, 1, 1) {
+                        continue;
+                    }
+                    let neighbour = Cube {
+                        origin: area.origin.add(&Vec3 {
+                            x: (x - 1) * area_size,
+                            y: (y - 1) * area_size,
+                            z: (z - 1) * area_size,
+                        }),
+                        size: area_size,
+                    };
+                    if neighbour.intersects_sphere(&self.bounding_sphere) {
+                        let direction = (x * 3 * 3 + y * 3 + z) as u8;
+                        ret.push(num::FromPrimitive::from_u8(direction).unwrap());
+                    }
+                }
+            }
+        }
+        ret
     }
 
-    pub fn get_containing_cell_part(&self, area: &Cube) -> CellPart {
-        let half_size = Vec3 {
-            x: area.size / 2,
-            y: area.size / 2,
-            z: area.size / 2,
-        };
-        let area_center = area.origin.add(&half_size);
-        let area_size = area.size;
-        let relative_sphere = self.bounding_sphere.sub_to_center(&area_center);
-        if !relative_sphere.center.is_inside_centered_cube(area_size) {
+    // fit_margin is the slack kept with the quadrant borders, see
+    // MatterTreeConfig::quadrant_fit_margin
+    pub fn get_containing_cell_part(&self, area: &Cube, fit_margin: i64) -> CellPart {
+        if !area.contains(&self.bounding_sphere.center) {
             return CellPart::CenterOutside;
         }
+        let relative_sphere = self.bounding_sphere.sub_to_center(&area.center());
         if !relative_sphere
             .center
-            .is_inside_centered_cube(area_size - relative_sphere.radius)
+            .is_inside_centered_cube(area.size - 2 * relative_sphere.radius)
         {
             return CellPart::PartlyOutside;
         }
 
+        let fit_sphere = Sphere {
+            center: relative_sphere.center,
+            radius: relative_sphere.radius + fit_margin,
+        };
         for i in 0..NB_QUADRANTS {
-            if relative_sphere.is_inside_quadrant(area, i) {
-                return CellPart::Quadrant(num::FromPrimitive::from_usize(i).unwrap());
+            let quadrant = num::FromPrimitive::from_usize(i).unwrap();
+            if fit_sphere.is_inside_quadrant(area.size, quadrant) {
+                return CellPart::Quadrant(quadrant);
             }
         }
         CellPart::MultiQuadrant
@@ -117,17 +339,26 @@ impl Entity {
         let area_size = area.size;
         let relative_sphere_center = self.bounding_sphere.center.sub(&area_center);
         let radius = self.bounding_sphere.radius;
+        // Quadrant centers are a quarter of the area size away from its center on each axis
+        let quarter_size = area_size / 4;
+        let axis_shift = |positive: bool| {
+            if positive {
+                quarter_size
+            } else {
+                -quarter_size
+            }
+        };
         let mut ret = vec![];
         for i in 0..NB_QUADRANTS {
+            let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
             let shift = Vec3 {
-                x: (i & (1 << 2)) as i64,
-                y: (i & (1 << 2)) as i64,
-                z: (i & (1 << 2)) as i64,
-            }
-            .mul_scalar(area_size)
-            .sub(&half_size);
+                x: axis_shift(quadrant.x_p()),
+                y: axis_shift(quadrant.y_p()),
+                z: axis_shift(quadrant.z_p()),
+            };
             let shifted_center = relative_sphere_center.sub(&shift);
-            if shifted_center.is_inside_centered_cube(area_size / 2 + radius) {
+            // The box around the sphere overlaps the quadrant
+            if shifted_center.is_inside_centered_cube(area_size / 2 + 2 * radius) {
                 ret.push(i as u8);
             }
         }
@@ -139,79 +370,1154 @@ impl Entity {
             .bounding_sphere
             .center
             .sub(&direction.mul_scalar(cell_size));
+        self.migration_origin = self.migration_origin.sub(&direction.mul_scalar(cell_size));
+    }
+
+    // Position of the voxel grid origin relative to the entity center. None for other entities.
+    pub fn grid_origin(&self) -> Option<Vec3> {
+        self.grid_shape
+            .map(|shape| Vec3::ZERO.sub(&self.orientation.mul_vec(&shape.center)))
+    }
+
+    // Fits the bounding sphere to the voxels after an edit, so that the entity lands in the right
+    // matter node. The grid shape from before the edit locates the grid origin, and is refreshed.
+    pub fn refresh_bounding_sphere(&mut self) {
+        let grid_origin = match self.grid_origin() {
+            Some(grid_origin) => self.bounding_sphere.center.add(&grid_origin),
+            None => return,
+        };
+        if let EntityData::Voxels(grid) = &self.entity {
+            self.bounding_sphere = grid.tight_bounding_sphere().add_to_center(&grid_origin);
+            self.grid_shape = Some(grid.shape());
+        }
+    }
+
+    // Keeps the bounding sphere, shape and mass in sync with the voxel grid after an edit
+    pub fn refresh_voxel_grid(&mut self, materials: &MaterialTable) {
+        self.refresh_bounding_sphere();
+        if let EntityData::Voxels(grid) = &mut self.entity {
+            self.mass = grid.mass(materials);
+            grid.mass_dirty = false;
+        }
+    }
+
+    // Debug builds check that voxel edits were followed by a refresh_voxel_grid
+    fn debug_assert_fresh_mass(&self) {
+        if let EntityData::Voxels(grid) = &self.entity {
+            debug_assert!(
+                !grid.mass_dirty,
+                "Entity {} voxels changed without refreshing its shape and mass",
+                self.id
+            );
+        }
+    }
+
+    // Moves within a cell of the smallest size, min_size, do not count as real moves
+    pub fn record_migration(&mut self, min_size: i64) {
+        let moved = self.bounding_sphere.center.sub(&self.migration_origin);
+        if !moved.is_inside_centered_cube(min_size) {
+            self.nb_migrations = 0;
+            self.migration_origin = self.bounding_sphere.center;
+        }
+        self.nb_migrations += 1;
+    }
+}
+
+impl Integrable for Entity {
+    fn position(&self) -> Vec3 {
+        self.bounding_sphere.center
+    }
+
+    fn velocity(&self) -> Vec3 {
+        self.speed
+    }
+
+    fn integrate(&mut self, dt: f64) {
+        self.bounding_sphere.move_by(&self.speed.mul_float(dt));
+        self.speed = self
+            .speed
+            .add(&self.external_forces.mul_float(dt * self.inverse_mass()));
+        self.reset_forces();
+        self.integrate_rotation(dt);
+        // println!(
+        //     "Entity: mass = {} | speed = {:?} | pos = {:?} | forces = {:?}",
+        //     self.mass, self.speed, self.bounding_sphere.center, self.external_forces
+        // );
     }
 }
 
 // Physics
 impl Entity {
-    pub fn run_movement(&mut self) {
-        let force_add = match &self.entity {
+    // Fixed divider the orientation is brought back to after each rotation step, so that the
+    // composed matrices do not overflow
+    pub const ORIENTATION_DIVIDER: i64 = 1 << 20;
+
+    // Largest distance an embedded entity is pushed by per collision check
+    pub const EJECTION_STEP: i64 = VOXEL_SIZE / 2;
+
+    // Forces are accumulated during the tick and only consumed by integrate
+    pub fn accumulate_force(&mut self, force: &Vec3) {
+        self.external_forces = self.external_forces.add(force);
+    }
+
+    pub fn reset_forces(&mut self) {
+        self.external_forces = Vec3::ZERO;
+    }
+
+    // The bounding sphere does not depend on the rotation, only the voxel grid follows it
+    fn integrate_rotation(&mut self, dt: f64) {
+        if self.angular_velocity == Vec3::ZERO {
+            return;
+        }
+        let radians = self.angular_velocity.length_f64() * dt / ANGLE_UNITS_PER_RADIAN as f64;
+        let step = Mat3::rotation_from_axis_angle(
+            &self.angular_velocity,
+            radians,
+            Self::ORIENTATION_DIVIDER,
+        );
+        // The angular velocity is in world axes, so the step applies after the current orientation
+        self.orientation = step
+            .mul_mat(&self.orientation)
+            .with_divider(Self::ORIENTATION_DIVIDER);
+        if let EntityData::Voxels(grid) = &mut self.entity {
+            grid.orientation = self.orientation;
+        }
+    }
+
+    // Gives a random rotation of at most max_rate radians per tick. The same seed always gives the
+    // same rotation.
+    pub fn apply_random_tumble(&mut self, seed: u64, max_rate: f64) {
+        // SplitMix64
+        let mut state = seed;
+        let mut next_unit = || {
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^= z >> 31;
+            (z >> 11) as f64 / (1u64 << 53) as f64
+        };
+
+        // Uniform direction, by rejecting the points of the cube out of the unit sphere
+        let axis = loop {
+            let axis = [
+                next_unit() * 2.0 - 1.0,
+                next_unit() * 2.0 - 1.0,
+                next_unit() * 2.0 - 1.0,
+            ];
+            let length = (axis[0] * axis[0] + axis[1] * axis[1] + axis[2] * axis[2]).sqrt();
+            if length > 1e-6 && length <= 1.0 {
+                break [axis[0] / length, axis[1] / length, axis[2] / length];
+            }
+        };
+        // Truncating toward 0 keeps the rate below max_rate
+        let rate = next_unit() * max_rate * ANGLE_UNITS_PER_RADIAN as f64;
+        self.angular_velocity = Vec3 {
+            x: (axis[0] * rate) as i64,
+            y: (axis[1] * rate) as i64,
+            z: (axis[2] * rate) as i64,
+        };
+    }
+
+    pub fn accumulate_control_forces(&mut self) {
+        let control_forces = match &self.entity {
             EntityData::Player(player) => player.borrow().control_forces,
             EntityData::Voxels(_) => Vec3::ZERO,
         };
-        self.external_forces = self.external_forces.add(&force_add);
+        self.accumulate_force(&control_forces);
+    }
 
-        self.bounding_sphere.move_by(&self.speed);
-        if self.mass != 0.0 {
-            self.speed = self.speed.add(&self.external_forces.div_float(self.mass));
+    pub fn run_movement(&mut self, dt: f64) {
+        self.debug_assert_fresh_mass();
+        if self.frozen || self.is_static || self.sleeping {
+            self.reset_forces();
+            return;
         }
-        self.external_forces = Vec3::ZERO;
-        // println!(
-        //     "Entity: mass = {} | speed = {:?} | pos = {:?} | forces = {:?} | force_add: {:?}",
-        //     self.mass, self.speed, self.bounding_sphere.center, self.external_forces, force_add
-        // );
+        self.accumulate_control_forces();
+        debug_assert!(
+            self.bounding_sphere
+                .center
+                .checked_add(&self.speed.mul_float(dt))
+                .is_some(),
+            "Entity {} position overflows: {:?} moving by {:?}",
+            self.id,
+            self.bounding_sphere.center,
+            self.speed
+        );
+        self.integrate(dt);
     }
 
-    pub fn check_collision(&self, other: &mut Self) -> bool {
-        // TODO
-        true
+    // Both entities are expected to be in the same matter node, their positions being relative to it
+    pub fn check_collision(&self, other: &Self) -> bool {
+        #[cfg(test)]
+        NB_COLLISION_CHECKS.with(|nb_checks| nb_checks.set(nb_checks.get() + 1));
+        self.bounding_sphere.intersects(&other.bounding_sphere)
     }
 
-    pub fn bounce(&mut self, other: &mut Self) {
-        let inter_center = self
+    // World box of a voxel entity
+    fn oriented_box(&self) -> Option<OrientedBox> {
+        let grid_origin = self.bounding_sphere.center.add(&self.grid_origin()?);
+        let mut oriented_box = self.grid_shape?.oriented_box(&self.orientation)?;
+        oriented_box.center = oriented_box.center.add(&grid_origin);
+        Some(oriented_box)
+    }
+
+    // Corners of the box of a voxel entity, rotated by its orientation, indexed like
+    // OrientedBox::corners. None for other entities and empty grids.
+    pub fn get_corners(&self) -> Option<[Vec3; NB_QUADRANTS]> {
+        self.oriented_box()
+            .map(|oriented_box| oriented_box.corners())
+    }
+
+    // Axis aligned cube around the entity: the min corner is its origin, and its size is the
+    // largest extent over the axes. Voxel entities fit their rotated box, others their bounding
+    // sphere.
+    pub fn aabb(&self) -> Cube {
+        let corners = match self.get_corners() {
+            Some(corners) => corners,
+            None => {
+                let radius = self.bounding_sphere.radius;
+                return Cube {
+                    origin: self.bounding_sphere.center.sub(&Vec3 {
+                        x: radius,
+                        y: radius,
+                        z: radius,
+                    }),
+                    size: 2 * radius,
+                };
+            }
+        };
+        let mut min = corners[0];
+        let mut max = corners[0];
+        for corner in corners.iter() {
+            min = Vec3 {
+                x: i64::min(min.x, corner.x),
+                y: i64::min(min.y, corner.y),
+                z: i64::min(min.z, corner.z),
+            };
+            max = Vec3 {
+                x: i64::max(max.x, corner.x),
+                y: i64::max(max.y, corner.y),
+                z: i64::max(max.z, corner.z),
+            };
+        }
+        let extent = max.sub(&min);
+        Cube {
+            origin: min,
+            size: i64::max(extent.x, i64::max(extent.y, extent.z)),
+        }
+    }
+
+    // The bounding spheres filter out far away entities, then voxel grids are tested as boxes
+    pub fn contact_manifold(&self, other: &Self) -> Option<ContactManifold> {
+        let manifold = self
             .bounding_sphere
-            .center
-            .sub(&other.bounding_sphere.center);
-        let inter_center_length = inter_center.length_f64();
-        let self_inter_speed_value = self.speed.dot_f64(&inter_center) / inter_center_length;
-        let other_inter_speed_value = other.speed.dot_f64(&inter_center) / inter_center_length;
-        let total_inter_momentum =
-            self_inter_speed_value * self.mass + other_inter_speed_value * other.mass;
-        let total_mass = self.mass + other.mass;
-
-        let self_resulting_momentum = total_inter_momentum * self.mass / total_mass;
-        let other_resulting_momentum = total_inter_momentum * other.mass / total_mass;
-
-        let self_resulting_inter_speed = inter_center
-            .mul_scalar((self_resulting_momentum / self.mass) as i64)
-            .div_scalar(inter_center_length as i64);
-        let other_resulting_inter_speed = inter_center
-            .mul_scalar((other_resulting_momentum / other.mass) as i64)
-            .div_scalar(inter_center_length as i64);
-
-        let self_inter_speed = inter_center
-            .mul_scalar(self_inter_speed_value as i64)
-            .div_scalar(inter_center_length as i64);
-        let other_inter_speed = inter_center
-            .mul_scalar(-other_inter_speed_value as i64)
-            .div_scalar(inter_center_length as i64);
+            .contact_manifold(&other.bounding_sphere)?;
+        match (self.oriented_box(), other.oriented_box()) {
+            (Some(self_box), Some(other_box)) => self_box.contact_manifold(&other_box),
+            _ => Some(manifold),
+        }
+    }
+
+    // 0 for the entities nothing can push: frozen, static, or with a null or infinite mass. Every
+    // impulse goes through it, so that these states combine the same way.
+    pub fn inverse_mass(&self) -> f64 {
+        if self.frozen || self.is_static || self.mass == 0.0 || self.mass.is_infinite() {
+            0.0
+        } else {
+            1.0 / self.mass
+        }
+    }
+
+    // Elastic bounce along the contact normal
+    pub fn bounce(&mut self, other: &mut Self, manifold: &ContactManifold) {
+        let normal = manifold.normal;
+        let normal_length = normal.length_f64();
+        if normal_length == 0.0 {
+            return;
+        }
+        let self_normal_speed = self.speed.dot_f64(&normal) / normal_length;
+        let other_normal_speed = other.speed.dot_f64(&normal) / normal_length;
+        // Already moving apart
+        if other_normal_speed >= self_normal_speed {
+            return;
+        }
+
+        let self_inverse_mass = self.inverse_mass();
+        let other_inverse_mass = other.inverse_mass();
+        if self_inverse_mass == 0.0 && other_inverse_mass == 0.0 {
+            return;
+        }
+        let impulse = 2.0 * (self_normal_speed - other_normal_speed)
+            / (self_inverse_mass + other_inverse_mass);
+        let self_new_speed = self_normal_speed - impulse * self_inverse_mass;
+        let other_new_speed = other_normal_speed + impulse * other_inverse_mass;
 
         self.speed = self
             .speed
-            .sub(&self_inter_speed)
-            .add(&self_resulting_inter_speed);
+            .reject_from_f64(&normal)
+            .add(&normal.mul_float(self_new_speed / normal_length));
         other.speed = other
             .speed
-            .sub(&other_inter_speed)
-            .add(&other_resulting_inter_speed);
+            .reject_from_f64(&normal)
+            .add(&normal.mul_float(other_new_speed / normal_length));
     }
 
-    pub fn apply_collision(&mut self, other: &mut Self) {
+    // Pushes this entity toward the closest empty voxel when its center is inside a solid voxel of
+    // the other entity, where no contact normal makes sense. Returns whether it was embedded.
+    pub fn eject_from(&mut self, other: &Self) -> bool {
+        let (grid, grid_origin) = match (&other.entity, other.grid_origin()) {
+            (EntityData::Voxels(grid), Some(grid_origin)) => {
+                (grid, other.bounding_sphere.center.add(&grid_origin))
+            }
+            _ => return false,
+        };
+        let coord = grid.voxel_coord_at(&self.bounding_sphere.center.sub(&grid_origin));
+        if grid.get(&coord) == VoxelType::Empty {
+            return false;
+        }
+
+        let target = grid_origin.add(&grid.voxel_center(&grid.nearest_empty_voxel(&coord)));
+        let to_target = target.sub(&self.bounding_sphere.center);
+        let distance = to_target.length_f64();
+        let shift = if distance > Self::EJECTION_STEP as f64 {
+            to_target.mul_float(Self::EJECTION_STEP as f64 / distance)
+        } else {
+            to_target
+        };
+        self.bounding_sphere.move_by(&shift);
+        true
+    }
+
+    // Returns whether the entities were in contact
+    pub fn apply_collision(&mut self, other: &mut Self) -> bool {
         if !self.check_collision(other) {
-            return;
+            return false;
+        }
+        self.debug_assert_fresh_mass();
+        other.debug_assert_fresh_mass();
+        self.sleeping = false;
+        other.sleeping = false;
+
+        if (self.inverse_mass() != 0.0 && self.eject_from(other))
+            || (other.inverse_mass() != 0.0 && other.eject_from(self))
+        {
+            return true;
+        }
+
+        match self.contact_manifold(other) {
+            Some(manifold) => {
+                self.bounce(other, &manifold);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Serialization
+impl Entity {
+    pub fn encode(&self, out: &mut Encoder) {
+        out.u64(self.id);
+        out.sphere(&self.bounding_sphere);
+        out.vec3(&self.speed);
+        out.vec3(&self.angular_velocity);
+        out.mat3(&self.orientation);
+        out.f64(self.mass);
+        match &self.entity {
+            EntityData::Player(player) => {
+                let player = player.borrow();
+                out.u8(0);
+                out.vec3(&player.control_forces);
+                out.bool(player.drop_block);
+                out.bool(player.drop_block_fixed);
+                out.bool(player.mine_voxel);
+                out.bool(player.place_voxel);
+                out.mat3(&player.facing);
+            }
+            EntityData::Voxels(grid) => {
+                out.u8(1);
+                grid.encode(out);
+            }
+        }
+        match &self.force_field {
+            Some(field) => {
+                out.bool(true);
+                out.i64(field.radius);
+                out.f64(field.strength);
+            }
+            None => out.bool(false),
+        }
+        out.bool(self.frozen);
+        out.bool(self.is_static);
+        out.bool(self.sleeping);
+        out.u8(self.collision_priority);
+        match self.render_color {
+            Some(color) => {
+                out.bool(true);
+                out.u32(color);
+            }
+            None => out.bool(false),
+        }
+        out.vec3(&self.external_forces);
+        out.u32(self.nb_migrations);
+        out.vec3(&self.migration_origin);
+        match &self.trajectory {
+            Some(trajectory) => {
+                out.bool(true);
+                out.len(trajectory.capacity);
+                out.len(trajectory.positions.len());
+                for pos in trajectory.positions.iter() {
+                    out.vec3(pos);
+                }
+            }
+            None => out.bool(false),
+        }
+    }
+
+    // Entities created afterwards get ids above the decoded one
+    pub fn decode(input: &mut Decoder) -> Result<Self, DeserializeError> {
+        let id = input.u64()?;
+        NEXT_ENTITY_ID.fetch_max(id.saturating_add(1), Ordering::Relaxed);
+        let bounding_sphere = input.sphere()?;
+        let speed = input.vec3()?;
+        let angular_velocity = input.vec3()?;
+        let orientation = input.mat3()?;
+        let mass = input.f64()?;
+        let entity = match input.u8()? {
+            0 => EntityData::Player(Rc::new(RefCell::new(Player {
+                control_forces: input.vec3()?,
+                drop_block: input.bool()?,
+                drop_block_fixed: input.bool()?,
+                mine_voxel: input.bool()?,
+                place_voxel: input.bool()?,
+                facing: input.mat3()?,
+            }))),
+            1 => EntityData::Voxels(Box::new(VoxelGridSpace::decode(input)?)),
+            tag => {
+                return Err(DeserializeError::InvalidTag {
+                    what: "entity data",
+                    tag,
+                })
+            }
+        };
+        // The shape is derived from the voxels rather than saved
+        let grid_shape = match &entity {
+            EntityData::Voxels(grid) => Some(grid.shape()),
+            _ => None,
+        };
+        let force_field = if input.bool()? {
+            Some(ForceField {
+                radius: input.i64()?,
+                strength: input.f64()?,
+            })
+        } else {
+            None
+        };
+        let frozen = input.bool()?;
+        let is_static = input.bool()?;
+        let sleeping = input.bool()?;
+        let collision_priority = input.u8()?;
+        let render_color = if input.bool()? {
+            Some(input.u32()?)
+        } else {
+            None
+        };
+        let external_forces = input.vec3()?;
+        let nb_migrations = input.u32()?;
+        let migration_origin = input.vec3()?;
+        let trajectory = if input.bool()? {
+            let mut trajectory = Trajectory::new(input.len()?);
+            for _ in 0..input.len()? {
+                trajectory.record(input.vec3()?);
+            }
+            Some(trajectory)
+        } else {
+            None
+        };
+        Ok(Self {
+            id,
+            bounding_sphere,
+            speed,
+            angular_velocity,
+            orientation,
+            mass,
+            entity,
+            grid_shape,
+            force_field,
+            frozen,
+            is_static,
+            sleeping,
+            collision_priority,
+            render_color,
+            external_forces,
+            nb_migrations,
+            migration_origin,
+            trajectory,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Axis;
+    use crate::voxel_grid::{chunk_index, VoxelTree, VoxelType};
+
+    #[test]
+    fn accumulated_forces_integrate_as_their_sum() {
+        let a = Vec3 {
+            x: 300,
+            y: -40,
+            z: 0,
+        };
+        let b = Vec3 {
+            x: -100,
+            y: 200,
+            z: 50,
+        };
+        let mut split = Entity::new(
+            Sphere {
+                center: Vec3::ZERO,
+                radius: 10,
+            },
+            EntityData::Voxels(Box::default()),
+        );
+        split.mass = 2.0;
+        let mut summed = split.clone();
+        for _ in 0..3 {
+            split.accumulate_force(&a);
+            split.accumulate_force(&b);
+            summed.accumulate_force(&a.add(&b));
+            split.run_movement(1.0);
+            summed.run_movement(1.0);
+        }
+        assert_eq!(
+            split.speed,
+            Vec3 {
+                x: 300,
+                y: 240,
+                z: 75
+            }
+        );
+        assert_eq!(split.speed, summed.speed);
+        assert_eq!(split.bounding_sphere, summed.bounding_sphere);
+        assert_eq!(split.external_forces, Vec3::ZERO);
+    }
+
+    #[test]
+    fn builder_sets_each_field() {
+        let defaults = Entity::builder().build();
+        assert_eq!(
+            defaults.bounding_sphere,
+            Sphere {
+                center: Vec3::ZERO,
+                radius: 1,
+            }
+        );
+        assert_eq!(defaults.mass, 0.0);
+        assert_eq!(defaults.speed, Vec3::ZERO);
+
+        let pos = Vec3 { x: 1, y: -2, z: 3 };
+        let speed = Vec3 { x: -4, y: 5, z: 0 };
+        let mut grid = VoxelGridSpace::new();
+        if let VoxelTree::Chunk(voxels) = &mut grid.voxels {
+            voxels[chunk_index(1, 1, 1)] = VoxelType::Rock;
+        }
+        let entity = Entity::builder()
+            .at(pos)
+            .radius(42)
+            .mass(12.5)
+            .speed(speed)
+            .voxels(grid.clone())
+            .build();
+        assert_eq!(entity.bounding_sphere.center, pos);
+        assert_eq!(entity.bounding_sphere.radius, 42);
+        assert_eq!(entity.mass, 12.5);
+        assert_eq!(entity.speed, speed);
+        match &entity.entity {
+            EntityData::Voxels(built) => assert_eq!(built.voxels, grid.voxels),
+            EntityData::Player(_) => panic!("Built a player"),
+        }
+        assert_ne!(entity.id, defaults.id);
+    }
+
+    #[test]
+    fn players_and_voxel_entities_integrate_the_same() {
+        fn integrate_pushed<T: Integrable>(body: &mut T, push: impl Fn(&mut T)) {
+            for _ in 0..4 {
+                push(body);
+                body.integrate(0.5);
+            }
+        }
+
+        let pos = Vec3 {
+            x: 100,
+            y: -200,
+            z: 300,
+        };
+        let speed = Vec3 { x: 8, y: 0, z: -6 };
+        let force = Vec3 {
+            x: 400,
+            y: 200,
+            z: 0,
+        };
+        let mut player = Entity::new_player(pos, Rc::new(RefCell::new(Player::new())));
+        player.speed = speed;
+        let mut voxels = Entity::builder()
+            .at(pos)
+            .radius(player::RADIUS)
+            .mass(player::MASS)
+            .speed(speed)
+            .build();
+
+        let push = |entity: &mut Entity| entity.accumulate_force(&force);
+        integrate_pushed(&mut player, push);
+        integrate_pushed(&mut voxels, push);
+        assert_eq!(player.velocity(), voxels.velocity());
+        assert_eq!(player.position(), voxels.position());
+        assert_eq!(player.bounding_sphere, voxels.bounding_sphere);
+        assert_eq!(player.velocity(), Vec3 { x: 16, y: 4, z: -6 });
+    }
+
+    #[test]
+    fn sphere_collision_detection() {
+        let ball = |x: i64, y: i64| {
+            Entity::builder()
+                .at(Vec3 { x, y, z: 0 })
+                .radius(100)
+                .build()
+        };
+        let origin = ball(0, 0);
+        // Touching spheres are not in contact yet
+        assert!(!origin.check_collision(&ball(200, 0)));
+        assert!(!origin.check_collision(&ball(120, 160)));
+        assert!(origin.check_collision(&ball(199, 0)));
+        assert!(origin.check_collision(&ball(120, 159)));
+        assert!(origin.check_collision(&ball(0, 0)));
+        assert!(!origin.check_collision(&ball(5000, -5000)));
+
+        // Far apart entities do not overflow the squared distance
+        let far = Entity::builder()
+            .at(Vec3 {
+                x: i64::MAX / 2,
+                y: i64::MIN / 2,
+                z: 0,
+            })
+            .radius(100)
+            .build();
+        assert!(!origin.check_collision(&far));
+    }
+
+    fn momentum(entities: &[&Entity]) -> Vec3 {
+        entities
+            .iter()
+            .map(|entity| entity.speed.mul_float(entity.mass))
+            .fold(Vec3::ZERO, |sum, momentum| sum.add(&momentum))
+    }
+
+    #[test]
+    fn elastic_collisions_keep_the_momentum() {
+        let ball = |x: i64, y: i64, mass: f64, speed: Vec3| {
+            Entity::builder()
+                .at(Vec3 { x, y, z: 0 })
+                .radius(100)
+                .mass(mass)
+                .speed(speed)
+                .build()
+        };
+
+        // Head on
+        let mut a = ball(0, 0, 2.0, Vec3 { x: 300, y: 0, z: 0 });
+        let mut b = ball(
+            150,
+            0,
+            3.0,
+            Vec3 {
+                x: -100,
+                y: 0,
+                z: 0,
+            },
+        );
+        let before = momentum(&[&a, &b]);
+        assert!(a.apply_collision(&mut b));
+        assert!(momentum(&[&a, &b]).sub(&before).length_f64() <= 5.0);
+        // The speeds are rounded toward 0
+        assert!((a.speed.x + 180).abs() <= 1);
+        assert!((b.speed.x - 220).abs() <= 1);
+
+        // Glancing
+        let mut a = ball(
+            0,
+            0,
+            2.0,
+            Vec3 {
+                x: 300,
+                y: 50,
+                z: 0,
+            },
+        );
+        let mut b = ball(
+            150,
+            90,
+            3.0,
+            Vec3 {
+                x: -100,
+                y: 0,
+                z: 0,
+            },
+        );
+        let before = momentum(&[&a, &b]);
+        assert!(a.apply_collision(&mut b));
+        assert!(momentum(&[&a, &b]).sub(&before).length_f64() <= 5.0);
+        assert!(a.speed.x < 300);
+        assert!(b.speed.y > 0);
+    }
+
+    #[test]
+    fn embedded_entity_is_pushed_to_the_nearest_surface() {
+        let mut grid = VoxelGridSpace::new();
+        if let VoxelTree::Chunk(voxels) = &mut grid.voxels {
+            for x in 0..5 {
+                for y in 0..5 {
+                    for z in 0..5 {
+                        voxels[chunk_index(x, y, z)] = VoxelType::Rock;
+                    }
+                }
+            }
+        }
+        // The grid origin is at the position given to new_voxels
+        let grid_origin = Vec3::ZERO;
+        let mut wall = Entity::new_voxels(grid_origin, grid, &MaterialTable::new());
+
+        // One voxel and a half deep behind the x face, 2.5 voxels from the others
+        let mut small = Entity::builder()
+            .at(grid_origin.add(&Vec3 {
+                x: 150,
+                y: 250,
+                z: 250,
+            }))
+            .radius(10)
+            .mass(1.0)
+            .build();
+        let wall_center = wall.bounding_sphere.center;
+        for _ in 0..4 {
+            small.apply_collision(&mut wall);
+        }
+        let pos = small.bounding_sphere.center;
+        assert!(pos.x < grid_origin.x);
+        assert_eq!((pos.y, pos.z), (grid_origin.y + 250, grid_origin.z + 250));
+        assert_eq!(wall.bounding_sphere.center, wall_center);
+    }
+
+    #[test]
+    fn random_tumble_is_seeded_and_bounded() {
+        let mut a = Entity::builder().build();
+        let mut b = Entity::builder().build();
+        a.apply_random_tumble(42, 0.5);
+        b.apply_random_tumble(42, 0.5);
+        assert_eq!(a.angular_velocity, b.angular_velocity);
+        assert_ne!(a.angular_velocity, Vec3::ZERO);
+        b.apply_random_tumble(43, 0.5);
+        assert_ne!(a.angular_velocity, b.angular_velocity);
+
+        let max_rate = 0.5 * ANGLE_UNITS_PER_RADIAN as f64;
+        for seed in 0..100 {
+            a.apply_random_tumble(seed, 0.5);
+            assert!(a.angular_velocity.length_f64() <= max_rate);
+        }
+    }
+
+    // Rock entity whose grid got one more voxel, without refreshing its mass
+    fn edited_rock_entity() -> Entity {
+        let mut grid = VoxelGridSpace::new();
+        grid.set((1, 1, 1), VoxelType::Rock);
+        let mut entity = Entity::new_voxels(Vec3::ZERO, grid, &MaterialTable::new());
+        if let EntityData::Voxels(grid) = &mut entity.entity {
+            grid.set((2, 1, 1), VoxelType::Rock);
+        }
+        entity
+    }
+
+    #[test]
+    #[should_panic(expected = "voxels changed without refreshing its shape and mass")]
+    fn stale_mass_is_detected() {
+        edited_rock_entity().run_movement(1.0);
+    }
+
+    #[test]
+    fn refreshed_mass_passes_the_check() {
+        let mut entity = edited_rock_entity();
+        assert_eq!(entity.mass, 1.0);
+        entity.refresh_voxel_grid(&MaterialTable::new());
+        assert_eq!(entity.mass, 2.0);
+        entity.run_movement(1.0);
+    }
+
+    #[test]
+    fn touched_external_cells_of_a_face_an_edge_and_a_corner() {
+        let area = Cube {
+            origin: Vec3::ZERO,
+            size: 1000,
+        };
+        let touched = |x: i64, y: i64, z: i64| {
+            Entity::builder()
+                .at(Vec3 { x, y, z })
+                .radius(20)
+                .build()
+                .get_touched_external_cells(&area)
+        };
+        assert!(touched(500, 500, 500).is_empty());
+        assert!(touched(970, 500, 500).is_empty());
+        assert_eq!(touched(995, 500, 500), vec![FineDirection::XpYzZz]);
+        assert_eq!(
+            touched(995, 995, 500),
+            vec![
+                FineDirection::XzYpZz,
+                FineDirection::XpYzZz,
+                FineDirection::XpYpZz
+            ]
+        );
+        assert_eq!(
+            touched(5, 995, 995),
+            vec![
+                FineDirection::XnYzZz,
+                FineDirection::XnYzZp,
+                FineDirection::XnYpZz,
+                FineDirection::XnYpZp,
+                FineDirection::XzYzZp,
+                FineDirection::XzYpZz,
+                FineDirection::XzYpZp
+            ]
+        );
+    }
+
+    #[test]
+    fn voxel_entity_places_its_grid_origin_at_its_position() {
+        let pos = Vec3 {
+            x: 1000,
+            y: -2000,
+            z: 3000,
+        };
+        let mut grid = VoxelGridSpace::new();
+        grid.set((3, 1, 2), VoxelType::Rock);
+        let entity = Entity::new_voxels(pos, grid, &MaterialTable::new());
+
+        if let EntityData::Voxels(grid) = &entity.entity {
+            let grid_origin = entity
+                .bounding_sphere
+                .center
+                .sub(&grid.tight_bounding_sphere().center);
+            assert_eq!(grid_origin, pos);
+            let voxel = Vec3 { x: 3, y: 1, z: 2 };
+            let world = grid_origin.add(&grid.voxel_center(&voxel));
+            assert!(entity.bounding_sphere.contains(&world));
+            assert_eq!(grid.voxel_coord_at(&world.sub(&grid_origin)), voxel);
+        } else {
+            panic!("not a voxel entity");
+        }
+    }
+
+    #[test]
+    fn immovable_targets_bounce_movers_and_sleepers_wake() {
+        let mover = || {
+            Entity::builder()
+                .at(Vec3::ZERO)
+                .radius(100)
+                .mass(1.0)
+                .speed(Vec3 { x: 10, y: 0, z: 0 })
+                .build()
+        };
+        let target = || {
+            Entity::builder()
+                .at(Vec3 { x: 150, y: 0, z: 0 })
+                .radius(100)
+                .mass(1.0)
+                .build()
+        };
+        let mut frozen = target();
+        frozen.frozen = true;
+        let mut fixed = target();
+        fixed.is_static = true;
+        let mut heavy = target();
+        heavy.mass = f64::INFINITY;
+        for target in [frozen, fixed, heavy].iter_mut() {
+            let mut mover = mover();
+            assert_eq!(target.inverse_mass(), 0.0);
+            mover.apply_collision(target);
+            assert_eq!(mover.speed, Vec3 { x: -10, y: 0, z: 0 });
+            assert_eq!(target.speed, Vec3::ZERO);
+            assert_eq!(target.bounding_sphere.center.x, 150);
+        }
+
+        // A sleeping entity is a regular one once hit
+        let mut sleeper = target();
+        sleeper.sleeping = true;
+        let mut mover = mover();
+        mover.apply_collision(&mut sleeper);
+        assert!(!sleeper.sleeping);
+        assert_eq!(mover.speed, Vec3::ZERO);
+        assert_eq!(sleeper.speed, Vec3 { x: 10, y: 0, z: 0 });
+    }
+
+    #[test]
+    fn bounding_sphere_tightens_around_the_remaining_corner_voxel() {
+        let mut grid = VoxelGridSpace::new();
+        grid.set((0, 0, 0), VoxelType::Rock);
+        grid.set((9, 9, 9), VoxelType::Rock);
+        let pos = Vec3 {
+            x: 5000,
+            y: 0,
+            z: 0,
+        };
+        let mut entity = Entity::new_voxels(pos, grid, &MaterialTable::new());
+        let before = entity.bounding_sphere;
+
+        if let EntityData::Voxels(grid) = &mut entity.entity {
+            grid.set((9, 9, 9), VoxelType::Empty);
+        }
+        entity.refresh_bounding_sphere();
+
+        // Only the voxel at the grid origin corner is left
+        let half_voxel = VOXEL_SIZE / 2;
+        assert!(entity.bounding_sphere.radius < before.radius / 5);
+        assert_eq!(
+            entity.bounding_sphere.center,
+            pos.add(&Vec3 {
+                x: half_voxel,
+                y: half_voxel,
+                z: half_voxel,
+            })
+        );
+        if let EntityData::Voxels(grid) = &entity.entity {
+            let grid_origin = entity
+                .bounding_sphere
+                .center
+                .sub(&grid.tight_bounding_sphere().center);
+            assert_eq!(grid_origin, pos);
+        }
+    }
+
+    #[test]
+    fn angular_velocity_turns_the_orientation_deterministically() {
+        // A quarter turn around z over 10 ticks
+        let quarter_turn_per_10_ticks =
+            (std::f64::consts::FRAC_PI_2 / 10.0 * ANGLE_UNITS_PER_RADIAN as f64) as i64;
+        let spinning = || {
+            let mut entity = Entity::builder().radius(10).mass(1.0).build();
+            entity.angular_velocity = Vec3 {
+                x: 0,
+                y: 0,
+                z: quarter_turn_per_10_ticks,
+            };
+            entity
+        };
+        let (mut a, mut b) = (spinning(), spinning());
+        for _ in 0..10 {
+            a.run_movement(1.0);
+            b.run_movement(1.0);
+        }
+        assert_eq!(a.orientation, b.orientation);
+        assert_eq!(a.bounding_sphere.center, Vec3::ZERO);
+
+        let turned = a.orientation.mul_vec(&Vec3 {
+            x: 1000,
+            y: 0,
+            z: 0,
+        });
+        assert!(turned.x.abs() <= 2 && (turned.y - 1000).abs() <= 2);
+        if let EntityData::Voxels(grid) = &a.entity {
+            assert_eq!(grid.orientation, a.orientation);
+        } else {
+            panic!("not a voxel entity");
         }
+    }
+
+    #[test]
+    fn collisioned_quadrants_follow_each_axis_bit() {
+        let area = Cube {
+            origin: Vec3 {
+                x: -1000,
+                y: -1000,
+                z: -1000,
+            },
+            size: 2000,
+        };
+        let quadrants = |x, y, z| {
+            Entity::builder()
+                .at(Vec3 { x, y, z })
+                .radius(100)
+                .build()
+                .get_collisioned_quadrants(&area)
+        };
+        assert_eq!(quadrants(500, 500, 500), vec![Quadrant::XpYpZp as u8]);
+        assert_eq!(quadrants(-500, 500, -500), vec![Quadrant::XnYpZn as u8]);
+        // Straddling the y = 0 plane
+        assert_eq!(
+            quadrants(500, 50, 500),
+            vec![Quadrant::XpYnZp as u8, Quadrant::XpYpZp as u8]
+        );
+    }
+
+    #[test]
+    fn collisioned_quadrants_come_in_quadrant_order() {
+        let area = Cube {
+            origin: Vec3::ZERO,
+            size: 1024,
+        };
+        let entity = |pos: Vec3, radius: i64| Entity::builder().at(pos).radius(radius).build();
+
+        let center = entity(area.center(), 10);
+        let all: Vec<_> = (0..NB_QUADRANTS as u8).collect();
+        assert_eq!(center.get_collisioned_quadrants(&area), all);
+
+        // On the x = 512 plane, in the +y+z quarter
+        let straddling = entity(
+            Vec3 {
+                x: 512,
+                y: 768,
+                z: 768,
+            },
+            10,
+        );
+        let expected = vec![Quadrant::XnYpZp as u8, Quadrant::XpYpZp as u8];
+        for _ in 0..10 {
+            assert_eq!(straddling.get_collisioned_quadrants(&area), expected);
+        }
+    }
+
+    #[test]
+    fn entity_in_each_quadrant_is_localized_there() {
+        let area = Cube {
+            origin: Vec3 {
+                x: -1024,
+                y: -1024,
+                z: -1024,
+            },
+            size: 2048,
+        };
+        for i in 0..NB_QUADRANTS {
+            let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+            let pos = quadrant.direction_vec().mul_scalar(512);
+            assert_eq!(Quadrant::from_pos(&pos), quadrant);
+            let entity = Entity::builder().at(pos).radius(100).build();
+            assert_eq!(
+                entity.get_containing_cell_part(&area, 4),
+                CellPart::Quadrant(quadrant)
+            );
+            assert_eq!(entity.get_collisioned_quadrants(&area), vec![i as u8]);
+        }
+    }
+
+    fn sorted_corners(entity: &Entity) -> Vec<Vec3> {
+        let mut corners = entity.get_corners().unwrap().to_vec();
+        corners.sort_by_key(|corner| (corner.x, corner.y, corner.z));
+        corners
+    }
+
+    fn box_corners(xs: [i64; 2], ys: [i64; 2], zs: [i64; 2]) -> Vec<Vec3> {
+        let mut corners = vec![];
+        for &x in xs.iter() {
+            for &y in ys.iter() {
+                for &z in zs.iter() {
+                    corners.push(Vec3 { x, y, z });
+                }
+            }
+        }
+        corners
+    }
+
+    #[test]
+    fn corners_of_a_two_voxel_box() {
+        let mut grid = VoxelGridSpace::new();
+        grid.set((0, 0, 0), VoxelType::Rock);
+        grid.set((1, 0, 0), VoxelType::Rock);
+        let pos = Vec3 {
+            x: 1000,
+            y: 0,
+            z: 0,
+        };
+        let entity = Entity::new_voxels(pos, grid.clone(), &MaterialTable::new());
+        let corners = entity.get_corners().unwrap();
+        // Indexed like the quadrants: the first one is the min corner, the last one the max corner
+        assert_eq!(corners[0], pos);
+        assert_eq!(
+            corners[NB_QUADRANTS - 1],
+            Vec3 {
+                x: 1200,
+                y: 100,
+                z: 100,
+            }
+        );
+        assert_eq!(
+            sorted_corners(&entity),
+            box_corners([1000, 1200], [0, 100], [0, 100])
+        );
+
+        grid.orientation = Mat3::rotation_from_degrees(Axis::Z, 90.0);
+        let entity = Entity::new_voxels(pos, grid, &MaterialTable::new());
+        assert_eq!(
+            sorted_corners(&entity),
+            box_corners([900, 1000], [0, 200], [0, 100])
+        );
+
+        assert!(Entity::builder().build().get_corners().is_none());
+    }
+
+    #[test]
+    fn aabb_grows_with_the_rotation() {
+        let mut grid = VoxelGridSpace::new();
+        grid.set((0, 0, 0), VoxelType::Rock);
+        let pos = Vec3 {
+            x: 1000,
+            y: 0,
+            z: 0,
+        };
+        let entity = Entity::new_voxels(pos, grid.clone(), &MaterialTable::new());
+        assert_eq!(
+            entity.aabb(),
+            Cube {
+                origin: pos,
+                size: VOXEL_SIZE,
+            }
+        );
+
+        // A voxel turned by 45° spans its diagonal
+        grid.orientation = Mat3::rotation_from_degrees(Axis::Z, 45.0);
+        let entity = Entity::new_voxels(pos, grid, &MaterialTable::new());
+        let diagonal = (VOXEL_SIZE as f64 * 2f64.sqrt()) as i64;
+        assert!((entity.aabb().size - diagonal).abs() <= 2);
+
+        let sphere = Entity::builder()
+            .at(Vec3 {
+                x: 10,
+                y: 20,
+                z: 30,
+            })
+            .radius(5)
+            .build();
+        assert_eq!(
+            sphere.aabb(),
+            Cube {
+                origin: Vec3 { x: 5, y: 15, z: 25 },
+                size: 10,
+            }
+        );
+    }
 
-        // TODO
-        self.bounce(other);
+    #[test]
+    #[should_panic(expected = "position overflows")]
+    fn movement_past_the_coordinate_limit_is_caught() {
+        let mut entity = Entity::builder()
+            .at(Vec3 {
+                x: i64::MAX - 10,
+                y: 0,
+                z: 0,
+            })
+            .radius(1)
+            .mass(1.0)
+            .speed(Vec3 { x: 100, y: 0, z: 0 })
+            .build();
+        entity.run_movement(1.0);
     }
 }
@@ -1,4 +1,6 @@
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+use std::convert::TryFrom;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Vec3 {
     pub x: i64,
     pub y: i64,
@@ -32,6 +34,31 @@ impl Vec3 {
         }
     }
 
+    pub fn checked_add(&self, other: &Vec3) -> Option<Self> {
+        Some(Self {
+            x: self.x.checked_add(other.x)?,
+            y: self.y.checked_add(other.y)?,
+            z: self.z.checked_add(other.z)?,
+        })
+    }
+
+    pub fn checked_mul_scalar(&self, v: i64) -> Option<Self> {
+        Some(Self {
+            x: self.x.checked_mul(v)?,
+            y: self.y.checked_mul(v)?,
+            z: self.z.checked_mul(v)?,
+        })
+    }
+
+    // Each component is clamped to the i64 range
+    pub fn saturating_add(&self, other: &Vec3) -> Self {
+        Self {
+            x: self.x.saturating_add(other.x),
+            y: self.y.saturating_add(other.y),
+            z: self.z.saturating_add(other.z),
+        }
+    }
+
     pub fn div_scalar(&self, v: i64) -> Self {
         Self {
             x: self.x / v,
@@ -40,6 +67,14 @@ impl Vec3 {
         }
     }
 
+    pub fn mul_float(&self, v: f64) -> Self {
+        Self {
+            x: (self.x as f64 * v) as i64,
+            y: (self.y as f64 * v) as i64,
+            z: (self.z as f64 * v) as i64,
+        }
+    }
+
     pub fn div_float(&self, v: f64) -> Self {
         Self {
             x: (self.x as f64 / v) as i64,
@@ -48,12 +83,60 @@ impl Vec3 {
         }
     }
 
+    pub fn lerp(a: &Vec3, b: &Vec3, t: f64) -> Vec3 {
+        let lerp = |a: i64, b: i64| (a as f64 + (b - a) as f64 * t).round() as i64;
+        Vec3 {
+            x: lerp(a.x, b.x),
+            y: lerp(a.y, b.y),
+            z: lerp(a.z, b.z),
+        }
+    }
+
     pub fn dot_f64(&self, other: &Self) -> f64 {
         self.x as f64 * other.x as f64
             + self.y as f64 * other.y as f64
             + self.z as f64 * other.z as f64
     }
 
+    // Each component is the difference of two products of components, so it only fits in an i64 if
+    // the components stay below about 2^31 (see checked_cross)
+    pub fn cross(&self, other: &Self) -> Self {
+        Self {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    pub fn checked_cross(&self, other: &Self) -> Option<Self> {
+        let component =
+            |a: i64, b: i64, c: i64, d: i64| a.checked_mul(b)?.checked_sub(c.checked_mul(d)?);
+        Some(Self {
+            x: component(self.y, other.z, self.z, other.y)?,
+            y: component(self.z, other.x, self.x, other.z)?,
+            z: component(self.x, other.y, self.y, other.x)?,
+        })
+    }
+
+    // Component along dir
+    pub fn project_onto_f64(&self, dir: &Vec3) -> Self {
+        let dir_length_sq = dir.dot_f64(dir);
+        if dir_length_sq == 0.0 {
+            return Self::ZERO;
+        }
+        let ratio = self.dot_f64(dir) / dir_length_sq;
+        Self {
+            x: (dir.x as f64 * ratio).round() as i64,
+            y: (dir.y as f64 * ratio).round() as i64,
+            z: (dir.z as f64 * ratio).round() as i64,
+        }
+    }
+
+    // Component perpendicular to dir
+    pub fn reject_from_f64(&self, dir: &Vec3) -> Self {
+        self.sub(&self.project_onto_f64(dir))
+    }
+
     pub fn is_inside_centered_cube(&self, side_length: i64) -> bool {
         let min = -side_length / 2;
         let max = side_length / 2 - 1;
@@ -86,6 +169,14 @@ impl Vec3 {
         ret
     }
 
+    // Exact, as i128 cannot overflow for any i64 coordinates
+    pub fn length_sq(&self) -> i128 {
+        let x = self.x as i128;
+        let y = self.y as i128;
+        let z = self.z as i128;
+        x * x + y * y + z * z
+    }
+
     pub fn length_f64(&self) -> f64 {
         let x = self.x as f64;
         let y = self.y as f64;
@@ -111,6 +202,23 @@ impl Vec3 {
     }
 }
 
+// Exponential moving average: alpha is the weight of each new sample, in [0, 1]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Vec3Ema {
+    pub value: Vec3,
+    pub alpha: f64,
+}
+
+impl Vec3Ema {
+    pub fn new(value: Vec3, alpha: f64) -> Self {
+        Self { value, alpha }
+    }
+
+    pub fn update(&mut self, sample: Vec3) {
+        self.value = Vec3::lerp(&self.value, &sample, self.alpha);
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Mat3 {
     pub divider: i64,
@@ -133,6 +241,269 @@ impl Mat3 {
                 / self.divider,
         }
     }
+
+    // Composes the transformations: other is applied first. The result is reduced so that
+    // composing many rotations does not overflow.
+    pub fn mul_mat(&self, other: &Mat3) -> Mat3 {
+        let mut values = [0; 9];
+        for row in 0..3 {
+            for column in 0..3 {
+                values[row * 3 + column] = (0..3)
+                    .map(|k| self.values[row * 3 + k] * other.values[k * 3 + column])
+                    .sum();
+            }
+        }
+        Self {
+            divider: self.divider * other.divider,
+            values,
+        }
+        .reduced()
+    }
+
+    // Same matrix, with values and divider divided by their GCD
+    fn reduced(&self) -> Self {
+        let gcd = self
+            .values
+            .iter()
+            .fold(self.divider, |gcd, value| num::integer::gcd(gcd, *value));
+        if gcd <= 1 {
+            return *self;
+        }
+        let mut values = self.values;
+        for value in values.iter_mut() {
+            *value /= gcd;
+        }
+        Self {
+            divider: self.divider / gcd,
+            values,
+        }
+    }
+
+    // Quaternion (w, x, y, z) of this rotation, each component scaled to the i16 range. Meant for
+    // network sync and saves.
+    pub fn to_quantized_quat(self) -> [i16; 4] {
+        let m =
+            |row: usize, column: usize| self.values[row * 3 + column] as f64 / self.divider as f64;
+        let trace = m(0, 0) + m(1, 1) + m(2, 2);
+        // Derive from the largest component to keep the divisions precise
+        let quat = if trace > 0.0 {
+            let s = f64::sqrt(trace + 1.0) * 2.0;
+            [
+                s / 4.0,
+                (m(2, 1) - m(1, 2)) / s,
+                (m(0, 2) - m(2, 0)) / s,
+                (m(1, 0) - m(0, 1)) / s,
+            ]
+        } else if m(0, 0) > m(1, 1) && m(0, 0) > m(2, 2) {
+            let s = f64::sqrt(1.0 + m(0, 0) - m(1, 1) - m(2, 2)) * 2.0;
+            [
+                (m(2, 1) - m(1, 2)) / s,
+                s / 4.0,
+                (m(0, 1) + m(1, 0)) / s,
+                (m(0, 2) + m(2, 0)) / s,
+            ]
+        } else if m(1, 1) > m(2, 2) {
+            let s = f64::sqrt(1.0 + m(1, 1) - m(0, 0) - m(2, 2)) * 2.0;
+            [
+                (m(0, 2) - m(2, 0)) / s,
+                (m(0, 1) + m(1, 0)) / s,
+                s / 4.0,
+                (m(1, 2) + m(2, 1)) / s,
+            ]
+        } else {
+            let s = f64::sqrt(1.0 + m(2, 2) - m(0, 0) - m(1, 1)) * 2.0;
+            [
+                (m(1, 0) - m(0, 1)) / s,
+                (m(0, 2) + m(2, 0)) / s,
+                (m(1, 2) + m(2, 1)) / s,
+                s / 4.0,
+            ]
+        };
+        let length = quat.iter().map(|v| v * v).sum::<f64>().sqrt();
+        let mut ret = [0; 4];
+        for (quantized, v) in ret.iter_mut().zip(quat.iter()) {
+            *quantized = (v / length * i16::MAX as f64).round() as i16;
+        }
+        ret
+    }
+
+    // Rotation matrix with a divider of 1000
+    pub fn from_quantized_quat(quat: [i16; 4]) -> Self {
+        let length = quat
+            .iter()
+            .map(|v| *v as f64 * *v as f64)
+            .sum::<f64>()
+            .sqrt();
+        if length == 0.0 {
+            return Self::IDENTITY;
+        }
+        let w = quat[0] as f64 / length;
+        let x = quat[1] as f64 / length;
+        let y = quat[2] as f64 / length;
+        let z = quat[3] as f64 / length;
+        let rotation = [
+            1.0 - 2.0 * (y * y + z * z),
+            2.0 * (x * y - w * z),
+            2.0 * (x * z + w * y),
+            2.0 * (x * y + w * z),
+            1.0 - 2.0 * (x * x + z * z),
+            2.0 * (y * z - w * x),
+            2.0 * (x * z - w * y),
+            2.0 * (y * z + w * x),
+            1.0 - 2.0 * (x * x + y * y),
+        ];
+        let mut values = [0; 9];
+        for (value, r) in values.iter_mut().zip(rotation.iter()) {
+            *value = (r * 1000.0).round() as i64;
+        }
+        Self {
+            divider: 1000,
+            values,
+        }
+    }
+
+    // Rotations from a (sin, cos, divider) triple, sin and cos being sin_num / divider and
+    // cos_num / divider. The triple should satisfy sin_num² + cos_num² ≈ divider² (ideally a
+    // Pythagorean triple such as (3, 4, 5)) or the matrix also scales what it rotates.
+    pub fn rotation_x(sin_num: i64, cos_num: i64, divider: i64) -> Self {
+        Self {
+            divider,
+            values: [divider, 0, 0, 0, cos_num, -sin_num, 0, sin_num, cos_num],
+        }
+    }
+
+    pub fn rotation_y(sin_num: i64, cos_num: i64, divider: i64) -> Self {
+        Self {
+            divider,
+            values: [cos_num, 0, sin_num, 0, divider, 0, -sin_num, 0, cos_num],
+        }
+    }
+
+    pub fn rotation_z(sin_num: i64, cos_num: i64, divider: i64) -> Self {
+        Self {
+            divider,
+            values: [cos_num, -sin_num, 0, sin_num, cos_num, 0, 0, 0, divider],
+        }
+    }
+
+    // Counterclockwise rotation around the axis, with sin and cos rounded to a fixed divider.
+    // Multiples of 90° are exact.
+    pub fn rotation_from_degrees(axis: Axis, deg: f64) -> Self {
+        const DIVIDER: i64 = 1 << 16;
+        let radians = deg.to_radians();
+        let sin_num = (radians.sin() * DIVIDER as f64).round() as i64;
+        let cos_num = (radians.cos() * DIVIDER as f64).round() as i64;
+        match axis {
+            Axis::X => Self::rotation_x(sin_num, cos_num, DIVIDER),
+            Axis::Y => Self::rotation_y(sin_num, cos_num, DIVIDER),
+            Axis::Z => Self::rotation_z(sin_num, cos_num, DIVIDER),
+        }
+        .reduced()
+    }
+
+    // Counterclockwise rotation of the given angle around the axis (which does not need to be
+    // normalized), rounded to the divider. Rodrigues' formula.
+    pub fn rotation_from_axis_angle(axis: &Vec3, radians: f64, divider: i64) -> Self {
+        let length = axis.length_f64();
+        if length == 0.0 {
+            return Self::IDENTITY;
+        }
+        let x = axis.x as f64 / length;
+        let y = axis.y as f64 / length;
+        let z = axis.z as f64 / length;
+        let (sin, cos) = radians.sin_cos();
+        let t = 1.0 - cos;
+        let rotation = [
+            t * x * x + cos,
+            t * x * y - sin * z,
+            t * x * z + sin * y,
+            t * x * y + sin * z,
+            t * y * y + cos,
+            t * y * z - sin * x,
+            t * x * z - sin * y,
+            t * y * z + sin * x,
+            t * z * z + cos,
+        ];
+        let mut values = [0; 9];
+        for (value, r) in values.iter_mut().zip(rotation.iter()) {
+            *value = (r * divider as f64).round() as i64;
+        }
+        Self { divider, values }
+    }
+
+    // Same transformation expressed over another divider, rounding the values to the nearest
+    pub fn with_divider(&self, divider: i64) -> Self {
+        let mut values = self.values;
+        for value in values.iter_mut() {
+            let scaled = *value as i128 * divider as i128;
+            let old = self.divider as i128;
+            // Round half away from zero
+            let rounded = (2 * scaled + scaled.signum() * old.abs()) / (2 * old);
+            *value = rounded as i64;
+        }
+        Self { divider, values }
+    }
+
+    // Inverse of a rotation matrix
+    pub fn transpose(&self) -> Self {
+        let v = &self.values;
+        Self {
+            divider: self.divider,
+            values: [v[0], v[3], v[6], v[1], v[4], v[7], v[2], v[5], v[8]],
+        }
+    }
+
+    // General inverse, from the adjugate over the determinant. Computed in i128, so that the
+    // products do not overflow. None for singular matrices, or when the inverse does not fit in
+    // i64 values.
+    pub fn inverse(&self) -> Option<Self> {
+        let v = |i: usize| self.values[i] as i128;
+        let cofactor =
+            |a: usize, b: usize, c: usize, d: usize| (v(a) * v(b)).checked_sub(v(c) * v(d));
+        let adjugate = [
+            cofactor(4, 8, 5, 7)?,
+            cofactor(2, 7, 1, 8)?,
+            cofactor(1, 5, 2, 4)?,
+            cofactor(5, 6, 3, 8)?,
+            cofactor(0, 8, 2, 6)?,
+            cofactor(2, 3, 0, 5)?,
+            cofactor(3, 7, 4, 6)?,
+            cofactor(1, 6, 0, 7)?,
+            cofactor(0, 4, 1, 3)?,
+        ];
+        let determinant = v(0)
+            .checked_mul(adjugate[0])?
+            .checked_add(v(1).checked_mul(adjugate[3])?)?
+            .checked_add(v(2).checked_mul(adjugate[6])?)?;
+        if determinant == 0 {
+            return None;
+        }
+        // (values / divider)⁻¹ = divider * adjugate / determinant
+        let sign = determinant.signum();
+        let mut values = [0; 9];
+        for (value, a) in values.iter_mut().zip(adjugate.iter()) {
+            *value = (sign * a).checked_mul(self.divider as i128)?;
+        }
+        let divider = sign * determinant;
+        let gcd = values
+            .iter()
+            .fold(divider, |gcd, value| num::integer::gcd(gcd, *value));
+        let mut reduced = [0; 9];
+        for (reduced, value) in reduced.iter_mut().zip(values.iter()) {
+            *reduced = i64::try_from(value / gcd).ok()?;
+        }
+        Some(Self {
+            divider: i64::try_from(divider / gcd).ok()?,
+            values: reduced,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -205,10 +576,16 @@ impl FineDirection {
         Vec3 { x, y, z }
     }
 
+    // Inverse of equivalent_vec. Each component must be -1, 0 or 1.
+    pub fn from_vec(vec: &Vec3) -> Self {
+        let val = (vec.x + 1) * 3 * 3 + (vec.y + 1) * 3 + (vec.z + 1);
+        num::FromPrimitive::from_i64(val).unwrap()
+    }
+
     pub fn equivalent_vec(&self) -> Vec3 {
         let mut val = *self as i64;
-        let x = val / 3 - 1;
-        val %= 3;
+        let x = val / (3 * 3) - 1;
+        val %= 3 * 3;
         let y = val / 3 - 1;
         val %= 3;
         let z = val - 1;
@@ -242,6 +619,15 @@ impl Quadrant {
         *self as usize & (1 << 0) != 0
     }
 
+    // Unit vector pointing from the parent center towards the quadrant
+    pub fn direction_vec(&self) -> Vec3 {
+        Vec3 {
+            x: if self.x_p() { 1 } else { -1 },
+            y: if self.y_p() { 1 } else { -1 },
+            z: if self.z_p() { 1 } else { -1 },
+        }
+    }
+
     pub fn from_pos(pos: &Vec3) -> Self {
         let val = (pos.x >= 0) as usize * (1 << 2)
             + (pos.y >= 0) as usize * (1 << 1)
@@ -308,14 +694,14 @@ impl Sphere {
 
     pub fn add_to_center(&self, v: &Vec3) -> Self {
         Self {
-            center: self.center.add(&v),
+            center: self.center.add(v),
             radius: self.radius,
         }
     }
 
     pub fn sub_to_center(&self, v: &Vec3) -> Self {
         Self {
-            center: self.center.sub(&v),
+            center: self.center.sub(v),
             radius: self.radius,
         }
     }
@@ -324,29 +710,210 @@ impl Sphere {
         self.center = self.center.add(shift);
     }
 
-    pub fn is_inside_quadrant(&self, cell_area: &Cube, quadrant: usize) -> bool {
-        let half_size = cell_area.size / 2;
-        let quarter_size = half_size / 2;
-        let quarter_vec = Vec3 {
-            x: quarter_size,
-            y: quarter_size,
-            z: quarter_size,
+    // The sphere must be relative to the cell center, so only the cell size matters
+    pub fn is_inside_quadrant(&self, cell_size: i64, quadrant: Quadrant) -> bool {
+        let half_size = cell_size / 2;
+        let centered_area = Cube {
+            origin: Vec3 {
+                x: -half_size,
+                y: -half_size,
+                z: -half_size,
+            },
+            size: cell_size,
         };
-        let shift = Vec3 {
-            x: (quadrant as i64 & (1 << 2) != 0) as i64,
-            y: (quadrant as i64 & (1 << 1) != 0) as i64,
-            z: (quadrant as i64 & (1 << 0) != 0) as i64,
-        }
-        .mul_scalar(half_size)
-        .sub(&quarter_vec);
-        let shifted_center = self.center.sub(&shift);
-        shifted_center.is_inside_centered_cube(half_size - self.radius)
+        let quadrant_area = centered_area.quadrant(quadrant);
+        let shifted_center = self.center.sub(&quadrant_area.center());
+        shifted_center.is_inside_centered_cube(half_size - 2 * self.radius)
+    }
+
+    // Points on the surface are contained
+    pub fn contains(&self, point: &Vec3) -> bool {
+        point.sub(&self.center).length_sq() <= (self.radius as i128).pow(2)
     }
 
     pub fn intersects(&self, other: &Sphere) -> bool {
-        let dist = self.center.sub(&other.center).length_f64();
-        let limit_dist = self.radius + other.radius;
-        dist < limit_dist as f64
+        let limit_dist = self.radius as i128 + other.radius as i128;
+        self.center.sub(&other.center).length_sq() < limit_dist * limit_dist
+    }
+
+    // Distance along the ray at which it enters the sphere (0 if the origin is already inside)
+    pub fn ray_entry_distance(&self, origin: &Vec3, dir: &Vec3) -> Option<f64> {
+        let dir_length = dir.length_f64();
+        if dir_length == 0.0 {
+            return None;
+        }
+        let to_center = self.center.sub(origin);
+        let closest_approach = to_center.dot_f64(dir) / dir_length;
+        let closest_dist_sq = to_center.dot_f64(&to_center) - closest_approach * closest_approach;
+        let radius_sq = (self.radius as f64) * (self.radius as f64);
+        if closest_dist_sq > radius_sq {
+            return None;
+        }
+        let half_chord = (radius_sq - closest_dist_sq).sqrt();
+        if closest_approach + half_chord < 0.0 {
+            return None;
+        }
+        Some(f64::max(0.0, closest_approach - half_chord))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ContactManifold {
+    pub point: Vec3,
+    // Not normalized: this is the line of centers, pointing from the first sphere to the second
+    pub normal: Vec3,
+    pub penetration: f64,
+}
+
+impl Sphere {
+    pub fn contact_manifold(&self, other: &Sphere) -> Option<ContactManifold> {
+        let normal = other.center.sub(&self.center);
+        let dist = normal.length_f64();
+        let penetration = (self.radius + other.radius) as f64 - dist;
+        if penetration <= 0.0 {
+            return None;
+        }
+
+        // Middle of the overlapping section of the line of centers
+        let point = if dist == 0.0 {
+            self.center
+        } else {
+            let depth = self.radius as f64 - penetration / 2.0;
+            self.center.add(&normal.mul_float(depth / dist))
+        };
+        Some(ContactManifold {
+            point,
+            normal,
+            penetration,
+        })
+    }
+}
+
+// Box rotated by its orientation: its local axes are the orientation columns
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OrientedBox {
+    pub center: Vec3,
+    pub half_extents: Vec3,
+    pub orientation: Mat3,
+}
+
+type AxisVector = [f64; 3];
+
+fn axis_dot(a: &AxisVector, b: &AxisVector) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn axis_cross(a: &AxisVector, b: &AxisVector) -> AxisVector {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+impl OrientedBox {
+    // Indexed like the quadrants: each axis bit of the index selects the upper side of the box on
+    // that axis
+    pub fn corners(&self) -> [Vec3; NB_QUADRANTS] {
+        let mut corners = [Vec3::ZERO; NB_QUADRANTS];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+            let side = |positive: bool, half_extent: i64| {
+                if positive {
+                    half_extent
+                } else {
+                    -half_extent
+                }
+            };
+            let local = Vec3 {
+                x: side(quadrant.x_p(), self.half_extents.x),
+                y: side(quadrant.y_p(), self.half_extents.y),
+                z: side(quadrant.z_p(), self.half_extents.z),
+            };
+            *corner = self.center.add(&self.orientation.mul_vec(&local));
+        }
+        corners
+    }
+
+    fn axes(&self) -> [AxisVector; 3] {
+        let m = &self.orientation;
+        let column = |i: usize| {
+            let axis = [
+                m.values[i] as f64,
+                m.values[3 + i] as f64,
+                m.values[6 + i] as f64,
+            ];
+            let length = axis_dot(&axis, &axis).sqrt();
+            [axis[0] / length, axis[1] / length, axis[2] / length]
+        };
+        [column(0), column(1), column(2)]
+    }
+
+    // Half length of the projection of the box on a normalized axis
+    fn projected_radius(&self, axes: &[AxisVector; 3], axis: &AxisVector) -> f64 {
+        self.half_extents.x as f64 * axis_dot(&axes[0], axis).abs()
+            + self.half_extents.y as f64 * axis_dot(&axes[1], axis).abs()
+            + self.half_extents.z as f64 * axis_dot(&axes[2], axis).abs()
+    }
+
+    // Separating axis test. The contact normal is the axis of least penetration, pointing from this
+    // box to the other one. Its length is the sum of both boxes projected radii on it.
+    pub fn contact_manifold(&self, other: &OrientedBox) -> Option<ContactManifold> {
+        let self_axes = self.axes();
+        let other_axes = other.axes();
+        let gap = other.center.sub(&self.center);
+        let gap = [gap.x as f64, gap.y as f64, gap.z as f64];
+
+        let mut candidates = vec![];
+        candidates.extend_from_slice(&self_axes);
+        candidates.extend_from_slice(&other_axes);
+        for a in self_axes.iter() {
+            for b in other_axes.iter() {
+                candidates.push(axis_cross(a, b));
+            }
+        }
+
+        let mut best: Option<(AxisVector, f64, f64)> = None;
+        for axis in candidates.into_iter() {
+            let length = axis_dot(&axis, &axis).sqrt();
+            // Cross products of parallel axes are already covered by the face axes
+            if length < 1e-9 {
+                continue;
+            }
+            let axis = [axis[0] / length, axis[1] / length, axis[2] / length];
+            let radii = self.projected_radius(&self_axes, &axis)
+                + other.projected_radius(&other_axes, &axis);
+            let distance = axis_dot(&gap, &axis);
+            let penetration = radii - distance.abs();
+            if penetration <= 0.0 {
+                return None;
+            }
+            if best.is_none_or(|(_, best_penetration, _)| penetration < best_penetration) {
+                let axis = if distance < 0.0 {
+                    [-axis[0], -axis[1], -axis[2]]
+                } else {
+                    axis
+                };
+                best = Some((axis, penetration, radii));
+            }
+        }
+
+        let (axis, penetration, radii) = best?;
+        let self_radius = self.projected_radius(&self_axes, &axis);
+        let to_contact = self_radius - penetration / 2.0;
+        Some(ContactManifold {
+            point: self.center.add(&Vec3 {
+                x: (axis[0] * to_contact).round() as i64,
+                y: (axis[1] * to_contact).round() as i64,
+                z: (axis[2] * to_contact).round() as i64,
+            }),
+            normal: Vec3 {
+                x: (axis[0] * radii).round() as i64,
+                y: (axis[1] * radii).round() as i64,
+                z: (axis[2] * radii).round() as i64,
+            },
+            penetration,
+        })
     }
 }
 
@@ -355,3 +922,665 @@ pub struct Cube {
     pub origin: Vec3,
     pub size: i64,
 }
+
+impl Cube {
+    pub fn center(&self) -> Vec3 {
+        let half_size = self.size / 2;
+        self.origin.add(&Vec3 {
+            x: half_size,
+            y: half_size,
+            z: half_size,
+        })
+    }
+
+    pub fn quadrant(&self, quadrant: Quadrant) -> Cube {
+        let size = self.size / 2;
+        Cube {
+            origin: Vec3 {
+                x: self.origin.x + quadrant.x_p() as i64 * size,
+                y: self.origin.y + quadrant.y_p() as i64 * size,
+                z: self.origin.z + quadrant.z_p() as i64 * size,
+            },
+            size,
+        }
+    }
+
+    pub fn subdivide(&self) -> [Cube; NB_QUADRANTS] {
+        let mut ret = [*self; NB_QUADRANTS];
+        for (i, cube) in ret.iter_mut().enumerate() {
+            *cube = self.quadrant(num::FromPrimitive::from_usize(i).unwrap());
+        }
+        ret
+    }
+
+    // The lower faces are part of the cube, the upper ones are not
+    pub fn contains(&self, point: &Vec3) -> bool {
+        let axis_contains = |pos: i64, min: i64| pos >= min && pos < min + self.size;
+        axis_contains(point.x, self.origin.x)
+            && axis_contains(point.y, self.origin.y)
+            && axis_contains(point.z, self.origin.z)
+    }
+
+    // Whether the point of the cube closest to the sphere center is inside the sphere
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        let axis_gap = |pos: i64, min: i64| {
+            let closest = i64::max(min, i64::min(pos, min + self.size));
+            (pos - closest) as f64
+        };
+        let x = axis_gap(sphere.center.x, self.origin.x);
+        let y = axis_gap(sphere.center.y, self.origin.y);
+        let z = axis_gap(sphere.center.z, self.origin.z);
+        let radius = sphere.radius as f64;
+        x * x + y * y + z * z < radius * radius
+    }
+
+    // Distance along the ray at which it enters the cube (0 if the origin is already inside), using
+    // the slab method
+    pub fn ray_entry_distance(&self, origin: &Vec3, dir: &Vec3) -> Option<f64> {
+        let dir_length = dir.length_f64();
+        if dir_length == 0.0 {
+            return None;
+        }
+        let mut enter = 0.0;
+        let mut exit = f64::INFINITY;
+        let axes = [
+            (origin.x, dir.x, self.origin.x),
+            (origin.y, dir.y, self.origin.y),
+            (origin.z, dir.z, self.origin.z),
+        ];
+        for (pos, dir, min) in axes.iter() {
+            let max = min + self.size;
+            if *dir == 0 {
+                if pos < min || *pos >= max {
+                    return None;
+                }
+            } else {
+                let speed = *dir as f64 / dir_length;
+                let a = (min - pos) as f64 / speed;
+                let b = (max - pos) as f64 / speed;
+                enter = f64::max(enter, f64::min(a, b));
+                exit = f64::min(exit, f64::max(a, b));
+            }
+        }
+        if enter > exit {
+            None
+        } else {
+            Some(enter)
+        }
+    }
+
+    // Distance to the closest point of the cube, whose upper faces are at origin + size as in
+    // intersects_sphere
+    pub fn distance_f64(&self, point: &Vec3) -> f64 {
+        let axis_distance = |pos: i64, min: i64| {
+            if pos < min {
+                (min - pos) as f64
+            } else if pos > min + self.size {
+                (pos - (min + self.size)) as f64
+            } else {
+                0.0
+            }
+        };
+        let x = axis_distance(point.x, self.origin.x);
+        let y = axis_distance(point.y, self.origin.y);
+        let z = axis_distance(point.z, self.origin.z);
+        f64::sqrt(x * x + y * y + z * z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sphere_contact_manifold() {
+        let a = Sphere {
+            center: Vec3::ZERO,
+            radius: 60,
+        };
+        let b = Sphere {
+            center: Vec3 { x: 100, y: 0, z: 0 },
+            radius: 50,
+        };
+        let manifold = a.contact_manifold(&b).unwrap();
+        assert_eq!(manifold.penetration, 10.0);
+        assert_eq!(manifold.normal, Vec3 { x: 100, y: 0, z: 0 });
+        // Middle of the overlap, between 50 and 60
+        assert_eq!(manifold.point, Vec3 { x: 55, y: 0, z: 0 });
+
+        let reversed = b.contact_manifold(&a).unwrap();
+        assert_eq!(
+            reversed.normal,
+            Vec3 {
+                x: -100,
+                y: 0,
+                z: 0
+            }
+        );
+        assert_eq!(reversed.penetration, 10.0);
+
+        let far = Sphere {
+            center: Vec3 { x: 110, y: 0, z: 0 },
+            radius: 50,
+        };
+        assert_eq!(a.contact_manifold(&far), None);
+    }
+
+    fn cube_contains(cube: &Cube, point: &Vec3) -> bool {
+        let axis_contains = |pos: i64, min: i64| pos >= min && pos < min + cube.size;
+        axis_contains(point.x, cube.origin.x)
+            && axis_contains(point.y, cube.origin.y)
+            && axis_contains(point.z, cube.origin.z)
+    }
+
+    #[test]
+    fn subdivide_tiles_the_parent() {
+        let parent = Cube {
+            origin: Vec3 { x: -8, y: 4, z: 0 },
+            size: 8,
+        };
+        let children = parent.subdivide();
+        for (i, child) in children.iter().enumerate() {
+            let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+            assert_eq!(*child, parent.quadrant(quadrant));
+            assert_eq!(child.size, 4);
+        }
+        // Every point of the parent is in exactly one child, and the children hold no other point
+        for x in -10..2 {
+            for y in 2..14 {
+                for z in -2..10 {
+                    let point = Vec3 { x, y, z };
+                    let nb_containing = children
+                        .iter()
+                        .filter(|child| cube_contains(child, &point))
+                        .count();
+                    let expected = if cube_contains(&parent, &point) { 1 } else { 0 };
+                    assert_eq!(nb_containing, expected, "{:?}", point);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lerp_and_ema() {
+        let a = Vec3 {
+            x: -100,
+            y: 40,
+            z: 7,
+        };
+        let b = Vec3 {
+            x: 300,
+            y: -60,
+            z: 7,
+        };
+        assert_eq!(Vec3::lerp(&a, &b, 0.0), a);
+        assert_eq!(Vec3::lerp(&a, &b, 1.0), b);
+        assert_eq!(
+            Vec3::lerp(&a, &b, 0.5),
+            Vec3 {
+                x: 100,
+                y: -10,
+                z: 7
+            }
+        );
+
+        let target = Vec3 {
+            x: 1000,
+            y: -1000,
+            z: 0,
+        };
+        let mut ema = Vec3Ema::new(Vec3::ZERO, 0.25);
+        let mut last_distance = f64::INFINITY;
+        for _ in 0..50 {
+            ema.update(target);
+            let distance = ema.value.sub(&target).length_f64();
+            assert!(distance <= last_distance);
+            last_distance = distance;
+        }
+        // Rounding stops the steps once they are under half a unit
+        assert!(last_distance < 4.0);
+    }
+
+    #[test]
+    fn oriented_box_contact_manifold() {
+        let a = OrientedBox {
+            center: Vec3::ZERO,
+            half_extents: Vec3 {
+                x: 100,
+                y: 100,
+                z: 100,
+            },
+            orientation: Mat3::IDENTITY,
+        };
+        let b = OrientedBox {
+            center: Vec3 {
+                x: 150,
+                y: 10,
+                z: 0,
+            },
+            ..a
+        };
+        let manifold = a.contact_manifold(&b).unwrap();
+        assert!((manifold.penetration - 50.0).abs() < 1e-6);
+        assert!(manifold.normal.x > 0);
+        assert_eq!((manifold.normal.y, manifold.normal.z), (0, 0));
+
+        // Thin bars turned by 45 degrees around z, side by side
+        let bar = OrientedBox {
+            center: Vec3::ZERO,
+            half_extents: Vec3 {
+                x: 100,
+                y: 10,
+                z: 10,
+            },
+            orientation: Mat3 {
+                divider: 1000,
+                values: [707, -707, 0, 707, 707, 0, 0, 0, 1000],
+            },
+        };
+        let other_bar = OrientedBox {
+            center: Vec3 {
+                x: 60,
+                y: -60,
+                z: 0,
+            },
+            ..bar
+        };
+        let bounding_sphere = |oriented_box: &OrientedBox| Sphere {
+            center: oriented_box.center,
+            radius: oriented_box.half_extents.length_f64().ceil() as i64,
+        };
+        assert!(bounding_sphere(&bar)
+            .contact_manifold(&bounding_sphere(&other_bar))
+            .is_some());
+        assert!(bar.contact_manifold(&other_bar).is_none());
+    }
+
+    #[test]
+    fn projection_and_rejection() {
+        let v = Vec3 {
+            x: 300,
+            y: -120,
+            z: 45,
+        };
+        let dir = Vec3 { x: 2, y: 1, z: -2 };
+        let projected = v.project_onto_f64(&dir);
+        let rejected = v.reject_from_f64(&dir);
+        assert_eq!(projected.add(&rejected), v);
+        // The rejection is orthogonal to the direction, up to the rounding of the projection
+        assert!(rejected.dot_f64(&dir).abs() < dir.length_f64() * 2.0);
+        assert_eq!(
+            v.project_onto_f64(&Vec3 { x: 0, y: 0, z: 5 }),
+            Vec3 { x: 0, y: 0, z: 45 }
+        );
+
+        let perpendicular = Vec3 { x: 1, y: 0, z: 1 };
+        assert_eq!(dir.project_onto_f64(&perpendicular), Vec3::ZERO);
+        assert_eq!(dir.reject_from_f64(&perpendicular), dir);
+        assert_eq!(v.project_onto_f64(&Vec3::ZERO), Vec3::ZERO);
+    }
+
+    // Rotation around the axis of index `axis`, quantized with a 1 << 16 divider
+    fn rotation(axis: usize, degrees: f64) -> Mat3 {
+        let divider = 1 << 16;
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut values = [0.0; 9];
+        values[axis * 3 + axis] = 1.0;
+        values[u * 3 + u] = cos;
+        values[u * 3 + v] = -sin;
+        values[v * 3 + u] = sin;
+        values[v * 3 + v] = cos;
+        Mat3 {
+            divider,
+            values: values.map(|value| (value * divider as f64).round() as i64),
+        }
+    }
+
+    #[test]
+    fn quantized_quat_round_trip() {
+        let rotations = [
+            rotation(0, 17.0),
+            rotation(2, 120.0),
+            rotation(1, -75.0),
+            // Half turn, where the quaternion w component is 0
+            Mat3 {
+                divider: 1,
+                values: [-1, 0, 0, 0, -1, 0, 0, 0, 1],
+            },
+        ];
+        for rotation in rotations.iter() {
+            let decoded = Mat3::from_quantized_quat(rotation.to_quantized_quat());
+            let value = |m: &Mat3, i: usize| m.values[i] as f64 / m.divider as f64;
+            for i in 0..9 {
+                assert!((value(&decoded, i) - value(rotation, i)).abs() < 0.002);
+            }
+
+            // Orthonormal columns
+            let column = |i: usize| {
+                [
+                    value(&decoded, i),
+                    value(&decoded, 3 + i),
+                    value(&decoded, 6 + i),
+                ]
+            };
+            for i in 0..3 {
+                for j in 0..3 {
+                    let dot: f64 = column(i)
+                        .iter()
+                        .zip(column(j).iter())
+                        .map(|(a, b)| a * b)
+                        .sum();
+                    let expected = if i == j { 1.0 } else { 0.0 };
+                    assert!((dot - expected).abs() < 0.005);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cross_products() {
+        let x = Vec3 { x: 1, y: 0, z: 0 };
+        let y = Vec3 { x: 0, y: 1, z: 0 };
+        let z = Vec3 { x: 0, y: 0, z: 1 };
+        assert_eq!(x.cross(&y), z);
+        assert_eq!(y.cross(&z), x);
+        assert_eq!(z.cross(&x), y);
+        assert_eq!(x.cross(&x), Vec3::ZERO);
+
+        let a = Vec3 { x: 3, y: -5, z: 7 };
+        let b = Vec3 { x: 2, y: 11, z: -4 };
+        assert_eq!(a.cross(&b), b.cross(&a).mul_scalar(-1));
+        assert_eq!(a.cross(&b).dot_f64(&a), 0.0);
+        assert_eq!(a.checked_cross(&b), Some(a.cross(&b)));
+        let big = Vec3 {
+            x: i64::MAX,
+            y: 2,
+            z: 0,
+        };
+        assert_eq!(big.checked_cross(&Vec3 { x: 0, y: 2, z: 2 }), None);
+    }
+
+    #[test]
+    fn matrix_products() {
+        // Exact rotations of the 3-4-5 triangle angle around z and x
+        let rz = Mat3 {
+            divider: 5,
+            values: [4, -3, 0, 3, 4, 0, 0, 0, 5],
+        };
+        let rx = Mat3 {
+            divider: 5,
+            values: [5, 0, 0, 0, 4, -3, 0, 3, 4],
+        };
+        assert_eq!(Mat3::IDENTITY.mul_mat(&rz), rz);
+        assert_eq!(rz.mul_mat(&Mat3::IDENTITY), rz);
+
+        let v = Vec3 {
+            x: 125,
+            y: 250,
+            z: 375,
+        };
+        let composed = rz.mul_mat(&rx);
+        assert_eq!(composed.mul_vec(&v), rz.mul_vec(&rx.mul_vec(&v)));
+        assert_ne!(composed, rx.mul_mat(&rz));
+        assert_eq!(composed.mul_mat(&rz), rz.mul_mat(&rx.mul_mat(&rz)));
+
+        let doubled = Mat3 {
+            divider: 2,
+            values: [2, 0, 0, 0, 2, 0, 0, 0, 2],
+        };
+        assert_eq!(doubled.mul_mat(&doubled), Mat3::IDENTITY);
+    }
+
+    #[test]
+    fn axis_rotations() {
+        let x = Vec3 {
+            x: 1000,
+            y: 0,
+            z: 0,
+        };
+        assert_eq!(
+            Mat3::rotation_from_degrees(Axis::Z, 90.0).mul_vec(&x),
+            Vec3 {
+                x: 0,
+                y: 1000,
+                z: 0
+            }
+        );
+        assert_eq!(
+            Mat3::rotation_z(1, 0, 1).mul_vec(&x),
+            Vec3 {
+                x: 0,
+                y: 1000,
+                z: 0
+            }
+        );
+        assert_eq!(
+            Mat3::rotation_from_degrees(Axis::Y, 90.0).mul_vec(&x),
+            Vec3 {
+                x: 0,
+                y: 0,
+                z: -1000
+            }
+        );
+
+        let rotated = Mat3::rotation_from_degrees(Axis::Z, 30.0).mul_vec(&x);
+        assert!((rotated.x - 866).abs() <= 1);
+        assert!((rotated.y - 500).abs() <= 1);
+        assert_eq!(rotated.z, 0);
+    }
+
+    #[test]
+    fn rotation_times_its_transpose_is_the_identity() {
+        let rotations = [
+            Mat3::rotation_from_degrees(Axis::X, 33.0),
+            Mat3::rotation_from_degrees(Axis::Y, 140.0),
+            Mat3::rotation_from_degrees(Axis::Z, -71.0),
+        ];
+        for rotation in rotations.iter() {
+            let product = rotation.mul_mat(&rotation.transpose());
+            for row in 0..3 {
+                for column in 0..3 {
+                    let value = product.values[row * 3 + column] as f64 / product.divider as f64;
+                    let expected = if row == column { 1.0 } else { 0.0 };
+                    assert!((value - expected).abs() < 1e-3);
+                }
+            }
+        }
+        let m = Mat3 {
+            divider: 7,
+            values: [1, 2, 3, 4, 5, 6, 7, 8, 9],
+        };
+        assert_eq!(m.transpose().transpose(), m);
+    }
+
+    #[test]
+    fn sphere_contains_points() {
+        let sphere = Sphere {
+            center: Vec3 {
+                x: 100,
+                y: -100,
+                z: 0,
+            },
+            radius: 50,
+        };
+        let offset = |x: i64, y: i64, z: i64| sphere.center.add(&Vec3 { x, y, z });
+        // Surface points are contained
+        assert!(sphere.contains(&offset(50, 0, 0)));
+        assert!(sphere.contains(&offset(0, -30, 40)));
+        assert!(sphere.contains(&sphere.center));
+        assert!(sphere.contains(&offset(10, 20, -30)));
+        assert!(!sphere.contains(&offset(51, 0, 0)));
+        assert!(!sphere.contains(&offset(36, 36, 0)));
+    }
+
+    #[test]
+    fn cube_queries() {
+        let cube = Cube {
+            origin: Vec3 {
+                x: -50,
+                y: 0,
+                z: 100,
+            },
+            size: 100,
+        };
+        assert_eq!(
+            cube.center(),
+            Vec3 {
+                x: 0,
+                y: 50,
+                z: 150
+            }
+        );
+        assert!(cube.contains(&cube.origin));
+        assert!(cube.contains(&Vec3 {
+            x: 49,
+            y: 99,
+            z: 199
+        }));
+        assert!(!cube.contains(&Vec3 {
+            x: 50,
+            y: 50,
+            z: 150
+        }));
+
+        let sphere = |x: i64, y: i64, radius: i64| Sphere {
+            center: Vec3 { x, y, z: 150 },
+            radius,
+        };
+        // Straddling a face
+        assert!(cube.intersects_sphere(&sphere(60, 50, 20)));
+        // Fully inside
+        assert!(cube.intersects_sphere(&sphere(0, 50, 5)));
+        // Fully outside, even though the box around it overlaps the cube corner
+        assert!(!cube.intersects_sphere(&sphere(80, 130, 40)));
+        assert!(!cube.intersects_sphere(&sphere(200, 50, 20)));
+    }
+
+    #[test]
+    fn sphere_inside_each_quadrant() {
+        let cell_size = 1024;
+        let quadrants: Vec<Quadrant> = (0..NB_QUADRANTS)
+            .map(|i| num::FromPrimitive::from_usize(i).unwrap())
+            .collect();
+        for quadrant in quadrants.iter() {
+            let coordinate = |positive: bool| if positive { 256 } else { -256 };
+            let sphere = Sphere {
+                center: Vec3 {
+                    x: coordinate(quadrant.x_p()),
+                    y: coordinate(quadrant.y_p()),
+                    z: coordinate(quadrant.z_p()),
+                },
+                radius: 100,
+            };
+            for other in quadrants.iter() {
+                assert_eq!(
+                    sphere.is_inside_quadrant(cell_size, *other),
+                    other == quadrant
+                );
+            }
+        }
+
+        // Straddling the x = 0 border between XnYpZp and XpYpZp
+        let straddling = Sphere {
+            center: Vec3 {
+                x: 10,
+                y: 256,
+                z: 256,
+            },
+            radius: 20,
+        };
+        assert!(quadrants
+            .iter()
+            .all(|quadrant| !straddling.is_inside_quadrant(cell_size, *quadrant)));
+    }
+
+    #[test]
+    fn integer_intersects_matches_the_float_distance() {
+        let float_intersects = |a: &Sphere, b: &Sphere| {
+            a.center.sub(&b.center).length_f64() < (a.radius + b.radius) as f64
+        };
+        let sphere = |x, y, z, radius| Sphere {
+            center: Vec3 { x, y, z },
+            radius,
+        };
+        let cases = [
+            // Touching, then overlapping by a unit
+            (sphere(0, 0, 0, 10), sphere(20, 0, 0, 10)),
+            (sphere(0, 0, 0, 10), sphere(19, 0, 0, 10)),
+            // Touching along a diagonal, then just beyond
+            (sphere(0, 0, 0, 10), sphere(12, 16, 0, 10)),
+            (sphere(0, 0, 0, 10), sphere(12, 16, 1, 10)),
+            (sphere(5, 5, 5, 1), sphere(5, 5, 5, 1)),
+            (sphere(-100, 40, 7, 50), sphere(30, -20, 9, 100)),
+            // Squares beyond i64
+            (
+                sphere(1 << 40, 0, 0, 1 << 39),
+                sphere(0, 1 << 40, 0, 1 << 39),
+            ),
+        ];
+        for (a, b) in cases.iter() {
+            assert_eq!(a.intersects(b), float_intersects(a, b));
+        }
+        let big = Vec3 {
+            x: 1 << 40,
+            y: 1 << 40,
+            z: 0,
+        };
+        assert_eq!(big.length_sq(), 2i128 << 80);
+    }
+
+    #[test]
+    fn checked_and_saturating_arithmetic_near_the_limits() {
+        let near_limits = Vec3 {
+            x: i64::MAX - 1,
+            y: 0,
+            z: i64::MIN + 1,
+        };
+        assert_eq!(
+            near_limits.checked_add(&Vec3 { x: 1, y: 5, z: -1 }),
+            Some(Vec3 {
+                x: i64::MAX,
+                y: 5,
+                z: i64::MIN,
+            })
+        );
+        assert_eq!(near_limits.checked_add(&Vec3 { x: 2, y: 0, z: 0 }), None);
+        assert_eq!(near_limits.checked_add(&Vec3 { x: 0, y: 0, z: -2 }), None);
+        assert_eq!(
+            near_limits.saturating_add(&Vec3 {
+                x: 10,
+                y: 1,
+                z: -10
+            }),
+            Vec3 {
+                x: i64::MAX,
+                y: 1,
+                z: i64::MIN,
+            }
+        );
+
+        assert_eq!(
+            Vec3 {
+                x: 1 << 62,
+                y: 0,
+                z: 0
+            }
+            .checked_mul_scalar(2),
+            None
+        );
+        assert_eq!(
+            Vec3 {
+                x: 1 << 61,
+                y: -3,
+                z: 0,
+            }
+            .checked_mul_scalar(2),
+            Some(Vec3 {
+                x: 1 << 62,
+                y: -6,
+                z: 0,
+            })
+        );
+    }
+}
@@ -0,0 +1,17 @@
+extern crate num;
+#[macro_use]
+extern crate num_derive;
+
+pub mod camera;
+pub mod entity;
+pub mod geometry;
+pub mod matter_tree;
+pub mod migration_log;
+pub mod physics;
+pub mod player;
+pub mod render;
+pub mod serialization;
+pub mod simulation;
+pub mod space;
+pub mod space_tree;
+pub mod voxel_grid;
@@ -1,6 +1,8 @@
 use crate::{
     entity::{Entity, EntityData},
     geometry::{Cube, FineDirection, Quadrant, Sphere, Vec3, NB_QUADRANTS},
+    migration_log::MigrationLogger,
+    serialization::{Decoder, DeserializeError, Encoder},
     voxel_grid::VoxelGridSpace,
 };
 
@@ -12,7 +14,57 @@ pub enum CellPart {
     Quadrant(Quadrant),
 }
 
-type Entities = Vec<Box<Entity>>;
+pub type Entities = Vec<Box<Entity>>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MatterTreeConfig {
+    // The smallest cells are 2^min_size_pow wide
+    pub min_size_pow: u32,
+    // Number of levels above the smallest cells: the root is 2^(min_size_pow + max_scale) wide.
+    // Larger trees cover more space, at the cost of smaller margins against i64 overflows in the
+    // computations on their coordinates.
+    pub max_scale: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MatterTreeConfigError {
+    // The tree would be larger than 2^MatterTreeConfig::MAX_SIZE_POW
+    TooLarge(MatterTreeConfig),
+}
+
+impl MatterTreeConfig {
+    // Keeps the sums of coordinates and offsets within a tree inside the i64 range: 64 bits, minus
+    // the sign one, minus a 2 bits margin
+    pub const MAX_SIZE_POW: u32 = 64 - 1 - 2;
+
+    // Matter trees of MatterTree::new and GrowableSpaceTree::new. The space tree covers the larger
+    // ranges, so its matter trees are kept small.
+    pub const DEFAULT: Self = Self {
+        min_size_pow: 5,
+        max_scale: 10,
+    };
+
+    pub const fn size(&self) -> i64 {
+        1 << (self.min_size_pow + self.max_scale)
+    }
+
+    pub const fn min_size(&self) -> i64 {
+        1 << self.min_size_pow
+    }
+
+    // Slack an entity must keep with the quadrant borders to be moved down into it. It keeps entities
+    // moving around a border from going down and up every tick.
+    pub const fn quadrant_fit_margin(&self) -> i64 {
+        self.min_size() / 8
+    }
+
+    pub fn is_valid(&self) -> bool {
+        match self.min_size_pow.checked_add(self.max_scale) {
+            Some(size_pow) => size_pow <= Self::MAX_SIZE_POW,
+            None => false,
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct MatterTree {
@@ -21,6 +73,18 @@ pub struct MatterTree {
     pub entities: Entities,
 
     pub area: Cube,
+
+    // Number of refreshes this node has spent empty
+    pub empty_ticks: u32,
+
+    // Shared by all the nodes of a tree
+    pub config: MatterTreeConfig,
+
+    // Mass of the entities of this node and its sub trees, and their center of mass. Brought up
+    // to date by the operations moving entities around, so it lags behind the movements of the
+    // current tick until the next refresh.
+    pub contained_mass: f64,
+    mass_center: Vec3,
 }
 
 enum QuadrantMoveOperation {
@@ -28,54 +92,63 @@ enum QuadrantMoveOperation {
     ToUpperCell,
 }
 
+impl Default for MatterTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MatterTree {
-    const MIN_SIZE_POW: i64 = 5;
-    pub const MIN_SIZE: i64 = 1 << Self::MIN_SIZE_POW;
-    const MAX_SCALE: u32 = 64 // Max
-        - 1 // Remove sign
-        - Self::MIN_SIZE_POW as u32 // Remove scales taken up by min size cells
-        - 1 // Margin
-        - 47; // Manual testing
-    pub const MAX_SIZE: i64 = 1 << (Self::MIN_SIZE_POW + Self::MAX_SCALE as i64);
+    // Sizes of the trees built with the default configuration
+    pub const MIN_SIZE: i64 = MatterTreeConfig::DEFAULT.min_size();
+    pub const MAX_SIZE: i64 = MatterTreeConfig::DEFAULT.size();
+    // Entities are kept in the smallest cell at least this many times larger than their diameter
+    pub const CELL_TO_DIAMETER_RATIO: i64 = 2;
     const NONE_SPACE_CELL: Option<Box<Self>> = None;
 
     pub fn new() -> Self {
+        Self::new_root(MatterTreeConfig::DEFAULT)
+    }
+
+    // Root tree, centered on 0
+    pub fn new_with_config(config: MatterTreeConfig) -> Result<Self, MatterTreeConfigError> {
+        if !config.is_valid() {
+            return Err(MatterTreeConfigError::TooLarge(config));
+        }
+        Ok(Self::new_root(config))
+    }
+
+    fn new_root(config: MatterTreeConfig) -> Self {
+        let size = config.size();
         Self::new_tree(
-            Self::MAX_SCALE,
+            config.max_scale,
             Cube {
                 origin: Vec3 {
-                    x: -Self::MAX_SIZE / 2,
-                    y: -Self::MAX_SIZE / 2,
-                    z: -Self::MAX_SIZE / 2,
+                    x: -size / 2,
+                    y: -size / 2,
+                    z: -size / 2,
                 },
-                size: Self::MAX_SIZE,
+                size,
             },
+            config,
         )
     }
 
-    fn new_tree(scale: u32, area: Cube) -> Self {
+    fn new_tree(scale: u32, area: Cube, config: MatterTreeConfig) -> Self {
         Self {
             scale,
+            config,
             sub_trees: [Self::NONE_SPACE_CELL; NB_QUADRANTS],
             entities: vec![],
             area,
+            empty_ticks: 0,
+            contained_mass: 0.0,
+            mass_center: area.center(),
         }
     }
 
     fn new_sub_tree(&self, quadrant: Quadrant) -> Self {
-        let origin = self.area.origin;
-        let size = self.area.size / 2;
-        Self::new_tree(
-            self.scale - 1,
-            Cube {
-                origin: Vec3 {
-                    x: origin.x + quadrant.x_p() as i64 * size,
-                    y: origin.y + quadrant.y_p() as i64 * size,
-                    z: origin.z + quadrant.z_p() as i64 * size,
-                },
-                size,
-            },
-        )
+        Self::new_tree(self.scale - 1, self.area.quadrant(quadrant), self.config)
     }
 
     fn move_entities_to_quadrant(&mut self, entities: Entities, quadrant: Quadrant) {
@@ -91,12 +164,11 @@ impl MatterTree {
     }
 
     fn center(&self) -> Vec3 {
-        let half = self.area.size / 2;
-        self.area.origin.add(&Vec3 {
-            x: half,
-            y: half,
-            z: half,
-        })
+        self.area.center()
+    }
+
+    fn fits_in_cell(entity: &Entity, cell_size: i64) -> bool {
+        cell_size >= Self::CELL_TO_DIAMETER_RATIO * 2 * entity.bounding_sphere.radius
     }
 
     pub fn add_entities(&mut self, entities: Entities) {
@@ -108,7 +180,13 @@ impl MatterTree {
             for entity in entities.into_iter() {
                 let relative_sphere = entity.bounding_sphere.sub_to_center(&self.center());
                 let quadrant = Quadrant::from_pos(&relative_sphere.center);
-                if relative_sphere.is_inside_quadrant(&self.area, quadrant as usize) {
+                let fit_sphere = Sphere {
+                    center: relative_sphere.center,
+                    radius: relative_sphere.radius + self.config.quadrant_fit_margin(),
+                };
+                if fit_sphere.is_inside_quadrant(self.area.size, quadrant)
+                    && Self::fits_in_cell(&entity, self.area.size / 2)
+                {
                     per_quadrant[quadrant as usize].push(entity);
                 } else {
                     self.entities.push(entity);
@@ -124,29 +202,74 @@ impl MatterTree {
                 }
             }
         }
+        self.update_mass();
+    }
+
+    // Entities with no mass or an infinite one (immovable scenery) do not weigh in
+    fn update_mass(&mut self) {
+        let mut mass = 0.0;
+        let mut moment = [0.0; 3];
+        let mut add = |m: f64, pos: &Vec3| {
+            if m > 0.0 && m.is_finite() {
+                mass += m;
+                moment[0] += pos.x as f64 * m;
+                moment[1] += pos.y as f64 * m;
+                moment[2] += pos.z as f64 * m;
+            }
+        };
+        for entity in self.entities.iter() {
+            add(entity.mass, &entity.bounding_sphere.center);
+        }
+        for tree in self.sub_trees.iter().flatten() {
+            add(tree.contained_mass, &tree.mass_center);
+        }
+        self.contained_mass = mass;
+        self.mass_center = if mass > 0.0 {
+            Vec3 {
+                x: (moment[0] / mass).round() as i64,
+                y: (moment[1] / mass).round() as i64,
+                z: (moment[2] / mass).round() as i64,
+            }
+        } else {
+            self.center()
+        };
+    }
+
+    // Center of mass of the node entities, in the matter tree frame. The node center if it holds no
+    // mass.
+    pub fn center_of_mass(&self) -> Vec3 {
+        self.mass_center
     }
 
     pub fn is_empty(&self) -> bool {
         self.sub_trees.iter().all(|cell| cell.is_none()) && self.entities.is_empty()
     }
 
-    pub fn refresh(&mut self) -> Entities {
+    pub fn refresh(&mut self, empty_node_ttl: u32, logger: &mut MigrationLogger) -> Entities {
         let mut quitters = vec![];
 
         // Run each entity dynamics and catch crossing cell boundaries
         for (i, entity) in self.entities.iter().enumerate() {
             // Check if entity should change cell
-            let cell_part = entity.get_containing_cell_part(&self.area);
+            let cell_part =
+                entity.get_containing_cell_part(&self.area, self.config.quadrant_fit_margin());
+            let too_big = !Self::fits_in_cell(entity, self.area.size);
             match cell_part {
-                CellPart::MultiQuadrant => (),
+                CellPart::MultiQuadrant => {
+                    if too_big && self.scale < self.config.max_scale {
+                        quitters.push((i, QuadrantMoveOperation::ToUpperCell))
+                    }
+                }
                 CellPart::PartlyOutside => {
-                    if self.scale < Self::MAX_SCALE {
+                    if self.scale < self.config.max_scale {
                         quitters.push((i, QuadrantMoveOperation::ToUpperCell))
                     }
                 }
                 CellPart::CenterOutside => quitters.push((i, QuadrantMoveOperation::ToUpperCell)),
                 CellPart::Quadrant(quadrant) => {
-                    if self.scale > 0 {
+                    if too_big && self.scale < self.config.max_scale {
+                        quitters.push((i, QuadrantMoveOperation::ToUpperCell))
+                    } else if self.scale > 0 && Self::fits_in_cell(entity, self.area.size / 2) {
                         quitters.push((i, QuadrantMoveOperation::ToSubCell { quadrant }))
                     }
                 }
@@ -158,9 +281,14 @@ impl MatterTree {
         let mut nb_insiders = 0;
         let mut outsiders = vec![];
         for (i, quitter) in quitters.into_iter().rev() {
-            let entity = self.entities.remove(i);
+            let mut entity = self.entities.remove(i);
+            logger.log(entity.id, &self.area);
             match quitter {
-                QuadrantMoveOperation::ToUpperCell => outsiders.push(entity),
+                // Settling down into the sub cells is not a back and forth move
+                QuadrantMoveOperation::ToUpperCell => {
+                    entity.record_migration(self.config.min_size());
+                    outsiders.push(entity)
+                }
                 QuadrantMoveOperation::ToSubCell { quadrant } => {
                     insiders[quadrant as usize].push(entity);
                     nb_insiders += 1;
@@ -176,30 +304,30 @@ impl MatterTree {
                 area,
                 ..
             } = self;
-            for quad in sub_trees.iter_mut() {
-                if let Some(quad) = quad {
-                    for entity in quad.refresh().into_iter() {
-                        match entity.get_containing_cell_part(area) {
-                            CellPart::MultiQuadrant => {
+            for quad in sub_trees.iter_mut().flatten() {
+                for entity in quad.refresh(empty_node_ttl, logger).into_iter() {
+                    match entity.get_containing_cell_part(area, self.config.quadrant_fit_margin()) {
+                        CellPart::MultiQuadrant => {
+                            entities.push(entity);
+                        }
+                        CellPart::PartlyOutside => {
+                            if self.scale < self.config.max_scale {
+                                outsiders.push(entity);
+                            } else {
                                 entities.push(entity);
                             }
-                            CellPart::PartlyOutside => {
-                                if self.scale < Self::MAX_SCALE {
-                                    outsiders.push(entity);
-                                } else {
-                                    entities.push(entity);
-                                }
-                            }
-                            CellPart::CenterOutside => {
+                        }
+                        CellPart::CenterOutside => {
+                            outsiders.push(entity);
+                        }
+                        CellPart::Quadrant(quadrant) => {
+                            if self.scale == 0 {
                                 outsiders.push(entity);
-                            }
-                            CellPart::Quadrant(quadrant) => {
-                                if self.scale > 0 {
-                                    insiders[quadrant as usize].push(entity);
-                                    nb_insiders += 1;
-                                } else {
-                                    outsiders.push(entity);
-                                }
+                            } else if Self::fits_in_cell(&entity, area.size / 2) {
+                                insiders[quadrant as usize].push(entity);
+                                nb_insiders += 1;
+                            } else {
+                                entities.push(entity);
                             }
                         }
                     }
@@ -222,12 +350,16 @@ impl MatterTree {
             }
         }
 
-        // Clean empty quadrants
+        // Clean quadrants that stayed empty for long enough
         for i in 0..NB_QUADRANTS {
             let mut need_emptying = false;
-            if let Some(quad) = self.sub_trees[i].as_ref() {
-                if quad.is_empty() {
+            if let Some(quad) = self.sub_trees[i].as_mut() {
+                if !quad.is_empty() {
+                    quad.empty_ticks = 0;
+                } else if quad.empty_ticks >= empty_node_ttl {
                     need_emptying = true;
+                } else {
+                    quad.empty_ticks += 1;
                 }
             }
             if need_emptying {
@@ -239,7 +371,7 @@ impl MatterTree {
         if self.entities.is_empty() && self.nb_entities() == 1 {
             for i in 0..NB_QUADRANTS {
                 let found = if let Some(quad) = self.sub_trees[i].as_mut() {
-                    if !quad.is_empty() {
+                    if !quad.entities.is_empty() {
                         self.entities.push(quad.entities.pop().unwrap());
                         true
                     } else {
@@ -255,23 +387,52 @@ impl MatterTree {
             }
         }
 
+        self.update_mass();
         outsiders
     }
 
-    pub fn apply_neighbourhood_collisions(&mut self) {
-        // Apply collisions to entities of this node
-        let mut entity_quadrant = vec![];
-        let area = &self.area;
+    // The ids of the entities found in contact are pushed to contacts
+    pub fn apply_neighbourhood_collisions(&mut self, contacts: &mut Vec<(u64, u64)>) {
+        self.for_each_touching_pair(&mut |a, b| {
+            if a.apply_collision(b) {
+                contacts.push((a.id, b.id));
+            }
+        });
+    }
+
+    // Calls f once with each pair of entities of this tree whose bounding spheres intersect. Within
+    // each node, pairs involving higher priority entities come first.
+    pub fn for_each_touching_pair<F: FnMut(&mut Entity, &mut Entity)>(&mut self, f: &mut F) {
+        // Entities of a node are fully inside it, so a lone entity has nothing to collide with here
+        if self.nb_entities() < 2 {
+            return;
+        }
+
+        // Pairs within this node
+        let mut pairs = vec![];
         for i in 0..self.entities.len() {
-            let (source, remainder) = self.entities.split_at_mut(i + 1);
-            let source = source.last_mut().unwrap();
-            for e in remainder.iter_mut() {
-                source.apply_collision(e);
+            for j in i + 1..self.entities.len() {
+                if self.entities[i].check_collision(&self.entities[j]) {
+                    pairs.push((i, j));
+                }
             }
-            entity_quadrant.push(source.get_collisioned_quadrants(area));
         }
+        pairs.sort_by_key(|(i, j)| {
+            std::cmp::Reverse(Self::pair_priority(&self.entities[*i], &self.entities[*j]))
+        });
+        for (i, j) in pairs.into_iter() {
+            let (source, remainder) = self.entities.split_at_mut(j);
+            f(&mut source[i], &mut remainder[0]);
+        }
+        let area = &self.area;
+        let entity_quadrant: Vec<_> = self
+            .entities
+            .iter()
+            .map(|entity| entity.get_collisioned_quadrants(area))
+            .collect();
 
-        // Apply collisions to all sub_tree entities
+        // Pairs between this node entities and the ones of a sub tree, then pairs within the sub
+        // trees. Sub trees do not overlap, so no pair spans two of them.
         let Self {
             sub_trees,
             entities,
@@ -285,7 +446,52 @@ impl MatterTree {
                     .filter(|(j, _)| entity_quadrant[*j].contains(&(i as u8)))
                     .map(|(_, e)| e)
                     .collect();
-                quad.apply_external_collisions(&mut relevant_entities[..]);
+                if !relevant_entities.is_empty() {
+                    quad.for_each_touching_outsider(&mut relevant_entities[..], f);
+                }
+                quad.for_each_touching_pair(f);
+            }
+        }
+    }
+
+    // Calls f with each pair made of an entity of this tree and an outsider whose bounding spheres
+    // intersect, the entity of the tree coming first
+    fn for_each_touching_outsider<F: FnMut(&mut Entity, &mut Entity)>(
+        &mut self,
+        outsiders: &mut [&mut Box<Entity>],
+        f: &mut F,
+    ) {
+        let mut pairs = vec![];
+        for (i, a) in self.entities.iter().enumerate() {
+            for (j, b) in outsiders.iter().enumerate() {
+                if a.check_collision(b) {
+                    pairs.push((i, j));
+                }
+            }
+        }
+        pairs.sort_by_key(|(i, j)| {
+            std::cmp::Reverse(Self::pair_priority(&self.entities[*i], outsiders[*j]))
+        });
+        for (i, j) in pairs.into_iter() {
+            f(&mut self.entities[i], outsiders[j]);
+        }
+
+        // Outsiders reach down into the sub trees they touch
+        let outsider_quadrant: Vec<_> = outsiders
+            .iter()
+            .map(|outsider| outsider.get_collisioned_quadrants(&self.area))
+            .collect();
+        for (i, sub_tree) in self.sub_trees.iter_mut().enumerate() {
+            if let Some(quad) = sub_tree {
+                let mut relevant_outsiders: Vec<_> = outsiders
+                    .iter_mut()
+                    .enumerate()
+                    .filter(|(j, _)| outsider_quadrant[*j].contains(&(i as u8)))
+                    .map(|(_, outsider)| &mut **outsider)
+                    .collect();
+                if !relevant_outsiders.is_empty() {
+                    quad.for_each_touching_outsider(&mut relevant_outsiders[..], f);
+                }
             }
         }
     }
@@ -305,14 +511,25 @@ impl MatterTree {
             .collect()
     }
 
-    pub fn apply_external_collisions(&mut self, outsiders: &mut [&mut Box<Entity>]) {
-        for a in self.entities.iter_mut() {
-            for b in outsiders.iter_mut() {
-                if a.bounding_sphere.intersects(&b.bounding_sphere) {
-                    a.apply_collision(b);
-                }
+    // Highest priority of the pair first, then the lowest one
+    fn pair_priority(a: &Entity, b: &Entity) -> (u8, u8) {
+        (
+            u8::max(a.collision_priority, b.collision_priority),
+            u8::min(a.collision_priority, b.collision_priority),
+        )
+    }
+
+    // Collides the outsiders with the entities of the whole tree they touch
+    pub fn apply_external_collisions(
+        &mut self,
+        outsiders: &mut [&mut Box<Entity>],
+        contacts: &mut Vec<(u64, u64)>,
+    ) {
+        self.for_each_touching_outsider(outsiders, &mut |a, b| {
+            if a.apply_collision(b) {
+                contacts.push((a.id, b.id));
             }
-        }
+        });
     }
 
     pub fn run_actions(&mut self) {
@@ -343,22 +560,302 @@ impl MatterTree {
             }
         }
 
-        for sub_tree in self.sub_trees.iter_mut() {
-            if let Some(tree) = sub_tree {
-                tree.run_actions();
-            }
+        for tree in self.sub_trees.iter_mut().flatten() {
+            tree.run_actions();
         }
+        self.update_mass();
     }
 
-    pub fn run_movements(&mut self) {
+    pub fn run_movements(&mut self, dt: f64) {
         for entity in self.entities.iter_mut() {
-            entity.run_movement();
+            entity.run_movement(dt);
+        }
+        for tree in self.sub_trees.iter_mut().flatten() {
+            tree.run_movements(dt);
+        }
+    }
+
+    pub fn for_each_entity<F: FnMut(&Entity)>(&self, f: &mut F) {
+        for entity in self.entities.iter() {
+            f(entity);
+        }
+        for tree in self.sub_trees.iter().flatten() {
+            tree.for_each_entity(f);
+        }
+    }
+
+    pub fn for_each_entity_mut<F: FnMut(&mut Entity)>(&mut self, f: &mut F) {
+        for entity in self.entities.iter_mut() {
+            f(entity);
+        }
+        for tree in self.sub_trees.iter_mut().flatten() {
+            tree.for_each_entity_mut(f);
+        }
+    }
+
+    // Depth first, the entities of a node coming before the ones of its sub trees
+    pub fn iter_entities(&self) -> EntityIter<'_> {
+        EntityIter {
+            nodes: vec![self],
+            entities: [].iter(),
+        }
+    }
+
+    pub fn iter_entities_mut(&mut self) -> EntityIterMut<'_> {
+        EntityIterMut {
+            nodes: vec![self],
+            entities: [].iter_mut(),
+        }
+    }
+
+    pub fn find_entity(&self, id: u64) -> Option<&Entity> {
+        if let Some(entity) = self.entities.iter().find(|entity| entity.id == id) {
+            return Some(entity);
+        }
+        self.sub_trees
+            .iter()
+            .flatten()
+            .find_map(|tree| tree.find_entity(id))
+    }
+
+    pub fn find_entity_mut(&mut self, id: u64) -> Option<&mut Entity> {
+        if let Some(entity) = self.entities.iter_mut().find(|entity| entity.id == id) {
+            return Some(entity);
+        }
+        self.sub_trees
+            .iter_mut()
+            .flatten()
+            .find_map(|tree| tree.find_entity_mut(id))
+    }
+
+    // Removes and returns the entities for which f returns true, dropping emptied sub trees
+    pub fn drain_filter<F: FnMut(&mut Entity) -> bool>(&mut self, f: &mut F) -> Entities {
+        let mut drained = vec![];
+        let mut i = 0;
+        while i < self.entities.len() {
+            if f(&mut self.entities[i]) {
+                drained.push(self.entities.remove(i));
+            } else {
+                i += 1;
+            }
         }
         for sub_tree in self.sub_trees.iter_mut() {
             if let Some(tree) = sub_tree {
-                tree.run_movements();
+                drained.extend(tree.drain_filter(f));
+                if tree.is_empty() {
+                    *sub_tree = None;
+                }
             }
         }
+        self.update_mass();
+        drained
+    }
+
+    // Entity whose center is the closest to the point, with its distance
+    pub fn nearest_entity(&self, point: &Vec3) -> Option<(&Entity, f64)> {
+        let mut best = None;
+        self.nearest_matching(point, &|_| true, &mut best);
+        best
+    }
+
+    pub fn nearest_matching<'a, F: Fn(&Entity) -> bool>(
+        &'a self,
+        point: &Vec3,
+        pred: &F,
+        best: &mut Option<(&'a Entity, f64)>,
+    ) {
+        for entity in self.entities.iter() {
+            if pred(entity) {
+                let dist = entity.bounding_sphere.center.sub(point).length_f64();
+                if best.is_none_or(|(_, best_dist)| dist < best_dist) {
+                    *best = Some((entity, dist));
+                }
+            }
+        }
+
+        // Closest sub trees first, to prune as many far ones as possible
+        let mut sub_trees: Vec<_> = self
+            .sub_trees
+            .iter()
+            .flatten()
+            .map(|tree| (tree.area.distance_f64(point), tree))
+            .collect();
+        sub_trees.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
+        for (dist, tree) in sub_trees.into_iter() {
+            if let Some((_, best_dist)) = best {
+                if dist >= *best_dist {
+                    break;
+                }
+            }
+            tree.nearest_matching(point, pred, best);
+        }
+    }
+
+    pub fn entities_in_sphere(&self, query: &Sphere) -> Vec<&Entity> {
+        let mut found = vec![];
+        self.collect_entities_in_sphere(query, &mut found);
+        found
+    }
+
+    // Returns the number of visited nodes
+    pub fn collect_entities_in_sphere<'a>(
+        &'a self,
+        query: &Sphere,
+        found: &mut Vec<&'a Entity>,
+    ) -> usize {
+        // Entities of this node may span several quadrants or stick out of the area, so they are
+        // checked even when the area itself is outside the query
+        found.extend(
+            self.entities
+                .iter()
+                .filter(|entity| entity.bounding_sphere.intersects(query))
+                .map(|entity| entity.as_ref()),
+        );
+        let mut visited = 1;
+        for tree in self.sub_trees.iter().flatten() {
+            if tree.area.intersects_sphere(query) {
+                visited += tree.collect_entities_in_sphere(query, found);
+            }
+        }
+        visited
+    }
+
+    // Nearest entity whose bounding sphere is crossed by the ray within max_dist, with the distance
+    // at which the ray enters it
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: i64) -> Option<(&Entity, i64)> {
+        let mut best = None;
+        self.raycast_matching(&origin, &dir, max_dist as f64, &|_| true, &mut best);
+        best.map(|(entity, dist)| (entity, dist as i64))
+    }
+
+    // Only the entities accepted by pred can be hit. pred is only called for the entities the ray
+    // enters before the best hit so far.
+    pub fn raycast_matching<'a, F: Fn(&Entity) -> bool>(
+        &'a self,
+        origin: &Vec3,
+        dir: &Vec3,
+        max_dist: f64,
+        pred: &F,
+        best: &mut Option<(&'a Entity, f64)>,
+    ) {
+        for entity in self.entities.iter() {
+            if let Some(dist) = entity.bounding_sphere.ray_entry_distance(origin, dir) {
+                if dist <= max_dist
+                    && best.is_none_or(|(_, best_dist)| dist < best_dist)
+                    && pred(entity)
+                {
+                    *best = Some((entity, dist));
+                }
+            }
+        }
+
+        // Sub trees in the order the ray enters them, so that the ones behind the best hit are
+        // pruned
+        let mut sub_trees: Vec<_> = self
+            .sub_trees
+            .iter()
+            .flatten()
+            .filter_map(|tree| {
+                tree.area
+                    .ray_entry_distance(origin, dir)
+                    .filter(|dist| *dist <= max_dist)
+                    .map(|dist| (dist, tree))
+            })
+            .collect();
+        sub_trees.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
+        for (dist, tree) in sub_trees.into_iter() {
+            if let Some((_, best_dist)) = best {
+                if dist >= *best_dist {
+                    break;
+                }
+            }
+            tree.raycast_matching(origin, dir, max_dist, pred, best);
+        }
+    }
+
+    // Adds the gravity pull of the whole tree to each entity forces (Barnes-Hut approximation).
+    // Sub trees seen under a ratio of their size over their distance below theta are taken as a
+    // single mass at their center of mass; theta = 0 gives the exact pairwise sum.
+    pub fn apply_gravity(&mut self, theta: f64, g: f64) {
+        let forces: Vec<_> = self
+            .iter_entities()
+            .map(|entity| {
+                if entity.mass > 0.0 && entity.mass.is_finite() {
+                    let acceleration = self.gravity_acceleration(
+                        &entity.bounding_sphere.center,
+                        entity.id,
+                        theta,
+                        g,
+                    );
+                    [
+                        acceleration[0] * entity.mass,
+                        acceleration[1] * entity.mass,
+                        acceleration[2] * entity.mass,
+                    ]
+                } else {
+                    [0.0; 3]
+                }
+            })
+            .collect();
+        // Both iterators walk the tree in the same order
+        for (entity, force) in self.iter_entities_mut().zip(forces.iter()) {
+            entity.accumulate_force(&Vec3 {
+                x: force[0].round() as i64,
+                y: force[1].round() as i64,
+                z: force[2].round() as i64,
+            });
+        }
+    }
+
+    // Acceleration the tree entities, except the excluded one, give to a point
+    pub fn gravity_acceleration(
+        &self,
+        pos: &Vec3,
+        excluded_id: u64,
+        theta: f64,
+        g: f64,
+    ) -> [f64; 3] {
+        let mut acceleration = [0.0; 3];
+        let pull = |acceleration: &mut [f64; 3], mass: f64, from: &Vec3| {
+            if mass <= 0.0 || !mass.is_finite() {
+                return;
+            }
+            let gap = [
+                (from.x - pos.x) as f64,
+                (from.y - pos.y) as f64,
+                (from.z - pos.z) as f64,
+            ];
+            let dist_sq = gap[0] * gap[0] + gap[1] * gap[1] + gap[2] * gap[2];
+            if dist_sq == 0.0 {
+                return;
+            }
+            let factor = g * mass / (dist_sq * dist_sq.sqrt());
+            for (a, gap) in acceleration.iter_mut().zip(gap.iter()) {
+                *a += factor * gap;
+            }
+        };
+        for entity in self.entities.iter() {
+            if entity.id != excluded_id {
+                pull(
+                    &mut acceleration,
+                    entity.mass,
+                    &entity.bounding_sphere.center,
+                );
+            }
+        }
+        for tree in self.sub_trees.iter().flatten() {
+            let dist = tree.mass_center.sub(pos).length_f64();
+            // The point own sub tree is never approximated, as it may hold the excluded entity
+            if !tree.area.contains(pos) && (tree.area.size as f64) < theta * dist {
+                pull(&mut acceleration, tree.contained_mass, &tree.mass_center);
+            } else {
+                let sub_acceleration = tree.gravity_acceleration(pos, excluded_id, theta, g);
+                for (a, sub) in acceleration.iter_mut().zip(sub_acceleration.iter()) {
+                    *a += sub;
+                }
+            }
+        }
+        acceleration
     }
 
     pub fn nb_nodes(&self) -> usize {
@@ -386,3 +883,746 @@ impl MatterTree {
                 .sum::<usize>()
     }
 }
+
+pub struct EntityIter<'a> {
+    // Nodes whose entities are still to be visited
+    nodes: Vec<&'a MatterTree>,
+    entities: std::slice::Iter<'a, Box<Entity>>,
+}
+
+impl<'a> Iterator for EntityIter<'a> {
+    type Item = &'a Entity;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entity) = self.entities.next() {
+                return Some(entity);
+            }
+            let node = self.nodes.pop()?;
+            self.entities = node.entities.iter();
+            // Reversed so that sub trees are visited in quadrant order
+            self.nodes.extend(
+                node.sub_trees
+                    .iter()
+                    .rev()
+                    .flatten()
+                    .map(|tree| tree.as_ref()),
+            );
+        }
+    }
+}
+
+pub struct EntityIterMut<'a> {
+    nodes: Vec<&'a mut MatterTree>,
+    entities: std::slice::IterMut<'a, Box<Entity>>,
+}
+
+impl<'a> Iterator for EntityIterMut<'a> {
+    type Item = &'a mut Entity;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entity) = self.entities.next() {
+                return Some(entity);
+            }
+            let node = self.nodes.pop()?;
+            self.entities = node.entities.iter_mut();
+            self.nodes.extend(
+                node.sub_trees
+                    .iter_mut()
+                    .rev()
+                    .flatten()
+                    .map(|tree| tree.as_mut()),
+            );
+        }
+    }
+}
+
+// Serialization
+impl MatterTree {
+    pub fn encode(&self, out: &mut Encoder) {
+        out.u32(self.config.min_size_pow);
+        out.u32(self.config.max_scale);
+        self.encode_node(out);
+    }
+
+    fn encode_node(&self, out: &mut Encoder) {
+        out.u32(self.scale);
+        out.cube(&self.area);
+        out.u32(self.empty_ticks);
+        out.len(self.entities.len());
+        for entity in self.entities.iter() {
+            entity.encode(out);
+        }
+        out.presence_mask(&self.sub_trees);
+        for sub_tree in self.sub_trees.iter().flatten() {
+            sub_tree.encode_node(out);
+        }
+    }
+
+    pub fn decode(input: &mut Decoder) -> Result<Self, DeserializeError> {
+        let config = MatterTreeConfig {
+            min_size_pow: input.u32()?,
+            max_scale: input.u32()?,
+        };
+        if !config.is_valid() {
+            return Err(DeserializeError::InvalidValue("matter tree config"));
+        }
+        Self::decode_node(input, config)
+    }
+
+    fn decode_node(
+        input: &mut Decoder,
+        config: MatterTreeConfig,
+    ) -> Result<Self, DeserializeError> {
+        let scale = input.u32()?;
+        if scale > config.max_scale {
+            return Err(DeserializeError::InvalidValue("matter tree scale"));
+        }
+        let mut tree = Self::new_tree(scale, input.cube()?, config);
+        tree.empty_ticks = input.u32()?;
+        for _ in 0..input.len()? {
+            tree.entities.push(Box::new(Entity::decode(input)?));
+        }
+        let presence = input.presence_mask()?;
+        for (sub_tree, present) in tree.sub_trees.iter_mut().zip(presence.iter()) {
+            if *present {
+                let sub = Self::decode_node(input, config)?;
+                if sub.scale + 1 != scale {
+                    return Err(DeserializeError::InvalidValue("matter tree scale"));
+                }
+                *sub_tree = Some(Box::new(sub));
+            }
+        }
+        tree.update_mass();
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialization::Encoder;
+    use crate::voxel_grid::VOXEL_SCHEMA_VERSION;
+
+    fn cell_size_of(tree: &MatterTree, id: u64) -> Option<i64> {
+        if tree.entities.iter().any(|entity| entity.id == id) {
+            return Some(tree.area.size);
+        }
+        tree.sub_trees
+            .iter()
+            .flatten()
+            .find_map(|sub_tree| cell_size_of(sub_tree, id))
+    }
+
+    fn find_entity_mut(tree: &mut MatterTree, id: u64) -> Option<&mut Box<Entity>> {
+        match tree.entities.iter().position(|entity| entity.id == id) {
+            Some(i) => Some(&mut tree.entities[i]),
+            None => tree
+                .sub_trees
+                .iter_mut()
+                .flatten()
+                .find_map(|sub_tree| find_entity_mut(sub_tree, id)),
+        }
+    }
+
+    #[test]
+    fn entities_sink_to_a_cell_fitting_their_size() {
+        let mut tree = MatterTree::new();
+        let entity = |pos: i64, radius: i64| {
+            Box::new(
+                Entity::builder()
+                    .at(Vec3 {
+                        x: pos,
+                        y: pos,
+                        z: pos,
+                    })
+                    .radius(radius)
+                    .build(),
+            )
+        };
+        let big = entity(5120, 900);
+        let small = entity(4200, 10);
+        let (big_id, small_id) = (big.id, small.id);
+        tree.add_entities(vec![big, small]);
+        let mut logger = MigrationLogger::new(1);
+        for _ in 0..3 {
+            tree.refresh(0, &mut logger);
+        }
+
+        let big_cell = cell_size_of(&tree, big_id).unwrap();
+        let small_cell = cell_size_of(&tree, small_id).unwrap();
+        assert!(big_cell >= MatterTree::CELL_TO_DIAMETER_RATIO * 2 * 900);
+        assert!(big_cell < 2 * MatterTree::CELL_TO_DIAMETER_RATIO * 2 * 900);
+        assert!(small_cell < big_cell);
+    }
+
+    // Speed the heavy entity of a row of light ones leaning on it gets from a collision pass. The
+    // lights behind push the front one harder against it when their contacts are resolved first.
+    fn heavy_speed_after_collisions(heavy_priority: u8) -> i64 {
+        let mut tree = MatterTree::new();
+        for i in 0..8 {
+            let light = Entity::builder()
+                .at(Vec3 {
+                    x: 2540 + i * 100,
+                    y: 2000,
+                    z: 2000,
+                })
+                .radius(60)
+                .mass(1.0)
+                .speed(Vec3 {
+                    x: -50 * (i + 1),
+                    y: 0,
+                    z: 0,
+                })
+                .build();
+            tree.entities.push(Box::new(light));
+        }
+        let mut heavy = Entity::builder()
+            .at(Vec3 {
+                x: 2000,
+                y: 2000,
+                z: 2000,
+            })
+            .radius(500)
+            .mass(5.0)
+            .build();
+        heavy.collision_priority = heavy_priority;
+        let heavy_id = heavy.id;
+        tree.entities.push(Box::new(heavy));
+
+        tree.apply_neighbourhood_collisions(&mut vec![]);
+        let heavy = tree
+            .entities
+            .iter()
+            .find(|entity| entity.id == heavy_id)
+            .unwrap();
+        heavy.speed.x.abs()
+    }
+
+    #[test]
+    fn prioritized_heavy_entity_is_less_perturbed() {
+        let uniform = heavy_speed_after_collisions(0);
+        let prioritized = heavy_speed_after_collisions(1);
+        assert!(prioritized > 0);
+        assert!(prioritized < uniform);
+    }
+
+    fn nb_collision_checks(positions: &[(i64, i64, i64)]) -> usize {
+        let mut tree = MatterTree::new();
+        tree.add_entities(
+            positions
+                .iter()
+                .map(|(x, y, z)| {
+                    let center = Vec3 {
+                        x: *x,
+                        y: *y,
+                        z: *z,
+                    };
+                    Box::new(Entity::builder().at(center).radius(10).build())
+                })
+                .collect(),
+        );
+        let mut logger = MigrationLogger::new(1);
+        for _ in 0..3 {
+            tree.refresh(0, &mut logger);
+        }
+        crate::entity::NB_COLLISION_CHECKS.with(|nb_checks| nb_checks.set(0));
+        tree.apply_neighbourhood_collisions(&mut vec![]);
+        crate::entity::NB_COLLISION_CHECKS.with(|nb_checks| nb_checks.get())
+    }
+
+    #[test]
+    fn separated_entities_are_not_compared() {
+        let corners: Vec<_> = (0..NB_QUADRANTS)
+            .map(|i| {
+                let coord = |bit: usize| if i & bit == 0 { -8_000 } else { 8_000 };
+                (coord(1), coord(2), coord(4))
+            })
+            .collect();
+        assert_eq!(nb_collision_checks(&corners), 0);
+
+        let cluster: Vec<_> = (0..8).map(|i| (i * 15, 0, 0)).collect();
+        assert!(nb_collision_checks(&cluster) > 0);
+    }
+
+    #[test]
+    fn entity_along_a_border_stays_at_its_level() {
+        let mut tree = MatterTree::new();
+        let anchor = Entity::builder()
+            .at(Vec3 {
+                x: -5000,
+                y: -5000,
+                z: -5000,
+            })
+            .radius(10)
+            .build();
+        // Its left side goes back and forth around the x = 8192 quadrant border
+        let hopper = Entity::builder()
+            .at(Vec3 {
+                x: 8202,
+                y: 4000,
+                z: 4000,
+            })
+            .radius(10)
+            .speed(Vec3 { x: 2, y: 0, z: 0 })
+            .build();
+        let hopper_id = hopper.id;
+        let hopper_pos = hopper.bounding_sphere.center;
+        tree.add_entities(vec![Box::new(anchor), Box::new(hopper)]);
+        let mut logger = MigrationLogger::new(1);
+        tree.refresh(0, &mut logger);
+        // It does not go down into the x >= 8192 quadrant, too close to its border
+        let cell_size = cell_size_of(&tree, hopper_id).unwrap();
+        assert_eq!(cell_size, 16384);
+
+        let hopper = find_entity_mut(&mut tree, hopper_id).unwrap();
+        assert_eq!(hopper.bounding_sphere.center, hopper_pos);
+
+        for tick in 0..20 {
+            tree.run_movements(1.0);
+            tree.refresh(0, &mut logger);
+            assert_eq!(cell_size_of(&tree, hopper_id), Some(cell_size));
+            if tick % 2 == 1 {
+                let hopper = find_entity_mut(&mut tree, hopper_id).unwrap();
+                hopper.speed = hopper.speed.mul_scalar(-1);
+            }
+        }
+    }
+
+    fn refreshed_tree(entities: Entities) -> MatterTree {
+        let mut tree = MatterTree::new();
+        tree.add_entities(entities);
+        for _ in 0..3 {
+            tree.refresh(0, &mut MigrationLogger::new(1));
+        }
+        tree
+    }
+
+    fn entity_at(x: i64, y: i64, z: i64, radius: i64) -> Box<Entity> {
+        Box::new(
+            Entity::builder()
+                .at(Vec3 { x, y, z })
+                .radius(radius)
+                .build(),
+        )
+    }
+
+    fn nb_nodes(tree: &MatterTree) -> usize {
+        1 + tree
+            .sub_trees
+            .iter()
+            .flatten()
+            .map(|sub_tree| nb_nodes(sub_tree))
+            .sum::<usize>()
+    }
+
+    #[test]
+    fn sphere_query_returns_the_entities_in_range_only() {
+        let near = entity_at(100, 100, 100, 10);
+        let spanning = entity_at(0, 0, 0, 5000);
+        let (near_id, spanning_id) = (near.id, spanning.id);
+        let tree = refreshed_tree(vec![
+            near,
+            entity_at(-9000, -9000, 9000, 10),
+            entity_at(9000, 9000, 9000, 10),
+            spanning,
+        ]);
+
+        let query = Sphere {
+            center: Vec3 {
+                x: 120,
+                y: 120,
+                z: 120,
+            },
+            radius: 50,
+        };
+        let mut ids: Vec<u64> = tree
+            .entities_in_sphere(&query)
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![near_id, spanning_id]);
+
+        // The quadrants of the far entities are not visited
+        let mut found = vec![];
+        let nb_visited = tree.collect_entities_in_sphere(&query, &mut found);
+        assert_eq!(found.len(), 2);
+        assert!(nb_visited < nb_nodes(&tree));
+    }
+
+    #[test]
+    fn raycast_hits_the_first_entered_sphere() {
+        let first = entity_at(1000, 0, 0, 50);
+        let off_axis = entity_at(2000, 2000, 2000, 300);
+        let (first_id, off_axis_id) = (first.id, off_axis.id);
+        let tree = refreshed_tree(vec![
+            first,
+            entity_at(3000, 0, 0, 50),
+            entity_at(-2000, 500, 0, 50),
+            off_axis,
+        ]);
+
+        let origin = Vec3 {
+            x: -100,
+            y: 0,
+            z: 0,
+        };
+        let along_x = Vec3 { x: 1, y: 0, z: 0 };
+        let (hit, dist) = tree.raycast(origin, along_x, 100000).unwrap();
+        assert_eq!(hit.id, first_id);
+        assert_eq!(dist, 1050);
+        let (hit, _) = tree
+            .raycast(Vec3::ZERO, Vec3 { x: 1, y: 1, z: 1 }, 100000)
+            .unwrap();
+        assert_eq!(hit.id, off_axis_id);
+    }
+
+    #[test]
+    fn raycast_misses_short_and_empty_rays() {
+        let tree = refreshed_tree(vec![
+            entity_at(1000, 0, 0, 50),
+            entity_at(3000, 0, 0, 50),
+            entity_at(-2000, 500, 0, 50),
+        ]);
+        let origin = Vec3 {
+            x: -100,
+            y: 0,
+            z: 0,
+        };
+        assert!(tree
+            .raycast(origin, Vec3 { x: 1, y: 0, z: 0 }, 1000)
+            .is_none());
+        assert!(tree
+            .raycast(origin, Vec3 { x: 0, y: 0, z: -1 }, 100000)
+            .is_none());
+    }
+
+    #[test]
+    fn nearest_entity_matches_a_brute_force_scan() {
+        // Fixed seed LCG, for a reproducible scattering
+        let mut state: u64 = 12345;
+        let mut random_coord = move || {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((state >> 33) % 20000) as i64 - 10000
+        };
+        let mut entities = vec![];
+        for _ in 0..200 {
+            let (x, y, z) = (random_coord(), random_coord(), random_coord());
+            let radius = random_coord().abs() % 200 + 1;
+            entities.push(entity_at(x, y, z, radius));
+        }
+        let tree = refreshed_tree(entities);
+
+        for _ in 0..50 {
+            let point = Vec3 {
+                x: random_coord(),
+                y: random_coord(),
+                z: random_coord(),
+            };
+            let mut brute_force: Option<(u64, f64)> = None;
+            tree.for_each_entity(&mut |entity| {
+                let dist = entity.bounding_sphere.center.sub(&point).length_f64();
+                if brute_force.is_none_or(|(_, best)| dist < best) {
+                    brute_force = Some((entity.id, dist));
+                }
+            });
+            let (nearest, dist) = tree.nearest_entity(&point).unwrap();
+            assert_eq!(Some((nearest.id, dist)), brute_force);
+        }
+        assert!(MatterTree::new().nearest_entity(&Vec3::ZERO).is_none());
+    }
+
+    #[test]
+    fn iterators_visit_parent_level_and_leaf_entities() {
+        let big = entity_at(0, 0, 0, 3000);
+        let small = entity_at(5000, 5000, 5000, 10);
+        let (big_id, small_id) = (big.id, small.id);
+        let mut tree = refreshed_tree(vec![
+            big,
+            small,
+            entity_at(-5000, 100, 7000, 10),
+            entity_at(200, -9000, 100, 40),
+        ]);
+        // The big entity stays in the root, the small one sinks to a leaf
+        assert!(tree.entities.iter().any(|e| e.id == big_id));
+        assert!(!tree.entities.iter().any(|e| e.id == small_id));
+
+        assert_eq!(tree.iter_entities().count(), tree.nb_entities());
+        let ids: Vec<u64> = tree.iter_entities().map(|e| e.id).collect();
+        assert!(ids.contains(&big_id) && ids.contains(&small_id));
+        let mut visited_ids = vec![];
+        tree.for_each_entity(&mut |e| visited_ids.push(e.id));
+        assert_eq!(ids, visited_ids);
+
+        for entity in tree.iter_entities_mut() {
+            entity.speed.x = 7;
+        }
+        assert!(tree.iter_entities().all(|e| e.speed.x == 7));
+    }
+
+    // Checks the cached mass aggregates of every node against a recomputation from its entities
+    fn check_mass_aggregates(tree: &MatterTree) {
+        let mut mass = 0.0;
+        let mut moment = [0.0; 3];
+        tree.for_each_entity(&mut |entity| {
+            mass += entity.mass;
+            moment[0] += entity.bounding_sphere.center.x as f64 * entity.mass;
+            moment[1] += entity.bounding_sphere.center.y as f64 * entity.mass;
+            moment[2] += entity.bounding_sphere.center.z as f64 * entity.mass;
+        });
+        assert_eq!(tree.contained_mass, mass);
+        if mass > 0.0 {
+            let center = tree.center_of_mass();
+            // Each level rounds its center of mass
+            let tolerance = 1.0 + tree.scale as f64;
+            assert!((center.x as f64 - moment[0] / mass).abs() <= tolerance);
+            assert!((center.y as f64 - moment[1] / mass).abs() <= tolerance);
+            assert!((center.z as f64 - moment[2] / mass).abs() <= tolerance);
+        }
+        for sub_tree in tree.sub_trees.iter().flatten() {
+            check_mass_aggregates(sub_tree);
+        }
+    }
+
+    // Entities of integer masses spread over the tree, with various speeds
+    fn moving_entities() -> Entities {
+        (0..20)
+            .map(|i: i64| {
+                let pos = Vec3 {
+                    x: (i * 1543) % 30_000 - 15_000,
+                    y: (i * 7919) % 30_000 - 15_000,
+                    z: (i * 3571) % 30_000 - 15_000,
+                };
+                Box::new(
+                    Entity::builder()
+                        .at(pos)
+                        .radius(5 + i)
+                        .mass(1.0 + i as f64)
+                        .speed(Vec3 {
+                            x: 40 - 4 * i,
+                            y: 3 * i - 30,
+                            z: i % 7 * 11,
+                        })
+                        .build(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mass_aggregates_follow_the_movements() {
+        let mut tree = MatterTree::new();
+        tree.add_entities(moving_entities());
+        check_mass_aggregates(&tree);
+        let mut logger = MigrationLogger::new(1);
+        for _ in 0..20 {
+            tree.run_movements(1.0);
+            assert!(tree.refresh(0, &mut logger).is_empty());
+            check_mass_aggregates(&tree);
+        }
+    }
+
+    #[test]
+    fn contained_mass_follows_insertions_migrations_and_removals() {
+        let entity = |x, mass, speed_x| {
+            Box::new(
+                Entity::builder()
+                    .at(Vec3 {
+                        x,
+                        y: 1000,
+                        z: 1000,
+                    })
+                    .radius(10)
+                    .mass(mass)
+                    .speed(Vec3 {
+                        x: speed_x,
+                        y: 0,
+                        z: 0,
+                    })
+                    .build(),
+            )
+        };
+        let mut tree = MatterTree::new();
+        tree.add_entities(vec![entity(1000, 2.0, 0), entity(3000, 6.0, 0)]);
+        tree.add_entities(vec![entity(-5000, 1.0, 900), entity(100, 3.0, 0)]);
+        assert_eq!(tree.contained_mass, 12.0);
+        check_mass_aggregates(&tree);
+
+        // The mover crosses several cells
+        let mut logger = MigrationLogger::new(1);
+        for _ in 0..12 {
+            tree.run_movements(1.0);
+            tree.refresh(0, &mut logger);
+            check_mass_aggregates(&tree);
+        }
+        assert_eq!(tree.contained_mass, 12.0);
+
+        tree.drain_filter(&mut |entity| entity.mass == 6.0);
+        assert_eq!(tree.contained_mass, 6.0);
+        check_mass_aggregates(&tree);
+    }
+
+    fn relative_error(approx: [f64; 3], exact: [f64; 3]) -> f64 {
+        let norm = |v: [f64; 3]| (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+        norm([
+            approx[0] - exact[0],
+            approx[1] - exact[1],
+            approx[2] - exact[2],
+        ]) / norm(exact)
+    }
+
+    #[test]
+    fn barnes_hut_gravity_is_close_to_the_pairwise_sum() {
+        // Fixed seed LCG, for two reproducible clusters
+        let mut state: u64 = 99;
+        let mut random = move || {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((state >> 33) % 10000) as i64
+        };
+        let mut entities: Entities = vec![];
+        for i in 0..60 {
+            let base = if i < 30 { -8000 } else { 3000 };
+            let pos = Vec3 {
+                x: base + random() / 2,
+                y: base + random() / 3,
+                z: random() / 2,
+            };
+            let mass = 1.0e6 + random() as f64 * 100.0;
+            entities.push(Box::new(
+                Entity::builder().at(pos).radius(10).mass(mass).build(),
+            ));
+        }
+        let mut tree = refreshed_tree(entities);
+
+        for entity in tree.iter_entities() {
+            let pos = entity.bounding_sphere.center;
+            let mut exact = [0.0; 3];
+            for other in tree.iter_entities().filter(|other| other.id != entity.id) {
+                let gap = other.bounding_sphere.center.sub(&pos);
+                let dist = gap.length_f64();
+                let factor = other.mass / (dist * dist * dist);
+                exact[0] += factor * gap.x as f64;
+                exact[1] += factor * gap.y as f64;
+                exact[2] += factor * gap.z as f64;
+            }
+            let pairwise = tree.gravity_acceleration(&pos, entity.id, 0.0, 1.0);
+            assert!(relative_error(pairwise, exact) < 1e-9);
+            let barnes_hut = tree.gravity_acceleration(&pos, entity.id, 0.5, 1.0);
+            assert!(relative_error(barnes_hut, exact) < 0.05);
+        }
+
+        tree.apply_gravity(0.5, 1.0);
+        assert!(tree
+            .iter_entities()
+            .all(|e| e.external_forces != Vec3::ZERO));
+    }
+
+    #[test]
+    fn touching_pairs_match_a_brute_force_scan() {
+        // Fixed seed LCG, for a reproducible mix of tiny, medium and big overlapping entities
+        let mut state: u64 = 7;
+        let mut random = move |n: u64| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((state >> 33) % n) as i64
+        };
+        let mut entities = vec![];
+        for _ in 0..300 {
+            let radius = match random(3) {
+                0 => 5 + random(20),
+                1 => 50 + random(200),
+                _ => 500 + random(1500),
+            };
+            let (x, y, z) = (random(12000), random(12000), random(12000));
+            entities.push(entity_at(x - 6000, y - 6000, z - 6000, radius));
+        }
+        let mut tree = refreshed_tree(entities);
+
+        let spheres: Vec<(u64, Sphere)> = tree
+            .iter_entities()
+            .map(|e| (e.id, e.bounding_sphere))
+            .collect();
+        let mut expected = vec![];
+        for (i, (a_id, a)) in spheres.iter().enumerate() {
+            for (b_id, b) in spheres[i + 1..].iter() {
+                if a.intersects(b) {
+                    expected.push((*a_id.min(b_id), *a_id.max(b_id)));
+                }
+            }
+        }
+        expected.sort_unstable();
+
+        let mut found = vec![];
+        tree.for_each_touching_pair(&mut |a, b| found.push((a.id.min(b.id), a.id.max(b.id))));
+        found.sort_unstable();
+        // Each pair is found exactly once
+        assert_eq!(found, expected);
+    }
+
+    fn check_sizes(tree: &MatterTree, config: MatterTreeConfig) {
+        assert_eq!(tree.config, config);
+        assert_eq!(tree.area.size, 1 << (config.min_size_pow + tree.scale));
+        for sub_tree in tree.sub_trees.iter().flatten() {
+            assert_eq!(sub_tree.scale + 1, tree.scale);
+            check_sizes(sub_tree, config);
+        }
+    }
+
+    fn run_config(config: MatterTreeConfig) {
+        let mut tree = MatterTree::new_with_config(config).unwrap();
+        assert_eq!(tree.scale, config.max_scale);
+        assert_eq!(tree.area.size, config.size());
+
+        let h = config.size() / 4;
+        let entity = |x, y, z| Box::new(Entity::builder().at(Vec3 { x, y, z }).radius(1).build());
+        tree.add_entities(vec![entity(h, h, h), entity(-h, h, -h), entity(1, 1, 1)]);
+        let mut logger = MigrationLogger::new(1);
+        for _ in 0..config.max_scale + 2 {
+            assert!(tree.refresh(0, &mut logger).is_empty());
+        }
+        check_sizes(&tree, config);
+        assert_eq!(tree.nb_entities(), 3);
+        assert!(tree.sub_trees.iter().any(|sub_tree| sub_tree.is_some()));
+
+        let mut out = Encoder::new();
+        tree.encode(&mut out);
+        let bytes = out.into_bytes();
+        let decoded = MatterTree::decode(&mut Decoder::new(&bytes, VOXEL_SCHEMA_VERSION)).unwrap();
+        assert_eq!(decoded, tree);
+    }
+
+    #[test]
+    fn tiny_config() {
+        run_config(MatterTreeConfig {
+            min_size_pow: 5,
+            max_scale: 2,
+        });
+    }
+
+    #[test]
+    fn large_config() {
+        run_config(MatterTreeConfig {
+            min_size_pow: 5,
+            max_scale: 50,
+        });
+    }
+
+    #[test]
+    fn too_large_config() {
+        let config = MatterTreeConfig {
+            min_size_pow: 5,
+            max_scale: 60,
+        };
+        assert_eq!(
+            MatterTree::new_with_config(config),
+            Err(MatterTreeConfigError::TooLarge(config))
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use crate::geometry::Cube;
+use std::time::{Duration, Instant};
+
+// Logs entities changing cell, at most max_per_second of them each second. The ones beyond are only
+// counted, and summarized once the second is over.
+#[derive(Debug, Clone)]
+pub struct MigrationLogger {
+    pub max_per_second: u32,
+    window_start: Option<Instant>,
+    nb_logged: u32,
+    nb_skipped: u64,
+}
+
+impl MigrationLogger {
+    pub fn new(max_per_second: u32) -> Self {
+        Self {
+            max_per_second,
+            window_start: None,
+            nb_logged: 0,
+            nb_skipped: 0,
+        }
+    }
+
+    pub fn log(&mut self, id: u64, from: &Cube) {
+        if !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        let now = Instant::now();
+        match self.window_start {
+            Some(start) if now.duration_since(start) < Duration::from_secs(1) => (),
+            _ => {
+                self.flush();
+                self.window_start = Some(now);
+            }
+        }
+        if self.nb_logged < self.max_per_second {
+            self.nb_logged += 1;
+            log::debug!("Entity {} left cell {:?}", id, from);
+        } else {
+            self.nb_skipped += 1;
+        }
+    }
+
+    // Reports the migrations that were not logged in the current window
+    pub fn flush(&mut self) {
+        if self.nb_skipped > 0 {
+            log::debug!("{} more cell migrations were not logged", self.nb_skipped);
+        }
+        self.nb_logged = 0;
+        self.nb_skipped = 0;
+    }
+}
+
+// The current window is runtime state that is not saved: only the configuration is compared
+impl PartialEq for MigrationLogger {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_per_second == other.max_per_second
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Vec3;
+    use std::sync::Mutex;
+    use std::thread::{self, ThreadId};
+
+    // Keeps the messages of each thread, so that parallel tests do not see each other's records
+    struct CapturingLogger;
+
+    static RECORDS: Mutex<Vec<(ThreadId, String)>> = Mutex::new(Vec::new());
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            RECORDS
+                .lock()
+                .unwrap()
+                .push((thread::current().id(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn thread_records() -> Vec<String> {
+        let id = thread::current().id();
+        RECORDS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(thread_id, _)| *thread_id == id)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn logs_are_capped_and_the_rest_counted() {
+        static LOGGER: CapturingLogger = CapturingLogger;
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let mut logger = MigrationLogger::new(5);
+        let from = Cube {
+            origin: Vec3::ZERO,
+            size: 32,
+        };
+        for id in 0..100 {
+            logger.log(id, &from);
+        }
+        logger.flush();
+
+        let records = thread_records();
+        assert_eq!(records.len(), 6);
+        assert!(records[..5]
+            .iter()
+            .all(|record| record.starts_with("Entity ")));
+        assert_eq!(records[5], "95 more cell migrations were not logged");
+    }
+}
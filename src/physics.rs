@@ -0,0 +1,8 @@
+use crate::geometry::Vec3;
+
+// Anything moved by the physics step. dt is the duration to integrate over, in ticks.
+pub trait Integrable {
+    fn position(&self) -> Vec3;
+    fn velocity(&self) -> Vec3;
+    fn integrate(&mut self, dt: f64);
+}
@@ -1,14 +1,29 @@
-use crate::geometry::Vec3;
+use crate::entity::Entity;
+use crate::geometry::{Mat3, Vec3};
 
 pub const MASS: f64 = 100.0;
 pub const RADIUS: i64 = 200;
 pub const CONTROL_FORCE: i64 = 1000;
+// Farthest voxel a player can mine or place a voxel against
+pub const TOOL_REACH: i64 = 2000;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Player {
     pub control_forces: Vec3,
     pub drop_block: bool,
     pub drop_block_fixed: bool,
+    // Removes the aimed voxel
+    pub mine_voxel: bool,
+    // Adds a voxel against the aimed face
+    pub place_voxel: bool,
+    // Rotation from the player axes (the control inputs) to the world axes
+    pub facing: Mat3,
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Player {
@@ -17,11 +32,87 @@ impl Player {
             control_forces: Vec3::ZERO,
             drop_block: false,
             drop_block_fixed: false,
+            mine_voxel: false,
+            place_voxel: false,
+            facing: Mat3::IDENTITY,
         }
     }
 
+    // dir is in the player axes. It is normalized, so that diagonals push as hard as single axes.
     pub fn control(&mut self, dir: &Vec3) {
+        if *dir == Vec3::ZERO {
+            self.control_forces = Vec3::ZERO;
+            return;
+        }
         let div = dir.length_f64();
-        self.control_forces = dir.mul_scalar(CONTROL_FORCE).div_float(div);
+        // Scaled before the rotation, so that the rotation does not truncate the unit inputs
+        self.control_forces = self
+            .facing
+            .mul_vec(&dir.mul_scalar(CONTROL_FORCE))
+            .div_float(div);
+    }
+
+    // World direction the player looks toward (its +Y axis), scaled by the facing divider
+    pub fn aim(&self) -> Vec3 {
+        self.facing.mul_vec(&Vec3 {
+            x: 0,
+            y: Entity::ORIENTATION_DIVIDER,
+            z: 0,
+        })
+    }
+
+    // delta is expressed in the player axes
+    pub fn rotate(&mut self, delta: Mat3) {
+        self.facing = self
+            .facing
+            .mul_mat(&delta)
+            .with_divider(Entity::ORIENTATION_DIVIDER);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Axis;
+
+    #[test]
+    fn forward_thrust_follows_the_facing() {
+        let forward = Vec3 { x: 1, y: 0, z: 0 };
+        let mut player = Player::new();
+        player.control(&forward);
+        assert_eq!(
+            player.control_forces,
+            Vec3 {
+                x: CONTROL_FORCE,
+                y: 0,
+                z: 0,
+            }
+        );
+
+        player.rotate(Mat3::rotation_from_degrees(Axis::Z, 90.0));
+        player.control(&forward);
+        assert_eq!(
+            player.control_forces,
+            Vec3 {
+                x: 0,
+                y: CONTROL_FORCE,
+                z: 0,
+            }
+        );
+
+        player.rotate(Mat3::rotation_from_degrees(Axis::Z, 45.0));
+        player.control(&forward);
+        let diagonal = (CONTROL_FORCE as f64 / 2f64.sqrt()) as i64;
+        assert!((player.control_forces.x + diagonal).abs() <= 1);
+        assert!((player.control_forces.y - diagonal).abs() <= 1);
+    }
+
+    #[test]
+    fn no_direction_gives_zero_forces() {
+        let mut player = Player::new();
+        player.control(&Vec3 { x: 1, y: 1, z: 0 });
+        assert!(player.control_forces.length_f64() <= CONTROL_FORCE as f64);
+        player.control(&Vec3::ZERO);
+        assert_eq!(player.control_forces, Vec3::ZERO);
     }
 }
@@ -0,0 +1,236 @@
+use crate::camera::Camera;
+use crate::entity::EntityData;
+use crate::geometry::Quadrant;
+use crate::matter_tree::MatterTree;
+use crate::space::{EntityKind, Space};
+use crate::space_tree::SpaceTree;
+
+pub struct Colors {
+    pub space_node: u32,
+    pub matter_node: u32,
+    pub player: u32,
+    pub voxels: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Rect {
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+}
+
+impl Rect {
+    // Projection of a quadrant on the XY plane
+    fn quadrant(&self, quadrant: Quadrant) -> Self {
+        let mut sub_area = *self;
+        if quadrant.x_p() {
+            sub_area.x += sub_area.w / 2;
+        }
+        if quadrant.y_p() {
+            sub_area.y += sub_area.h / 2;
+        }
+        sub_area.w /= 2;
+        sub_area.h /= 2;
+        sub_area
+    }
+}
+
+// Pixel buffer of a window, row by row from the top of the screen
+pub struct Screen {
+    pub width: usize,
+    pub height: usize,
+    pub buffer: Vec<u32>,
+}
+
+impl Screen {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            buffer: vec![0; width * height],
+        }
+    }
+
+    fn clear(&mut self) {
+        for i in self.buffer.iter_mut() {
+            *i = 0x00000000;
+        }
+    }
+
+    // Outline of the area, whose y axis goes up from the bottom of the screen
+    fn draw_outline(&mut self, area: &Rect, color: u32) {
+        for y in 0..area.h {
+            for x in 0..area.w {
+                let offset = (self.height - 1 - (area.y + y)) * self.width + area.x + x;
+                if x == 0 || y == 0 || x == area.w - 1 || y == area.h - 1 {
+                    self.buffer[offset] = color;
+                }
+            }
+        }
+    }
+
+    // Disc centered on the (column, row) pixel, row 0 being the top of the screen
+    fn draw_disc(&mut self, x: usize, y: usize, radius: isize, color: u32) {
+        let (width, height) = (self.width as isize, self.height as isize);
+        for y_i in isize::max(y as isize - radius, 0)..isize::min(y as isize + radius, height) {
+            let y_shift = y_i - y as isize;
+            let x_size = f32::sqrt((radius * radius - y_shift * y_shift) as f32) as isize;
+            for x_i in isize::max(x as isize - x_size, 0)..isize::min(x as isize + x_size, width) {
+                self.buffer[y_i as usize * self.width + x_i as usize] = color;
+            }
+        }
+    }
+
+    fn draw_matter_tree(
+        &mut self,
+        colors: &Colors,
+        matter_area: &Rect,
+        area: Rect,
+        tree: &MatterTree,
+    ) {
+        self.draw_outline(&area, colors.matter_node);
+
+        for entity in tree.entities.iter() {
+            let pos = entity.bounding_sphere.center;
+            let x = (pos.x as f64 + MatterTree::MAX_SIZE as f64 / 2.0f64) * matter_area.w as f64
+                / MatterTree::MAX_SIZE as f64
+                + matter_area.x as f64;
+            let y = (pos.y as f64 + MatterTree::MAX_SIZE as f64 / 2.0f64) * matter_area.h as f64
+                / MatterTree::MAX_SIZE as f64
+                + matter_area.y as f64;
+            let x = x as usize;
+            let y = y as usize;
+            let color = match (entity.render_color, &entity.entity) {
+                (Some(color), _) => color,
+                (None, EntityData::Player(_)) => colors.player,
+                (None, EntityData::Voxels(_)) => colors.voxels,
+            };
+
+            let dot_size: isize = usize::max(
+                1,
+                entity.bounding_sphere.radius as usize * matter_area.w
+                    / MatterTree::MAX_SIZE as usize,
+            ) as isize;
+            let (width, height) = (self.width as isize, self.height as isize);
+            for y_i in
+                isize::max(y as isize - dot_size, 0)..isize::min(y as isize + dot_size, height)
+            {
+                let y_shift = y_i - y as isize;
+                let x_size = f32::sqrt((dot_size * dot_size - y_shift * y_shift) as f32) as isize;
+                for x_i in
+                    isize::max(x as isize - x_size, 0)..isize::min(x as isize + x_size, width)
+                {
+                    let offset = (self.height - 1 - y_i as usize) * self.width + x_i as usize;
+                    self.buffer[offset] = color;
+                }
+            }
+        }
+
+        for (i, sub_tree) in tree.sub_trees.iter().enumerate() {
+            if let Some(sub_tree) = sub_tree {
+                let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                self.draw_matter_tree(colors, matter_area, area.quadrant(quadrant), sub_tree);
+            }
+        }
+    }
+
+    fn draw_space_tree(&mut self, colors: &Colors, area: Rect, tree: &SpaceTree) {
+        self.draw_outline(&area, colors.space_node);
+
+        match tree {
+            SpaceTree::Matter(matter) => self.draw_matter_tree(colors, &area, area, matter),
+            SpaceTree::Parent(parent) => {
+                for (i, sub_tree) in parent.sub_trees.iter().enumerate() {
+                    if let Some(tree) = sub_tree {
+                        let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                        self.draw_space_tree(colors, area.quadrant(quadrant), tree)
+                    }
+                }
+            }
+        }
+    }
+
+    // The space tree nodes and the entities, seen from above on the XY plane
+    pub fn draw_space(&mut self, colors: &Colors, space: &Space) {
+        self.clear();
+        let area = Rect {
+            x: 0,
+            y: 0,
+            w: self.width,
+            h: self.height,
+        };
+        self.draw_space_tree(colors, area, &space.tree.tree);
+    }
+
+    // Entity bounding spheres seen from the camera, as dots shrinking with the distance
+    pub fn draw_space_3d(&mut self, colors: &Colors, space: &Space, camera: &Camera) {
+        self.clear();
+
+        let entities = space.render_snapshot().entities;
+        let points: Vec<_> = entities
+            .iter()
+            .map(|entity| (entity.id, entity.center))
+            .collect();
+        // Painter's algorithm
+        for entity in camera
+            .depth_order(&points)
+            .into_iter()
+            .map(|i| &entities[i])
+        {
+            let (x, y) = match camera.project(entity.center) {
+                Some(pixel) => pixel,
+                None => continue,
+            };
+            let (_, _, depth) = camera.view_position(&entity.center);
+            let radius = f64::max(1.0, camera.projected_radius(entity.radius, depth)) as isize;
+            let color = match (entity.color, entity.kind) {
+                (Some(color), _) => color,
+                (None, EntityKind::Player) => colors.player,
+                (None, EntityKind::Voxels) => colors.voxels,
+            };
+            self.draw_disc(x, y, radius, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::Entity;
+    use crate::geometry::Vec3;
+
+    #[test]
+    fn render_color_overrides_the_kind_color() {
+        let colors = Colors {
+            space_node: 0xFFFF0000,
+            matter_node: 0xFF00FF00,
+            voxels: 0xFF0080FF,
+            player: 0xFF8000FF,
+        };
+        let mut space = Space::new();
+        let entity_at = |x: i64| {
+            Entity::builder()
+                .at(Vec3 { x, y: 0, z: 0 })
+                .radius(1000)
+                .build()
+        };
+        let mut colored = entity_at(-8000);
+        colored.render_color = Some(0xFFABCDEF);
+        space.tree.insert_entity(Box::new(colored)).unwrap();
+        space.tree.insert_entity(Box::new(entity_at(8000))).unwrap();
+
+        let (width, height) = (500, 500);
+        let mut screen = Screen::new(width, height);
+        screen.draw_space(&colors, &space);
+        // Pixel of a world position, on the XY plane of a single matter tree
+        let pixel = |x: i64| {
+            let to_pixel = |pos: i64, size: usize| {
+                ((pos + MatterTree::MAX_SIZE / 2) as usize * size) / MatterTree::MAX_SIZE as usize
+            };
+            screen.buffer[(height - 1 - to_pixel(0, height)) * width + to_pixel(x, width)]
+        };
+        assert_eq!(pixel(-8000), 0xFFABCDEF);
+        assert_eq!(pixel(8000), colors.voxels);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::geometry::{Cube, Mat3, Sphere, Vec3};
+use crate::voxel_grid::VoxelDecodeError;
+use std::time::Duration;
+
+// Little endian binary encoding shared by the types saved with a Space
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeserializeError {
+    BadMagic,
+    UnsupportedFormatVersion(u16),
+    UnexpectedEnd,
+    // Enum tag or boolean out of the known values
+    InvalidTag { what: &'static str, tag: u8 },
+    InvalidValue(&'static str),
+    Voxel(VoxelDecodeError),
+    TrailingBytes(usize),
+}
+
+impl From<VoxelDecodeError> for DeserializeError {
+    fn from(error: VoxelDecodeError) -> Self {
+        Self::Voxel(error)
+    }
+}
+
+pub struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self { bytes: vec![] }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn i64(&mut self, value: i64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn len(&mut self, len: usize) {
+        self.u64(len as u64);
+    }
+
+    pub fn vec3(&mut self, value: &Vec3) {
+        self.i64(value.x);
+        self.i64(value.y);
+        self.i64(value.z);
+    }
+
+    pub fn sphere(&mut self, value: &Sphere) {
+        self.vec3(&value.center);
+        self.i64(value.radius);
+    }
+
+    pub fn cube(&mut self, value: &Cube) {
+        self.vec3(&value.origin);
+        self.i64(value.size);
+    }
+
+    pub fn duration(&mut self, value: &Duration) {
+        self.u64(value.as_secs());
+        self.u32(value.subsec_nanos());
+    }
+
+    pub fn mat3(&mut self, value: &Mat3) {
+        self.i64(value.divider);
+        for v in value.values.iter() {
+            self.i64(*v);
+        }
+    }
+
+    // One bit per present cell, the first cell being the lowest bit
+    pub fn presence_mask<T>(&mut self, cells: &[Option<T>]) {
+        let mask = cells
+            .iter()
+            .enumerate()
+            .filter(|(_, cell)| cell.is_some())
+            .fold(0, |mask, (i, _)| mask | (1 << i));
+        self.u8(mask);
+    }
+}
+
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // Schema version of the saved voxel types
+    pub voxel_schema_version: u8,
+}
+
+// len reads a length prefix, the decoder has no size of its own
+#[allow(clippy::len_without_is_empty)]
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8], voxel_schema_version: u8) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            voxel_schema_version,
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], DeserializeError> {
+        if self.remaining() < len {
+            return Err(DeserializeError::UnexpectedEnd);
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DeserializeError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DeserializeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, DeserializeError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DeserializeError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DeserializeError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, DeserializeError> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, DeserializeError> {
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, DeserializeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DeserializeError::InvalidTag { what: "bool", tag }),
+        }
+    }
+
+    // Lengths beyond the remaining bytes are rejected, so that corrupted data cannot trigger huge
+    // allocations
+    pub fn len(&mut self) -> Result<usize, DeserializeError> {
+        let len = self.u64()?;
+        if len > self.remaining() as u64 {
+            return Err(DeserializeError::UnexpectedEnd);
+        }
+        Ok(len as usize)
+    }
+
+    pub fn vec3(&mut self) -> Result<Vec3, DeserializeError> {
+        Ok(Vec3 {
+            x: self.i64()?,
+            y: self.i64()?,
+            z: self.i64()?,
+        })
+    }
+
+    pub fn sphere(&mut self) -> Result<Sphere, DeserializeError> {
+        Ok(Sphere {
+            center: self.vec3()?,
+            radius: self.i64()?,
+        })
+    }
+
+    pub fn cube(&mut self) -> Result<Cube, DeserializeError> {
+        Ok(Cube {
+            origin: self.vec3()?,
+            size: self.i64()?,
+        })
+    }
+
+    pub fn duration(&mut self) -> Result<Duration, DeserializeError> {
+        let secs = self.u64()?;
+        let nanos = self.u32()?;
+        if nanos >= 1_000_000_000 {
+            return Err(DeserializeError::InvalidValue("duration nanoseconds"));
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+
+    pub fn mat3(&mut self) -> Result<Mat3, DeserializeError> {
+        let divider = self.i64()?;
+        if divider == 0 {
+            return Err(DeserializeError::InvalidValue("matrix divider"));
+        }
+        let mut values = [0; 9];
+        for value in values.iter_mut() {
+            *value = self.i64()?;
+        }
+        Ok(Mat3 { divider, values })
+    }
+
+    // Whether each of the 8 cells is present
+    pub fn presence_mask(&mut self) -> Result<[bool; 8], DeserializeError> {
+        let mask = self.u8()?;
+        let mut ret = [false; 8];
+        for (i, present) in ret.iter_mut().enumerate() {
+            *present = mask & (1 << i) != 0;
+        }
+        Ok(ret)
+    }
+}
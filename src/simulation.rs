@@ -0,0 +1,218 @@
+use crate::serialization::{Decoder, DeserializeError, Encoder};
+use crate::space::{RenderSnapshot, Space, TickResult};
+use crate::voxel_grid::VOXEL_SCHEMA_VERSION;
+use std::time::Duration;
+
+// Runs a space at a fixed tick rate, whatever the rate at which it is advanced
+#[derive(Debug, Clone, PartialEq)]
+pub struct Simulation {
+    pub space: Space,
+    // Real time a tick stands for
+    pub tick_duration: Duration,
+    // Real time elapsed and not simulated yet
+    accumulator: Duration,
+    paused: bool,
+    pub nb_ticks: u64,
+    // Results of the ticks run since the recording started, None when not recording
+    recorder: Option<Vec<TickResult>>,
+}
+
+impl Simulation {
+    pub fn new(space: Space, tick_duration: Duration) -> Self {
+        Self {
+            space,
+            tick_duration,
+            accumulator: Duration::ZERO,
+            paused: false,
+            nb_ticks: 0,
+            recorder: None,
+        }
+    }
+
+    // Runs as many ticks as fit in the elapsed time, the remainder being kept for the next call
+    pub fn advance(&mut self, real_elapsed: Duration) -> Vec<TickResult> {
+        if self.paused {
+            return vec![];
+        }
+        self.accumulator += real_elapsed;
+        let mut results = vec![];
+        while self.accumulator >= self.tick_duration {
+            self.accumulator -= self.tick_duration;
+            results.push(self.space.step());
+            self.nb_ticks += 1;
+        }
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.extend(results.iter().cloned());
+        }
+        results
+    }
+
+    // Keeps the results of the following ticks, dropping any previous recording
+    pub fn start_recording(&mut self) {
+        self.recorder = Some(vec![]);
+    }
+
+    // Returns the results recorded since start_recording, empty when not recording
+    pub fn stop_recording(&mut self) -> Vec<TickResult> {
+        self.recorder.take().unwrap_or_default()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // Time elapsed while paused is not simulated afterwards
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn snapshot_for_render(&self) -> RenderSnapshot {
+        self.space.render_snapshot()
+    }
+
+    // The space save followed by the tick state. The recording is not saved.
+    pub fn save(&self) -> Vec<u8> {
+        let mut out = Encoder::new();
+        let space = self.space.serialize();
+        out.len(space.len());
+        out.bytes(&space);
+        out.duration(&self.tick_duration);
+        out.duration(&self.accumulator);
+        out.bool(self.paused);
+        out.u64(self.nb_ticks);
+        out.into_bytes()
+    }
+
+    pub fn load(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        // The tick state holds no voxels
+        let mut input = Decoder::new(bytes, VOXEL_SCHEMA_VERSION);
+        let len = input.len()?;
+        let space = Space::deserialize(input.bytes(len)?)?;
+        let tick_duration = input.duration()?;
+        if tick_duration.is_zero() {
+            return Err(DeserializeError::InvalidValue("tick duration"));
+        }
+        let accumulator = input.duration()?;
+        let paused = input.bool()?;
+        let nb_ticks = input.u64()?;
+        if input.remaining() > 0 {
+            return Err(DeserializeError::TrailingBytes(input.remaining()));
+        }
+        Ok(Self {
+            space,
+            tick_duration,
+            accumulator,
+            paused,
+            nb_ticks,
+            recorder: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::Entity;
+    use crate::geometry::Vec3;
+
+    fn colliding_simulation() -> Simulation {
+        let mut space = Space::new();
+        for (x, speed) in &[(-300, 30), (300, -30)] {
+            let entity = Entity::builder()
+                .at(Vec3 { x: *x, y: 0, z: 0 })
+                .radius(100)
+                .mass(1.0)
+                .speed(Vec3 {
+                    x: *speed,
+                    y: 0,
+                    z: 0,
+                })
+                .build();
+            space.tree.insert_entity(Box::new(entity)).unwrap();
+        }
+        Simulation::new(space, Duration::from_millis(10))
+    }
+
+    #[test]
+    fn irregular_elapsed_times_give_fixed_ticks() {
+        let elapsed_times = [3, 25, 7, 0, 15, 50];
+        let mut simulation = colliding_simulation();
+        let mut results = vec![];
+        for ms in elapsed_times.iter() {
+            results.extend(simulation.advance(Duration::from_millis(*ms)));
+        }
+        assert_eq!(results.len(), 10);
+        assert_eq!(simulation.nb_ticks, 10);
+        // The entities meet during the seventh tick
+        let nb_collisions: usize = results.iter().map(|result| result.collisions.len()).sum();
+        assert!(nb_collisions > 0);
+
+        // The same elapsed times give the same results, however they are split
+        let mut regular = colliding_simulation();
+        let regular_results = regular.advance(Duration::from_millis(100));
+        assert_eq!(regular_results.len(), results.len());
+        let collisions = |results: &[TickResult]| {
+            results
+                .iter()
+                .map(|result| result.collisions.len())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(collisions(&regular_results), collisions(&results));
+
+        simulation.pause();
+        assert!(simulation.advance(Duration::from_secs(1)).is_empty());
+        simulation.resume();
+        assert_eq!(simulation.advance(Duration::from_millis(10)).len(), 1);
+    }
+
+    #[test]
+    fn recorder_collects_the_advanced_ticks() {
+        let mut simulation = colliding_simulation();
+        simulation.advance(Duration::from_millis(20));
+        assert!(simulation.stop_recording().is_empty());
+
+        simulation.start_recording();
+        let mut results = simulation.advance(Duration::from_millis(35));
+        results.extend(simulation.advance(Duration::from_millis(50)));
+        assert!(simulation.is_recording());
+        let recorded = simulation.stop_recording();
+        assert_eq!(recorded.len(), 8);
+        assert_eq!(recorded, results);
+        assert!(!simulation.is_recording());
+    }
+
+    #[test]
+    fn save_and_load_keep_the_tick_state() {
+        let mut simulation = colliding_simulation();
+        simulation.advance(Duration::from_millis(45));
+        simulation.pause();
+
+        let mut loaded = Simulation::load(&simulation.save()).unwrap();
+        assert_eq!(loaded, simulation);
+        assert_eq!(loaded.nb_ticks, 4);
+        assert!(loaded.is_paused());
+
+        // The saved remainder of 5ms is simulated after resuming
+        simulation.resume();
+        loaded.resume();
+        let results = simulation.advance(Duration::from_millis(55));
+        assert_eq!(results.len(), 6);
+        assert_eq!(loaded.advance(Duration::from_millis(55)), results);
+        assert_eq!(loaded, simulation);
+
+        let mut bytes = simulation.save();
+        bytes.push(0);
+        assert_eq!(
+            Simulation::load(&bytes),
+            Err(DeserializeError::TrailingBytes(1))
+        );
+    }
+}
@@ -1,20 +1,664 @@
+use crate::entity::EntityData;
+use crate::geometry::{Cube, Vec3};
+use crate::serialization::{Decoder, DeserializeError, Encoder};
 use crate::space_tree::GrowableSpaceTree;
+use crate::voxel_grid::{VOXEL_SCHEMA_VERSION, VOXEL_SIZE};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpaceConfiguration {
+    // Space coordinates are integers, this is how many of them make a meter (centimeters by
+    // default). Speeds are in space units per tick, and forces in mass * space units per tick^2.
+    pub units_per_meter: f64,
+    // Simulated time of each run call, in ticks. Scaling it changes the simulation speed without
+    // changing the rate at which run is called.
+    pub tick_size: f64,
+    // Gravitational constant, in space units^3 / (mass * tick^2). 0 disables gravity.
+    pub gravity_constant: f64,
+    // Opening ratio of the Barnes-Hut gravity approximation, see MatterTree::apply_gravity
+    pub gravity_theta: f64,
+}
+
+impl Default for SpaceConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpaceConfiguration {
+    pub fn new() -> Self {
+        Self {
+            units_per_meter: 100.0,
+            tick_size: 1.0,
+            gravity_constant: 0.0,
+            gravity_theta: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CollisionEvent {
+    pub a: u64,
+    pub b: u64,
+}
+
+// An entity moving from a matter node to another one
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CellTransition {
+    pub id: u64,
+    pub from: Cube,
+    pub to: Cube,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TickResult {
+    // Pairs of entities whose contact was resolved during the tick
+    pub collisions: Vec<CollisionEvent>,
+    pub spawned: Vec<u64>,
+    // No part of the tick removes entities yet, so this stays empty. It is filled the same way as
+    // spawned, from the entities present before and after the tick.
+    pub despawned: Vec<u64>,
+    pub transitions: Vec<CellTransition>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EntityKind {
+    Player,
+    Voxels,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedEntity {
+    pub id: u64,
+    pub kind: EntityKind,
+    // Set when the entity color overrides the one of its kind
+    pub color: Option<u32>,
+    // World coordinates
+    pub center: Vec3,
+    pub radius: i64,
+    // World position of the origin corner of each solid voxel
+    pub voxels: Vec<Vec3>,
+}
+
+// Owned copy of what is needed to draw the world, so that a render thread can draw it while the
+// next ticks run
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderSnapshot {
+    pub entities: Vec<RenderedEntity>,
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Space {
     pub tree: GrowableSpaceTree,
+    pub configuration: SpaceConfiguration,
+}
+
+impl Default for Space {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Space {
     pub fn new() -> Self {
+        Self::new_with_configuration(SpaceConfiguration::new())
+    }
+
+    pub fn new_with_configuration(configuration: SpaceConfiguration) -> Self {
         Self {
             tree: GrowableSpaceTree::new(),
+            configuration,
+        }
+    }
+
+    pub fn to_meters(&self, v: Vec3) -> (f64, f64, f64) {
+        let units_per_meter = self.configuration.units_per_meter;
+        (
+            v.x as f64 / units_per_meter,
+            v.y as f64 / units_per_meter,
+            v.z as f64 / units_per_meter,
+        )
+    }
+
+    // Inverse of to_meters, rounded to the nearest unit
+    pub fn meters_to_units(&self, v: (f64, f64, f64)) -> Vec3 {
+        let units_per_meter = self.configuration.units_per_meter;
+        Vec3 {
+            x: (v.0 * units_per_meter).round() as i64,
+            y: (v.1 * units_per_meter).round() as i64,
+            z: (v.2 * units_per_meter).round() as i64,
         }
     }
 
+    // Saves start with a header: the magic bytes, the format version and the voxel schema version
+    pub const SAVE_MAGIC: [u8; 4] = *b"SPCE";
+    pub const SAVE_FORMAT_VERSION: u16 = 1;
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Encoder::new();
+        out.bytes(&Self::SAVE_MAGIC);
+        out.u16(Self::SAVE_FORMAT_VERSION);
+        out.u8(VOXEL_SCHEMA_VERSION);
+        out.f64(self.configuration.units_per_meter);
+        out.f64(self.configuration.tick_size);
+        out.f64(self.configuration.gravity_constant);
+        out.f64(self.configuration.gravity_theta);
+        self.tree.encode(&mut out);
+        out.into_bytes()
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, DeserializeError> {
+        let mut input = Decoder::new(bytes, VOXEL_SCHEMA_VERSION);
+        if input.bytes(Self::SAVE_MAGIC.len())? != Self::SAVE_MAGIC {
+            return Err(DeserializeError::BadMagic);
+        }
+        let version = input.u16()?;
+        if version != Self::SAVE_FORMAT_VERSION {
+            return Err(DeserializeError::UnsupportedFormatVersion(version));
+        }
+        input.voxel_schema_version = input.u8()?;
+        let configuration = SpaceConfiguration {
+            units_per_meter: input.f64()?,
+            tick_size: input.f64()?,
+            gravity_constant: input.f64()?,
+            gravity_theta: input.f64()?,
+        };
+        let tree = GrowableSpaceTree::decode(&mut input)?;
+        if input.remaining() > 0 {
+            return Err(DeserializeError::TrailingBytes(input.remaining()));
+        }
+        Ok(Self {
+            tree,
+            configuration,
+        })
+    }
+
+    pub fn render_snapshot(&self) -> RenderSnapshot {
+        let mut entities = vec![];
+        self.tree.for_each_entity(|entity, pos| {
+            let (kind, voxels) = match (&entity.entity, entity.grid_origin()) {
+                (EntityData::Voxels(grid), Some(grid_origin)) => {
+                    let grid_origin = pos.add(&grid_origin);
+                    let voxels = grid
+                        .iter_solid()
+                        .map(|((x, y, z), _)| {
+                            let voxel = Vec3 {
+                                x: x as i64 * VOXEL_SIZE,
+                                y: y as i64 * VOXEL_SIZE,
+                                z: z as i64 * VOXEL_SIZE,
+                            };
+                            grid_origin.add(&grid.orientation.mul_vec(&voxel))
+                        })
+                        .collect();
+                    (EntityKind::Voxels, voxels)
+                }
+                _ => (EntityKind::Player, vec![]),
+            };
+            entities.push(RenderedEntity {
+                id: entity.id,
+                kind,
+                color: entity.render_color,
+                center: pos,
+                radius: entity.bounding_sphere.radius,
+                voxels,
+            });
+        });
+        RenderSnapshot { entities }
+    }
+
     pub fn run(&mut self) {
+        self.run_tick(self.configuration.tick_size);
+    }
+
+    // Returns the pairs of entities that collided, as GrowableSpaceTree::apply_collisions
+    fn run_tick(&mut self, dt: f64) -> Vec<(u64, u64)> {
         self.tree.run_actions();
-        self.tree.run_movements();
+        let configuration = &self.configuration;
+        if configuration.gravity_constant != 0.0 {
+            self.tree
+                .apply_gravity(configuration.gravity_theta, configuration.gravity_constant);
+        }
+        self.tree.run_movements(dt);
+        let contacts = self.tree.apply_collisions();
         self.tree.refresh();
+        contacts
+    }
+
+    // Largest distance an entity may travel during an adaptive tick
+    pub fn max_adaptive_tick_move(&self) -> f64 {
+        self.tree.matter_config().min_size() as f64 / 2.0
+    }
+
+    // Duration of the next adaptive tick, in ticks: at most the configured tick size, and short
+    // enough for the fastest entity not to move by more than max_adaptive_tick_move during it
+    pub fn adaptive_tick_size(&self) -> f64 {
+        let mut max_speed = 0.0;
+        self.tree.for_each_entity(|entity, _| {
+            max_speed = f64::max(max_speed, entity.speed.length_f64());
+        });
+        let tick_size = self.configuration.tick_size;
+        let max_move = self.max_adaptive_tick_move();
+        if max_speed * tick_size <= max_move {
+            tick_size
+        } else {
+            max_move / max_speed
+        }
+    }
+
+    // Runs adaptive ticks until the budget is spent, and returns how many of them ran
+    pub fn run_adaptive(&mut self, budget: Duration) -> usize {
+        let start = Instant::now();
+        let mut nb_ticks = 0;
+        while start.elapsed() < budget {
+            let dt = self.adaptive_tick_size();
+            self.run_tick(dt);
+            nb_ticks += 1;
+        }
+        nb_ticks
+    }
+
+    // Runs a tick, reporting what happened during it
+    pub fn step(&mut self) -> TickResult {
+        let cells_before = self.tree.entity_cells();
+        let collisions = self
+            .run_tick(self.configuration.tick_size)
+            .into_iter()
+            .map(|(a, b)| CollisionEvent { a, b })
+            .collect();
+
+        let cells_after = self.tree.entity_cells();
+        let ids_before: HashSet<_> = cells_before.keys().collect();
+        let ids_after: HashSet<_> = cells_after.keys().collect();
+        let mut spawned: Vec<_> = ids_after.difference(&ids_before).map(|id| **id).collect();
+        let mut despawned: Vec<_> = ids_before.difference(&ids_after).map(|id| **id).collect();
+        spawned.sort_unstable();
+        despawned.sort_unstable();
+
+        let mut transitions: Vec<_> = cells_before
+            .iter()
+            .filter_map(|(id, from)| match cells_after.get(id) {
+                Some(to) if to != from => Some(CellTransition {
+                    id: *id,
+                    from: *from,
+                    to: *to,
+                }),
+                _ => None,
+            })
+            .collect();
+        transitions.sort_unstable_by_key(|transition| transition.id);
+
+        TickResult {
+            collisions,
+            spawned,
+            despawned,
+            transitions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::Entity;
+    use crate::entity::ForceField;
+    use crate::geometry::Axis;
+    use crate::player::Player;
+    use crate::voxel_grid::{VoxelGridSpace, VoxelType};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn meters_conversions() {
+        let space = Space::new();
+        let v = Vec3 {
+            x: 250,
+            y: -100,
+            z: 0,
+        };
+        assert_eq!(space.to_meters(v), (2.5, -1.0, 0.0));
+        assert_eq!(space.meters_to_units((2.5, -1.0, 0.0)), v);
+
+        let mut configuration = SpaceConfiguration::new();
+        configuration.units_per_meter = 1000.0;
+        let space = Space::new_with_configuration(configuration);
+        assert_eq!(space.to_meters(v), (0.25, -0.1, 0.0));
+        // Rounded to the nearest unit
+        assert_eq!(
+            space.meters_to_units((0.0004, -0.0006, 1.0)),
+            Vec3 {
+                x: 0,
+                y: -1,
+                z: 1000,
+            }
+        );
+    }
+
+    #[test]
+    fn step_reports_collisions_spawns_and_transitions() {
+        let mut space = Space::new();
+        let ball = |x: i64, y: i64, z: i64, speed_x: i64| {
+            Box::new(
+                Entity::builder()
+                    .at(Vec3 { x, y, z })
+                    .radius(10)
+                    .mass(1.0)
+                    .speed(Vec3 {
+                        x: speed_x,
+                        y: 0,
+                        z: 0,
+                    })
+                    .build(),
+            )
+        };
+        // Spread the world over several matter nodes
+        space
+            .tree
+            .insert_entity(ball(-30000, -30000, -30000, 0))
+            .unwrap();
+        space
+            .tree
+            .insert_entity(ball(30000, 30000, 30000, 0))
+            .unwrap();
+
+        let a = ball(-50, 0, 0, 10);
+        let b = ball(-35, 0, 0, -10);
+        let (a_id, b_id) = (a.id, b.id);
+        space.tree.insert_entity(a).unwrap();
+        space.tree.insert_entity(b).unwrap();
+
+        let player = Rc::new(RefCell::new(Player::new()));
+        player.borrow_mut().drop_block = true;
+        let player_pos = Vec3 {
+            x: -20000,
+            y: 20000,
+            z: -20000,
+        };
+        space
+            .tree
+            .insert_entity(Box::new(Entity::new_player(player_pos, player)))
+            .unwrap();
+
+        let mover = ball(0, 10000, 0, 0);
+        let mover_id = mover.id;
+        space.tree.insert_entity(mover).unwrap();
+        let cell = space.tree.entity_cells()[&mover_id];
+        let border = cell.origin.x + cell.size;
+        space.tree.teleport(
+            mover_id,
+            Vec3 {
+                x: border - 5,
+                y: 10000,
+                z: 0,
+            },
+        );
+        space.tree.for_each_entity_mut(|entity, _| {
+            if entity.id == mover_id {
+                entity.speed.x = 20;
+            }
+        });
+
+        let result = space.step();
+        assert!(result
+            .collisions
+            .contains(&CollisionEvent { a: a_id, b: b_id }));
+        assert_eq!(result.spawned.len(), 1);
+        assert!(result.despawned.is_empty());
+        let transition = result
+            .transitions
+            .iter()
+            .find(|transition| transition.id == mover_id)
+            .unwrap();
+        assert_eq!(transition.from, cell);
+        assert_eq!(transition.to.origin.x, border);
+    }
+
+    #[test]
+    fn render_snapshot_is_an_owned_sendable_copy() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        let mut space = Space::new();
+        for x in 0..3 {
+            let entity = Entity::builder()
+                .at(Vec3 {
+                    x: x * 1000,
+                    y: 0,
+                    z: 0,
+                })
+                .build();
+            space.tree.insert_entity(Box::new(entity)).unwrap();
+        }
+        let player = Rc::new(RefCell::new(Player::new()));
+        let player_entity = Entity::new_player(
+            Vec3 {
+                x: 0,
+                y: 5000,
+                z: 0,
+            },
+            player,
+        );
+        space.tree.insert_entity(Box::new(player_entity)).unwrap();
+
+        let snapshot = space.render_snapshot();
+        assert_send(&snapshot);
+        assert_eq!(snapshot.entities.len(), 4);
+        let nb_players = snapshot
+            .entities
+            .iter()
+            .filter(|entity| entity.kind == EntityKind::Player)
+            .count();
+        assert_eq!(nb_players, 1);
+
+        let nb_entities = std::thread::spawn(move || snapshot.entities.len())
+            .join()
+            .unwrap();
+        assert_eq!(nb_entities, 4);
+    }
+
+    // Runs adaptive ticks until `duration` ticks are simulated, and returns how many ran and the
+    // largest move of the entity during one of them
+    fn run_adaptive_for(speed: i64, duration: f64) -> (usize, i64) {
+        let mut space = Space::new();
+        let entity = Entity::builder()
+            .mass(1.0)
+            .speed(Vec3 {
+                x: speed,
+                y: 0,
+                z: 0,
+            })
+            .build();
+        let id = entity.id;
+        space.tree.insert_entity(Box::new(entity)).unwrap();
+
+        let mut elapsed = 0.0;
+        let mut nb_ticks = 0;
+        let mut max_move = 0;
+        let mut pos = Vec3::ZERO;
+        while elapsed < duration {
+            let dt = space.adaptive_tick_size();
+            space.run_tick(dt);
+            elapsed += dt;
+            nb_ticks += 1;
+            let new_pos = space.tree.entity_world_position(id).unwrap();
+            max_move = i64::max(max_move, new_pos.sub(&pos).x.abs());
+            pos = new_pos;
+        }
+        (nb_ticks, max_move)
+    }
+
+    #[test]
+    fn adaptive_ticks_shrink_with_the_speed() {
+        let (slow_ticks, slow_move) = run_adaptive_for(2, 10.0);
+        let (fast_ticks, fast_move) = run_adaptive_for(1000, 10.0);
+        assert!(fast_ticks > slow_ticks);
+        let max_move = Space::new().max_adaptive_tick_move() as i64;
+        assert!(slow_move <= max_move);
+        // Rounding the positions may add a unit
+        assert!(fast_move <= max_move + 1);
+
+        let mut space = Space::new();
+        assert!(space.run_adaptive(Duration::from_millis(1)) > 0);
+    }
+
+    #[test]
+    fn serialized_space_loads_back_equal() {
+        let mut space = Space::new();
+        let mut grid = VoxelGridSpace::new();
+        for i in 0..10 {
+            grid.set((i, i / 2, 3), VoxelType::Rock);
+        }
+        let mut rock = Entity::new_voxels(
+            Vec3 {
+                x: 40000,
+                y: 0,
+                z: 0,
+            },
+            grid,
+            &space.tree.materials,
+        );
+        rock.force_field = Some(ForceField {
+            radius: 500,
+            strength: -2.0,
+        });
+        rock.render_color = Some(0xff00ff);
+        let rock_id = rock.id;
+        space.tree.insert_entity(Box::new(rock)).unwrap();
+        let player = Rc::new(RefCell::new(Player::new()));
+        space
+            .tree
+            .insert_entity(Box::new(Entity::new_player(
+                Vec3 {
+                    x: -5000,
+                    y: 300,
+                    z: 0,
+                },
+                player,
+            )))
+            .unwrap();
+        for i in 0..6 {
+            let entity = Entity::builder()
+                .at(Vec3 {
+                    x: i * 700,
+                    y: -i * 300,
+                    z: 11,
+                })
+                .mass(2.0)
+                .speed(Vec3 { x: 3, y: 0, z: -i })
+                .build();
+            space.tree.insert_entity(Box::new(entity)).unwrap();
+        }
+        space.tree.set_axis_limit(Axis::Y, 90000);
+        space.tree.record_trajectory(rock_id, 4);
+        for _ in 0..3 {
+            space.run();
+        }
+
+        let bytes = space.serialize();
+        let loaded = Space::deserialize(&bytes).unwrap();
+        assert_eq!(loaded, space);
+        assert_eq!(loaded.serialize(), bytes);
+        // New entities do not reuse the loaded ids
+        assert!(Entity::builder().build().id > rock_id);
+    }
+
+    #[test]
+    fn truncated_or_foreign_bytes_are_refused() {
+        let bytes = Space::new().serialize();
+        assert_eq!(
+            Space::deserialize(&bytes[..bytes.len() - 1]),
+            Err(DeserializeError::UnexpectedEnd)
+        );
+        assert_eq!(Space::deserialize(b"nope"), Err(DeserializeError::BadMagic));
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(
+            Space::deserialize(&extra),
+            Err(DeserializeError::TrailingBytes(1))
+        );
+    }
+
+    fn distance_run_in_a_step(tick_size: f64) -> i64 {
+        let mut configuration = SpaceConfiguration::new();
+        configuration.tick_size = tick_size;
+        let mut space = Space::new_with_configuration(configuration);
+        let entity = Entity::builder()
+            .radius(10)
+            .mass(1.0)
+            .speed(Vec3 { x: 100, y: 0, z: 0 })
+            .build();
+        let id = entity.id;
+        space.tree.insert_entity(Box::new(entity)).unwrap();
+        space.run();
+        space.tree.find_entity(id).unwrap().1.x
+    }
+
+    #[test]
+    fn doubling_the_tick_size_doubles_the_displacement() {
+        assert_eq!(distance_run_in_a_step(1.0), 100);
+        assert_eq!(distance_run_in_a_step(2.0), 200);
+    }
+
+    #[test]
+    fn light_entity_falls_toward_a_heavy_one() {
+        let mut space = Space::new();
+        space.configuration.gravity_constant = 40.0;
+        let heavy = Entity::builder().radius(100).mass(1_000_000.0).build();
+        let heavy_id = heavy.id;
+        space.tree.insert_entity(Box::new(heavy)).unwrap();
+        let light = Entity::builder()
+            .at(Vec3 {
+                x: 2000,
+                y: 0,
+                z: 0,
+            })
+            .radius(10)
+            .mass(1.0)
+            .build();
+        let light_id = light.id;
+        space.tree.insert_entity(Box::new(light)).unwrap();
+
+        let mut last_speed = 0;
+        for _ in 0..5 {
+            space.run();
+            let speed = space.tree.find_entity(light_id).unwrap().0.speed;
+            assert!(speed.x < last_speed);
+            assert_eq!((speed.y, speed.z), (0, 0));
+            last_speed = speed.x;
+        }
+        assert!(space.tree.entity_world_position(light_id).unwrap().x < 2000);
+        assert_eq!(
+            space.tree.find_entity(heavy_id).unwrap().0.speed,
+            Vec3::ZERO
+        );
+    }
+
+    #[test]
+    fn run_separates_overlapping_entities() {
+        let mut space = Space::new();
+        let a = Entity::builder()
+            .at(Vec3 { x: -50, y: 0, z: 0 })
+            .radius(100)
+            .mass(1.0)
+            .speed(Vec3 { x: 10, y: 0, z: 0 })
+            .build();
+        let b = Entity::builder()
+            .at(Vec3 { x: 50, y: 0, z: 0 })
+            .radius(100)
+            .mass(1.0)
+            .speed(Vec3 { x: -10, y: 0, z: 0 })
+            .build();
+        let (a_id, b_id) = (a.id, b.id);
+        space.tree.insert_entity(Box::new(a)).unwrap();
+        space.tree.insert_entity(Box::new(b)).unwrap();
+
+        space.run();
+        let speed = |space: &Space, id| space.tree.find_entity(id).unwrap().0.speed;
+        assert_eq!(speed(&space, a_id), Vec3 { x: -10, y: 0, z: 0 });
+        assert_eq!(speed(&space, b_id), Vec3 { x: 10, y: 0, z: 0 });
+
+        for _ in 0..10 {
+            space.run();
+        }
+        assert!(space.tree.distance_between(a_id, b_id).unwrap() > 200.0);
     }
 }
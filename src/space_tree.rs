@@ -1,6 +1,15 @@
-use crate::entity::Entity;
-use crate::geometry::{Direction, FineDirection, Quadrant, Vec3, NB_DIRECTIONS, NB_QUADRANTS};
-use crate::matter_tree::MatterTree;
+use crate::entity::{self, Entity, EntityData};
+use crate::geometry::{
+    Axis, Cube, Direction, FineDirection, Quadrant, Sphere, Vec3, NB_DIRECTIONS, NB_QUADRANTS,
+};
+use crate::matter_tree::{Entities, MatterTree, MatterTreeConfig, MatterTreeConfigError};
+use crate::migration_log::MigrationLogger;
+use crate::player::{self, Player};
+use crate::serialization::{Decoder, DeserializeError, Encoder};
+use crate::voxel_grid::{MaterialTable, VoxelGridSpace, VoxelType};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum SpaceTree {
@@ -11,17 +20,33 @@ pub enum SpaceTree {
 #[derive(Debug, Clone, PartialEq)]
 pub struct SpaceTreeParent {
     pub scale: u32,
+    // Configuration of the matter trees at the bottom of this node
+    pub config: MatterTreeConfig,
     pub sub_trees: [Option<Box<SpaceTree>>; NB_QUADRANTS],
+
+    // Number of refreshes this node has spent empty
+    pub empty_ticks: u32,
 }
 
 impl SpaceTreeParent {
+    fn size(&self) -> i64 {
+        self.config.size() << (self.scale + 1)
+    }
+
+    // Position of a sub tree center, relative to this node center
+    fn sub_tree_shift(&self, quadrant: Quadrant) -> Vec3 {
+        quadrant.direction_vec().mul_scalar(self.size() / 4)
+    }
+
     fn build_sub_tree(&self) -> Box<SpaceTree> {
         Box::new(if self.scale == 0 {
-            SpaceTree::Matter(MatterTree::new())
+            SpaceTree::new_matter(self.config)
         } else {
             SpaceTree::Parent(SpaceTreeParent {
                 scale: self.scale - 1,
+                config: self.config,
                 sub_trees: [SpaceTree::NONE_SPACE_CELL; NB_QUADRANTS],
+                empty_ticks: 0,
             })
         })
     }
@@ -52,8 +77,16 @@ struct EntityToDisplaceDown {
 impl SpaceTree {
     const NONE_SPACE_CELL: Option<Box<Self>> = None;
 
-    pub fn new() -> Self {
-        Self::Matter(MatterTree::new())
+    // The config must be valid: it comes from an existing matter tree
+    fn new_matter(config: MatterTreeConfig) -> Self {
+        Self::Matter(MatterTree::new_with_config(config).unwrap())
+    }
+
+    pub fn config(&self) -> MatterTreeConfig {
+        match self {
+            Self::Parent(parent) => parent.config,
+            Self::Matter(matter) => matter.config,
+        }
     }
 
     fn new_parent(&self) -> Self {
@@ -62,7 +95,12 @@ impl SpaceTree {
             Self::Matter(_) => 0,
         };
         let sub_trees = [Self::NONE_SPACE_CELL; NB_QUADRANTS];
-        Self::Parent(SpaceTreeParent { scale, sub_trees })
+        Self::Parent(SpaceTreeParent {
+            scale,
+            config: self.config(),
+            sub_trees,
+            empty_ticks: 0,
+        })
     }
 
     pub fn is_empty(&self) -> bool {
@@ -72,12 +110,278 @@ impl SpaceTree {
         }
     }
 
-    fn get_displaced_outsider(mut entity: Box<Entity>) -> EntityToDisplaceUp {
-        let direction = FineDirection::outsider_direction_vec(
-            &entity.bounding_sphere.center,
-            MatterTree::MAX_SIZE,
-        );
-        entity.switch_space_tree(direction, MatterTree::MAX_SIZE);
+    fn empty_ticks_mut(&mut self) -> &mut u32 {
+        match self {
+            Self::Parent(parent) => &mut parent.empty_ticks,
+            Self::Matter(matter) => &mut matter.empty_ticks,
+        }
+    }
+
+    fn size(&self) -> i64 {
+        match self {
+            Self::Parent(parent) => parent.size(),
+            Self::Matter(matter) => matter.config.size(),
+        }
+    }
+
+    fn scale(&self) -> Option<u32> {
+        match self {
+            Self::Parent(parent) => Some(parent.scale),
+            Self::Matter(_) => None,
+        }
+    }
+
+    // The entity position must be relative to this node center
+    fn insert_entity(&mut self, mut entity: Box<Entity>) {
+        match self {
+            Self::Matter(matter) => matter.add_entities(vec![entity]),
+            Self::Parent(parent) => {
+                let quadrant = Quadrant::from_pos(&entity.bounding_sphere.center);
+                entity.bounding_sphere.center = entity
+                    .bounding_sphere
+                    .center
+                    .sub(&parent.sub_tree_shift(quadrant));
+                let i = quadrant as usize;
+                if parent.sub_trees[i].is_none() {
+                    parent.sub_trees[i] = Some(parent.build_sub_tree());
+                }
+                parent.sub_trees[i].as_mut().unwrap().insert_entity(entity);
+            }
+        }
+    }
+
+    fn for_each_entity_mut<F: FnMut(&mut Entity, Vec3)>(&mut self, center: Vec3, f: &mut F) {
+        match self {
+            Self::Matter(matter) => matter.for_each_entity_mut(&mut |entity| {
+                let pos = center.add(&entity.bounding_sphere.center);
+                f(entity, pos)
+            }),
+            Self::Parent(parent) => {
+                for i in 0..NB_QUADRANTS {
+                    let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                    let sub_center = center.add(&parent.sub_tree_shift(quadrant));
+                    if let Some(tree) = parent.sub_trees[i].as_mut() {
+                        tree.for_each_entity_mut(sub_center, f);
+                    }
+                }
+            }
+        }
+    }
+
+    fn find_entity(&self, center: Vec3, id: u64) -> Option<(&Entity, Vec3)> {
+        match self {
+            Self::Matter(matter) => matter
+                .find_entity(id)
+                .map(|entity| (entity, center.add(&entity.bounding_sphere.center))),
+            Self::Parent(parent) => {
+                for (i, sub_tree) in parent.sub_trees.iter().enumerate() {
+                    if let Some(tree) = sub_tree {
+                        let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                        let sub_center = center.add(&parent.sub_tree_shift(quadrant));
+                        if let Some(found) = tree.find_entity(sub_center, id) {
+                            return Some(found);
+                        }
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    // Fills path with the quadrants leading to the entity, along with each sub tree center relative
+    // to its parent center. Returns the entity position relative to its matter tree.
+    fn entity_path(&self, id: u64, path: &mut Vec<(Quadrant, Vec3)>) -> Option<Vec3> {
+        match self {
+            Self::Matter(matter) => matter
+                .find_entity(id)
+                .map(|entity| entity.bounding_sphere.center),
+            Self::Parent(parent) => {
+                for (i, sub_tree) in parent.sub_trees.iter().enumerate() {
+                    if let Some(tree) = sub_tree {
+                        let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                        path.push((quadrant, parent.sub_tree_shift(quadrant)));
+                        if let Some(pos) = tree.entity_path(id, path) {
+                            return Some(pos);
+                        }
+                        path.pop();
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    fn find_entity_mut(&mut self, center: Vec3, id: u64) -> Option<(&mut Entity, Vec3)> {
+        match self {
+            Self::Matter(matter) => matter.find_entity_mut(id).map(|entity| {
+                let pos = center.add(&entity.bounding_sphere.center);
+                (entity, pos)
+            }),
+            Self::Parent(parent) => {
+                let sub_centers: Vec<_> = (0..NB_QUADRANTS)
+                    .map(|i| {
+                        let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                        center.add(&parent.sub_tree_shift(quadrant))
+                    })
+                    .collect();
+                for (sub_tree, sub_center) in parent.sub_trees.iter_mut().zip(sub_centers) {
+                    if let Some(tree) = sub_tree {
+                        if let Some(found) = tree.find_entity_mut(sub_center, id) {
+                            return Some(found);
+                        }
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    // Drained entities are returned with their world position
+    fn drain_filter<F: FnMut(&mut Entity, Vec3) -> bool>(
+        &mut self,
+        center: Vec3,
+        f: &mut F,
+    ) -> Entities {
+        match self {
+            Self::Matter(matter) => {
+                let mut drained = matter.drain_filter(&mut |entity| {
+                    let pos = center.add(&entity.bounding_sphere.center);
+                    f(entity, pos)
+                });
+                for entity in drained.iter_mut() {
+                    entity.bounding_sphere.center = center.add(&entity.bounding_sphere.center);
+                }
+                drained
+            }
+            Self::Parent(parent) => {
+                let mut drained = vec![];
+                for i in 0..NB_QUADRANTS {
+                    let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                    let sub_center = center.add(&parent.sub_tree_shift(quadrant));
+                    if let Some(tree) = parent.sub_trees[i].as_mut() {
+                        drained.extend(tree.drain_filter(sub_center, f));
+                        if tree.is_empty() {
+                            parent.sub_trees[i] = None;
+                        }
+                    }
+                }
+                drained
+            }
+        }
+    }
+
+    fn area(&self, center: &Vec3) -> Cube {
+        let half_size = self.size() / 2;
+        Cube {
+            origin: center.sub(&Vec3 {
+                x: half_size,
+                y: half_size,
+                z: half_size,
+            }),
+            size: self.size(),
+        }
+    }
+
+    fn nearest_matching<'a, F: Fn(&Entity) -> bool>(
+        &'a self,
+        center: Vec3,
+        point: &Vec3,
+        pred: &F,
+        best: &mut Option<(&'a Entity, f64)>,
+    ) {
+        match self {
+            Self::Matter(matter) => matter.nearest_matching(&point.sub(&center), pred, best),
+            Self::Parent(parent) => {
+                let mut sub_trees = vec![];
+                for (i, sub_tree) in parent.sub_trees.iter().enumerate() {
+                    if let Some(tree) = sub_tree {
+                        let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                        let sub_center = center.add(&parent.sub_tree_shift(quadrant));
+                        let dist = tree.area(&sub_center).distance_f64(point);
+                        sub_trees.push((dist, sub_center, tree));
+                    }
+                }
+                sub_trees.sort_by(|(a, _, _), (b, _, _)| a.partial_cmp(b).unwrap());
+                for (dist, sub_center, tree) in sub_trees.into_iter() {
+                    if let Some((_, best_dist)) = best {
+                        if dist >= *best_dist {
+                            break;
+                        }
+                    }
+                    tree.nearest_matching(sub_center, point, pred, best);
+                }
+            }
+        }
+    }
+
+    // The positions given to pred are world ones. Every matter node is visited, as the entities at
+    // their root can stick out of them; each one only goes through its cells crossed by the ray.
+    fn raycast_matching<'a, F: Fn(&Entity, Vec3) -> bool>(
+        &'a self,
+        center: Vec3,
+        origin: &Vec3,
+        dir: &Vec3,
+        max_dist: f64,
+        pred: &F,
+        best: &mut Option<(&'a Entity, f64)>,
+    ) {
+        match self {
+            Self::Matter(matter) => matter.raycast_matching(
+                &origin.sub(&center),
+                dir,
+                max_dist,
+                &|entity: &Entity| pred(entity, center.add(&entity.bounding_sphere.center)),
+                best,
+            ),
+            Self::Parent(parent) => {
+                for (i, sub_tree) in parent.sub_trees.iter().enumerate() {
+                    if let Some(tree) = sub_tree {
+                        let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                        let sub_center = center.add(&parent.sub_tree_shift(quadrant));
+                        tree.raycast_matching(sub_center, origin, dir, max_dist, pred, best);
+                    }
+                }
+            }
+        }
+    }
+
+    // Calls f with each matter node and its world cube
+    fn for_each_matter<'a, F: FnMut(&'a MatterTree, Cube)>(&'a self, center: Vec3, f: &mut F) {
+        match self {
+            Self::Matter(matter) => f(matter, self.area(&center)),
+            Self::Parent(parent) => {
+                for (i, sub_tree) in parent.sub_trees.iter().enumerate() {
+                    if let Some(tree) = sub_tree {
+                        let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                        let sub_center = center.add(&parent.sub_tree_shift(quadrant));
+                        tree.for_each_matter(sub_center, f);
+                    }
+                }
+            }
+        }
+    }
+
+    fn for_each_entity<F: FnMut(&Entity, Vec3)>(&self, center: Vec3, f: &mut F) {
+        match self {
+            Self::Matter(matter) => matter.for_each_entity(&mut |entity| {
+                f(entity, center.add(&entity.bounding_sphere.center))
+            }),
+            Self::Parent(parent) => {
+                for (i, sub_tree) in parent.sub_trees.iter().enumerate() {
+                    if let Some(tree) = sub_tree {
+                        let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                        let sub_center = center.add(&parent.sub_tree_shift(quadrant));
+                        tree.for_each_entity(sub_center, f);
+                    }
+                }
+            }
+        }
+    }
+
+    fn get_displaced_outsider(mut entity: Box<Entity>, matter_size: i64) -> EntityToDisplaceUp {
+        let direction =
+            FineDirection::outsider_direction_vec(&entity.bounding_sphere.center, matter_size);
+        entity.switch_space_tree(direction, matter_size);
         EntityToDisplaceUp {
             path: vec![],
             direction,
@@ -116,127 +420,205 @@ impl SpaceTree {
         match self {
             Self::Matter(matter) => matter.run_actions(),
             Self::Parent(tree) => {
-                for sub_tree in tree.sub_trees.iter_mut() {
-                    if let Some(tree) = sub_tree {
-                        tree.run_actions();
-                    }
+                for tree in tree.sub_trees.iter_mut().flatten() {
+                    tree.run_actions();
                 }
             }
         }
     }
 
-    fn run_movements(&mut self) {
+    fn run_movements(&mut self, dt: f64) {
         match self {
-            Self::Matter(matter) => matter.run_movements(),
+            Self::Matter(matter) => matter.run_movements(dt),
             Self::Parent(tree) => {
-                for sub_tree in tree.sub_trees.iter_mut() {
-                    if let Some(tree) = sub_tree {
-                        tree.run_movements();
-                    }
+                for tree in tree.sub_trees.iter_mut().flatten() {
+                    tree.run_movements(dt);
                 }
             }
         }
     }
 
-    fn apply_neighbourhood_collisions(&mut self) {
+    fn apply_neighbourhood_collisions(&mut self, contacts: &mut Vec<(u64, u64)>) {
         match self {
-            Self::Matter(matter) => matter.apply_neighbourhood_collisions(),
+            Self::Matter(matter) => matter.apply_neighbourhood_collisions(contacts),
             Self::Parent(tree) => {
-                for sub_tree in tree.sub_trees.iter_mut() {
-                    if let Some(tree) = sub_tree {
-                        tree.apply_neighbourhood_collisions();
-                    }
+                for tree in tree.sub_trees.iter_mut().flatten() {
+                    tree.apply_neighbourhood_collisions(contacts);
                 }
             }
         }
     }
 
-    fn apply_external_collisions(&mut self, outsiders: &mut [&mut Box<Entity>]) {
+    // The outsiders positions must be relative to this node center
+    fn apply_external_collisions(
+        &mut self,
+        outsiders: &mut [&mut Box<Entity>],
+        contacts: &mut Vec<(u64, u64)>,
+    ) {
         match self {
-            Self::Matter(matter) => matter.apply_external_collisions(outsiders),
-            Self::Parent(tree) => {
-                for sub_tree in tree.sub_trees.iter_mut() {
-                    if let Some(tree) = sub_tree {
-                        tree.apply_external_collisions(outsiders);
+            Self::Matter(matter) => matter.apply_external_collisions(outsiders, contacts),
+            Self::Parent(parent) => {
+                for i in 0..NB_QUADRANTS {
+                    let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                    let shift = parent.sub_tree_shift(quadrant);
+                    if let Some(tree) = parent.sub_trees[i].as_mut() {
+                        for outsider in outsiders.iter_mut() {
+                            outsider.bounding_sphere.center =
+                                outsider.bounding_sphere.center.sub(&shift);
+                        }
+                        tree.apply_external_collisions(outsiders, contacts);
+                        for outsider in outsiders.iter_mut() {
+                            outsider.bounding_sphere.center =
+                                outsider.bounding_sphere.center.add(&shift);
+                        }
                     }
                 }
             }
         }
     }
 
-    fn apply_inter_neighbourhood_collisions(
+    // Part of the direction, taken from a sub tree in the quadrant, that leaves this node
+    fn direction_leaving_parent(
+        quadrant: Quadrant,
+        direction: FineDirection,
+    ) -> Option<FineDirection> {
+        let vec = direction.equivalent_vec();
+        let leaves = |component: i64, axis: Vec3| {
+            if quadrant.move_to(axis.mul_scalar(component)).is_none() {
+                component
+            } else {
+                0
+            }
+        };
+        let outside = Vec3 {
+            x: leaves(vec.x, Vec3 { x: 1, y: 0, z: 0 }),
+            y: leaves(vec.y, Vec3 { x: 0, y: 1, z: 0 }),
+            z: leaves(vec.z, Vec3 { x: 0, y: 0, z: 1 }),
+        };
+        if outside == Vec3::ZERO {
+            None
+        } else {
+            Some(FineDirection::from_vec(&outside))
+        }
+    }
+
+    // Entities overflowing this node, with the directions of the neighbour nodes they touch and
+    // the center of their matter node relative to this node center
+    fn get_entities_touching_outside(
         &mut self,
-    ) -> Vec<(&mut Box<Entity>, Vec<FineDirection>)> {
+    ) -> Vec<(&mut Box<Entity>, Vec<FineDirection>, Vec3)> {
         match self {
-            Self::Matter(matter) => matter.get_entities_touching_outside(),
+            Self::Matter(matter) => matter
+                .get_entities_touching_outside()
+                .into_iter()
+                .map(|(entity, directions)| (entity, directions, Vec3::ZERO))
+                .collect(),
             Self::Parent(parent) => {
-                // TODO Not working. Requires full refactor.
-                // let mut outsiders = vec![];
-                // let mut insiders = vec![];
-                // for (quad_i, sub_tree) in parent.sub_trees.iter_mut().enumerate() {
-                //     let quad: Quadrant = num::FromPrimitive::from_usize(quad_i).unwrap();
-                //     if let Some(tree) = sub_tree {
-                //         for (overflower, dirs) in tree.apply_inter_neighbourhood_collisions() {
-                //             let mut inside_quadrants = vec![];
-                //             let mut remaining_dirs = vec![];
-                //             for dir in dirs.into_iter() {
-                //                 if let Some(dest_quad) = quad.move_to(dir.equivalent_vec()) {
-                //                     inside_quadrants.push(dest_quad);
-                //                 } else {
-                //                     remaining_dirs.push(dir);
-                //                 }
-                //             }
-                //             if inside_quadrants.is_empty() {
-                //                 outsiders.push((overflower, remaining_dirs));
-                //             } else {
-                //                 insiders.push((overflower, inside_quadrants, remaining_dirs));
-                //             }
-                //         }
-                //     }
-                // }
-
-                // // TODO See if there is a safe way to keep this optimization
-                // unsafe {
-                //     for i in 0..NB_QUADRANTS {
-                //         let quad = num::FromPrimitive::from_usize(i).unwrap();
-                //         let mut insiders: Vec<_> = insiders
-                //             .iter_mut()
-                //             .filter_map(|(entity, target_quads, _)| {
-                //                 if target_quads.contains(&quad) {
-                //                     Some(*entity)
-                //                 } else {
-                //                     None
-                //                 }
-                //             })
-                //             .collect();
-                //         if !insiders.is_empty() {
-                //             let parent = parent as *mut SpaceTreeParent;
-                //             if let Some(tree) = (*parent).sub_trees[i].as_mut() {
-                //                 tree.apply_external_collisions(&mut insiders[..]);
-                //             }
-                //         }
-                //     }
-                // }
-
-                // for (insider, _, dirs) in insiders.into_iter() {
-                //     if !dirs.is_empty() {
-                //         outsiders.push((insider, dirs));
-                //     }
-                // }
-
-                // outsiders
-                vec![]
-            }
-        }
-    }
-
-    fn refresh(&mut self) -> Vec<EntityToDisplaceUp> {
+                let shifts: Vec<_> = (0..NB_QUADRANTS)
+                    .map(|i| parent.sub_tree_shift(num::FromPrimitive::from_usize(i).unwrap()))
+                    .collect();
+                let mut outsiders = vec![];
+                for (i, sub_tree) in parent.sub_trees.iter_mut().enumerate() {
+                    let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                    if let Some(tree) = sub_tree {
+                        for (entity, directions, center) in tree.get_entities_touching_outside() {
+                            let mut outside_directions = vec![];
+                            for direction in directions.into_iter() {
+                                if let Some(outside) =
+                                    Self::direction_leaving_parent(quadrant, direction)
+                                {
+                                    if !outside_directions.contains(&outside) {
+                                        outside_directions.push(outside);
+                                    }
+                                }
+                            }
+                            if !outside_directions.is_empty() {
+                                outsiders.push((
+                                    entity,
+                                    outside_directions,
+                                    center.add(&shifts[i]),
+                                ));
+                            }
+                        }
+                    }
+                }
+                outsiders
+            }
+        }
+    }
+
+    // Collisions between entities of different matter nodes
+    fn apply_inter_neighbourhood_collisions(&mut self, contacts: &mut Vec<(u64, u64)>) {
+        if let Self::Parent(parent) = self {
+            for tree in parent.sub_trees.iter_mut().flatten() {
+                tree.apply_inter_neighbourhood_collisions(contacts);
+            }
+
+            let shifts: Vec<_> = (0..NB_QUADRANTS)
+                .map(|i| parent.sub_tree_shift(num::FromPrimitive::from_usize(i).unwrap()))
+                .collect();
+            // Take each sub tree out in turn to borrow its outsiders along with its siblings
+            let mut sub_trees: Vec<_> = parent
+                .sub_trees
+                .iter_mut()
+                .map(|tree| tree.as_mut())
+                .collect();
+            for i in 0..NB_QUADRANTS {
+                let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                let source = match sub_trees[i].take() {
+                    Some(source) => source,
+                    None => continue,
+                };
+                {
+                    let mut outsiders = source.get_entities_touching_outside();
+                    for (j, target) in sub_trees.iter_mut().enumerate() {
+                        let target = match target {
+                            Some(target) => target,
+                            None => continue,
+                        };
+                        // Move the outsiders touching the sibling to its frame
+                        let target_quadrant = num::FromPrimitive::from_usize(j);
+                        let shift = shifts[i].sub(&shifts[j]);
+                        let mut relevant = vec![];
+                        let mut offsets = vec![];
+                        for (entity, directions, center) in outsiders.iter_mut() {
+                            if directions.iter().any(|direction| {
+                                quadrant.move_to(direction.equivalent_vec()) == target_quadrant
+                            }) {
+                                let offset = center.add(&shift);
+                                entity.bounding_sphere.center =
+                                    entity.bounding_sphere.center.add(&offset);
+                                relevant.push(&mut **entity);
+                                offsets.push(offset);
+                            }
+                        }
+                        if relevant.is_empty() {
+                            continue;
+                        }
+                        target.apply_external_collisions(&mut relevant[..], contacts);
+                        for (entity, offset) in relevant.into_iter().zip(offsets) {
+                            entity.bounding_sphere.center =
+                                entity.bounding_sphere.center.sub(&offset);
+                        }
+                    }
+                }
+                sub_trees[i] = Some(source);
+            }
+        }
+    }
+
+    fn refresh(
+        &mut self,
+        empty_node_ttl: u32,
+        logger: &mut MigrationLogger,
+    ) -> Vec<EntityToDisplaceUp> {
         match self {
             Self::Matter(cell) => {
-                let outsiders = cell.refresh();
+                let outsiders = cell.refresh(empty_node_ttl, logger);
+                let matter_size = cell.config.size();
                 outsiders
                     .into_iter()
-                    .map(Self::get_displaced_outsider)
+                    .map(|entity| Self::get_displaced_outsider(entity, matter_size))
                     .collect()
             }
             Self::Parent(parent) => {
@@ -245,7 +627,7 @@ impl SpaceTree {
                 for (i, child) in parent.sub_trees.iter_mut().enumerate() {
                     if let Some(child) = child {
                         let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
-                        let sub_outsiders = child.refresh();
+                        let sub_outsiders = child.refresh(empty_node_ttl, logger);
                         for mut displaced_outsider in sub_outsiders.into_iter() {
                             if let Some(relocation) = quadrant.move_to(displaced_outsider.direction)
                             {
@@ -280,15 +662,21 @@ impl SpaceTree {
         }
     }
 
-    fn clean_empty_children(&mut self) {
+    fn clean_empty_children(&mut self, empty_node_ttl: u32) {
         if let Self::Parent(parent) = self {
-            // Clean empty quadrants
+            // Clean quadrants that stayed empty for long enough
             for i in 0..NB_QUADRANTS {
                 let mut need_emptying = false;
                 if let Some(quad) = parent.sub_trees[i].as_mut() {
-                    quad.clean_empty_children();
-                    if quad.is_empty() {
+                    quad.clean_empty_children(empty_node_ttl);
+                    let is_empty = quad.is_empty();
+                    let empty_ticks = quad.empty_ticks_mut();
+                    if !is_empty {
+                        *empty_ticks = 0;
+                    } else if *empty_ticks >= empty_node_ttl {
                         need_emptying = true;
+                    } else {
+                        *empty_ticks += 1;
                     }
                 }
                 if need_emptying {
@@ -341,78 +729,884 @@ impl SpaceTree {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum InsertError {
+    // The entity is given back, as it could not be placed farther than the biggest universe
+    OutOfUniverse(Box<Entity>),
+}
+
+// Difference of an entity between two worlds, as seen from the first one
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntityDelta {
+    Changed {
+        id: u64,
+        position: Vec3,
+        speed: Vec3,
+        mass: f64,
+    },
+    OnlyInSelf(u64),
+    OnlyInOther(u64),
+}
+
+// Area whose callback is called with the id of each entity leaving it
+#[derive(Clone)]
+pub struct Boundary {
+    pub area: Cube,
+    callback: Rc<RefCell<dyn FnMut(u64)>>,
+    // Entities that were inside during the last check
+    inside: HashSet<u64>,
+}
+
+impl Boundary {
+    fn check(&mut self, positions: &[(u64, Vec3)]) {
+        let mut inside = HashSet::new();
+        for (id, pos) in positions.iter() {
+            if self.area.contains(pos) {
+                inside.insert(*id);
+            } else if self.inside.contains(id) {
+                (self.callback.borrow_mut())(*id);
+            }
+        }
+        self.inside = inside;
+    }
+}
+
+impl std::fmt::Debug for Boundary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Boundary")
+            .field("area", &self.area)
+            .field("inside", &self.inside)
+            .finish()
+    }
+}
+
+impl PartialEq for Boundary {
+    fn eq(&self, other: &Self) -> bool {
+        self.area == other.area
+            && Rc::ptr_eq(&self.callback, &other.callback)
+            && self.inside == other.inside
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct GrowableSpaceTree {
     pub tree: Box<SpaceTree>,
+    // World position of the root node center
+    pub center: Vec3,
+    // Number of refreshes an empty node survives before being removed, to avoid recreating it
+    // over and over when entities oscillate around a boundary
+    pub empty_node_ttl: u32,
+    // Used to compute the mass of voxel entities
+    pub materials: MaterialTable,
+    pub migration_logger: MigrationLogger,
+    pub boundaries: Vec<Boundary>,
+    // Debug builds warn about entities farther from the world origin than this fraction of the
+    // largest world coordinate, where precision starts to matter
+    pub precision_warning_ratio: f64,
+    // Largest distance to the world origin allowed along each axis, if any. Entities going
+    // farther are bounced back instead of growing the tree.
+    axis_limits: [Option<i64>; 3],
+    // Set once an entity trajectory is recorded, to skip the recording pass otherwise
+    recording_trajectories: bool,
+}
+
+impl Default for GrowableSpaceTree {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GrowableSpaceTree {
+    pub const DEFAULT_MIGRATION_LOGS_PER_SECOND: u32 = 20;
+    // Half the size of the biggest root node, whatever the matter tree size. Beyond it, the root
+    // node size or the world coordinates would overflow.
+    pub const MAX_WORLD_COORDINATE: i64 = 1 << (62 - 1);
+
     pub fn new() -> Self {
-        Self {
-            tree: Box::new(SpaceTree::new()),
-        }
+        Self::new_root(MatterTreeConfig::DEFAULT)
     }
 
-    pub fn pick_expansion_quadrant(
-        expansion_dirs: &mut [usize; NB_DIRECTIONS as usize],
-    ) -> (Quadrant, usize) {
-        let mut i_direction = 0;
-        let mut dirs_consumed = 0;
-        if expansion_dirs[Direction::Xp as usize] != 0 {
-            expansion_dirs[Direction::Xp as usize] = 0;
-            i_direction += 1 << 2;
-            dirs_consumed += 1;
-        } else if expansion_dirs[Direction::Xn as usize] != 0 {
-            expansion_dirs[Direction::Xn as usize] = 0;
-            dirs_consumed += 1;
-        }
+    pub fn new_with_config(config: MatterTreeConfig) -> Result<Self, MatterTreeConfigError> {
+        MatterTree::new_with_config(config)?;
+        Ok(Self::new_root(config))
+    }
 
-        if expansion_dirs[Direction::Yp as usize] != 0 {
-            expansion_dirs[Direction::Yp as usize] = 0;
-            i_direction += 1 << 1;
-            dirs_consumed += 1;
-        } else if expansion_dirs[Direction::Yn as usize] != 0 {
-            expansion_dirs[Direction::Yn as usize] = 0;
-            dirs_consumed += 1;
+    fn new_root(config: MatterTreeConfig) -> Self {
+        Self {
+            tree: Box::new(SpaceTree::new_matter(config)),
+            center: Vec3::ZERO,
+            empty_node_ttl: 0,
+            materials: MaterialTable::new(),
+            migration_logger: MigrationLogger::new(Self::DEFAULT_MIGRATION_LOGS_PER_SECOND),
+            precision_warning_ratio: 0.5,
+            boundaries: vec![],
+            axis_limits: [None; 3],
+            recording_trajectories: false,
         }
+    }
 
-        if expansion_dirs[Direction::Yp as usize] != 0 {
-            expansion_dirs[Direction::Yp as usize] = 0;
-            i_direction += 1 << 1;
-            dirs_consumed += 1;
-        } else if expansion_dirs[Direction::Yn as usize] != 0 {
-            expansion_dirs[Direction::Yn as usize] = 0;
-            dirs_consumed += 1;
-        }
-        let opposite_quadrant: Quadrant = num::FromPrimitive::from_usize(i_direction).unwrap();
-        (opposite_quadrant.invert(), dirs_consumed)
+    pub fn matter_config(&self) -> MatterTreeConfig {
+        self.tree.config()
     }
 
-    pub fn run_actions(&mut self) {
-        self.tree.run_actions();
+    // Scale of the biggest root node
+    pub fn max_scale(&self) -> u32 {
+        Self::max_scale_for(self.matter_config())
     }
 
-    pub fn run_movements(&mut self) {
-        self.tree.run_movements();
+    fn max_scale_for(config: MatterTreeConfig) -> u32 {
+        Self::MAX_WORLD_COORDINATE.trailing_zeros() - config.size().trailing_zeros()
     }
 
-    pub fn refresh(&mut self) {
-        let mut outsiders = self.tree.refresh();
+    pub fn set_axis_limit(&mut self, axis: Axis, max_half_extent: i64) {
+        self.axis_limits[axis as usize] = Some(max_half_extent);
+    }
 
-        // Check in which directions the ousiders are
-        let mut expansion_dirs = [0; NB_DIRECTIONS as usize];
-        let mut nb_expansion_dirs = 0;
-        for outsider in outsiders.iter() {
-            let dirs = outsider.direction.direction_components();
-            for dir in dirs.into_iter() {
-                if expansion_dirs[dir as usize] == 0 {
-                    nb_expansion_dirs += 1;
+    // Clamps the entities beyond an axis limit back to it and reverses their speed along that
+    // axis
+    fn apply_axis_limits(&mut self) {
+        if self.axis_limits.iter().all(|limit| limit.is_none()) {
+            return;
+        }
+        let limits = self.axis_limits;
+        self.for_each_entity_mut(|entity, pos| {
+            let mut position = [pos.x, pos.y, pos.z];
+            let mut speed = [entity.speed.x, entity.speed.y, entity.speed.z];
+            for axis in 0..3 {
+                if let Some(limit) = limits[axis] {
+                    if position[axis].abs() > limit {
+                        position[axis] = position[axis].signum() * limit;
+                        if speed[axis].signum() == position[axis].signum() {
+                            speed[axis] = -speed[axis];
+                        }
+                    }
                 }
-                expansion_dirs[dir as usize] += 1;
             }
-        }
-
-        // While some outsiders are outside
+            let shift = Vec3 {
+                x: position[0],
+                y: position[1],
+                z: position[2],
+            }
+            .sub(&pos);
+            entity.bounding_sphere.move_by(&shift);
+            entity.speed = Vec3 {
+                x: speed[0],
+                y: speed[1],
+                z: speed[2],
+            };
+        });
+    }
+
+    fn grow(&mut self, child_quadrant: Quadrant) {
+        let parent = self.tree.new_parent();
+        let child = std::mem::replace(&mut self.tree, Box::new(parent));
+        if let SpaceTree::Parent(parent) = self.tree.as_mut() {
+            self.center = self.center.sub(&parent.sub_tree_shift(child_quadrant));
+            parent.sub_trees[child_quadrant as usize] = Some(child);
+        }
+    }
+
+    // The entity position is expected in world coordinates
+    pub fn insert_entity(&mut self, mut entity: Box<Entity>) -> Result<(), InsertError> {
+        let pos = entity.bounding_sphere.center;
+        let relative_pos = loop {
+            let relative_pos = match (
+                pos.x.checked_sub(self.center.x),
+                pos.y.checked_sub(self.center.y),
+                pos.z.checked_sub(self.center.z),
+            ) {
+                (Some(x), Some(y), Some(z)) => Vec3 { x, y, z },
+                _ => return Err(InsertError::OutOfUniverse(entity)),
+            };
+            if relative_pos.is_inside_centered_cube(self.tree.size()) {
+                break relative_pos;
+            }
+            if self.tree.scale() >= Some(self.max_scale()) {
+                return Err(InsertError::OutOfUniverse(entity));
+            }
+            self.grow(Quadrant::from_pos(&relative_pos).invert());
+        };
+        entity.bounding_sphere.center = relative_pos;
+        self.tree.insert_entity(entity);
+        Ok(())
+    }
+
+    // Calls f with each entity and its world position
+    pub fn for_each_entity(&self, mut f: impl FnMut(&Entity, Vec3)) {
+        self.tree.for_each_entity(self.center, &mut f);
+    }
+
+    // Returns the entity along with its world position
+    pub fn find_entity(&self, id: u64) -> Option<(&Entity, Vec3)> {
+        self.tree.find_entity(self.center, id)
+    }
+
+    pub fn find_entity_mut(&mut self, id: u64) -> Option<(&mut Entity, Vec3)> {
+        self.tree.find_entity_mut(self.center, id)
+    }
+
+    // Edits the voxel grid of an entity, keeping its bounding sphere and mass in sync. Returns
+    // false if the entity does not exist or is not a voxel grid. The next refresh relocates the
+    // entity if its new bounding sphere does not fit its cell anymore.
+    pub fn with_voxel_grid_mut(&mut self, id: u64, f: impl FnOnce(&mut VoxelGridSpace)) -> bool {
+        let entity = match self.tree.find_entity_mut(self.center, id) {
+            Some((entity, _)) => entity,
+            None => return false,
+        };
+        match &mut entity.entity {
+            EntityData::Voxels(grid) => f(grid),
+            _ => return false,
+        }
+        entity.refresh_voxel_grid(&self.materials);
+        true
+    }
+
+    // World cube of each leaf of the space tree (the matter trees) along with its number of
+    // entities
+    pub fn iter_leaves(&self) -> impl Iterator<Item = (Cube, usize)> {
+        let mut leaves = vec![];
+        self.tree.for_each_matter(self.center, &mut |matter, area| {
+            leaves.push((area, matter.nb_entities()));
+        });
+        leaves.into_iter()
+    }
+
+    // Leaf holding the most entities, if any
+    pub fn densest_leaf(&self) -> Option<(Cube, usize)> {
+        self.iter_leaves()
+            .filter(|(_, nb_entities)| *nb_entities > 0)
+            .max_by_key(|(_, nb_entities)| *nb_entities)
+    }
+
+    // World cube of the matter node containing each entity
+    pub fn entity_cells(&self) -> HashMap<u64, Cube> {
+        let mut ret = HashMap::new();
+        self.tree.for_each_matter(self.center, &mut |matter, area| {
+            matter.for_each_entity(&mut |entity| {
+                ret.insert(entity.id, area);
+            });
+        });
+        ret
+    }
+
+    // Entity controlled by this player, if it is in the tree
+    pub fn find_player_entity(&self, player: &Rc<RefCell<Player>>) -> Option<u64> {
+        let mut ret = None;
+        self.for_each_entity(|entity, _| {
+            if let EntityData::Player(entity_player) = &entity.entity {
+                if Rc::ptr_eq(entity_player, player) {
+                    ret = Some(entity.id);
+                }
+            }
+        });
+        ret
+    }
+
+    pub fn entity_world_position(&self, id: u64) -> Option<Vec3> {
+        self.find_entity(id).map(|(_, pos)| pos)
+    }
+
+    // Distance between two entity centers. Only the offsets below the deepest node holding both
+    // entities are summed, in i128, so that entities far apart in a huge tree do not overflow.
+    pub fn distance_between(&self, a: u64, b: u64) -> Option<f64> {
+        let mut a_path = vec![];
+        let a_pos = self.tree.entity_path(a, &mut a_path)?;
+        let mut b_path = vec![];
+        let b_pos = self.tree.entity_path(b, &mut b_path)?;
+        let common = a_path
+            .iter()
+            .zip(b_path.iter())
+            .take_while(|((a_quadrant, _), (b_quadrant, _))| a_quadrant == b_quadrant)
+            .count();
+        // Position relative to the common node center
+        let relative_position = |path: &[(Quadrant, Vec3)], pos: &Vec3| {
+            path.iter()
+                .map(|(_, shift)| shift)
+                .chain(std::iter::once(pos))
+                .fold([0i128; 3], |sum, v| {
+                    [
+                        sum[0] + v.x as i128,
+                        sum[1] + v.y as i128,
+                        sum[2] + v.z as i128,
+                    ]
+                })
+        };
+        let a_pos = relative_position(&a_path[common..], &a_pos);
+        let b_pos = relative_position(&b_path[common..], &b_pos);
+        let gap_sq: f64 = (0..3)
+            .map(|axis| {
+                let gap = (a_pos[axis] - b_pos[axis]) as f64;
+                gap * gap
+            })
+            .sum();
+        Some(gap_sq.sqrt())
+    }
+
+    // World position and speed of an entity, for a camera to follow it
+    pub fn center_on(&self, id: u64) -> Option<(Vec3, Vec3)> {
+        self.find_entity(id)
+            .map(|(entity, pos)| (pos, entity.speed))
+    }
+
+    // Calls f with each entity and its world position
+    pub fn for_each_entity_mut(&mut self, mut f: impl FnMut(&mut Entity, Vec3)) {
+        self.tree.for_each_entity_mut(self.center, &mut f);
+    }
+
+    // Mutates every entity in place, and pulls out the ones for which f returns true. The pulled out
+    // entities positions are converted to world coordinates.
+    pub fn drain_filter_entities(
+        &mut self,
+        mut f: impl FnMut(&mut Entity, Vec3) -> bool,
+    ) -> Entities {
+        self.tree.drain_filter(self.center, &mut f)
+    }
+
+    // Moves an entity to a new world position right away, instead of letting refresh migrate it
+    // cell by cell
+    pub fn teleport(&mut self, id: u64, new_world_pos: Vec3) -> bool {
+        let mut entity = match self
+            .drain_filter_entities(|entity, _| entity.id == id)
+            .pop()
+        {
+            Some(entity) => entity,
+            None => return false,
+        };
+        let previous_pos = entity.bounding_sphere.center;
+        entity.bounding_sphere.center = new_world_pos;
+        match self.insert_entity(entity) {
+            Ok(()) => true,
+            Err(InsertError::OutOfUniverse(mut entity)) => {
+                // It was inside the universe at its previous position
+                entity.bounding_sphere.center = previous_pos;
+                self.insert_entity(entity).unwrap();
+                false
+            }
+        }
+    }
+
+    // Returns the number of entities whose center is in the region
+    pub fn set_frozen_in_region(&mut self, region: &Cube, frozen: bool) -> usize {
+        let mut nb_affected = 0;
+        self.for_each_entity_mut(|entity, pos| {
+            if region.contains(&pos) {
+                entity.frozen = frozen;
+                nb_affected += 1;
+            }
+        });
+        nb_affected
+    }
+
+    pub fn set_render_color(&mut self, id: u64, color: Option<u32>) -> bool {
+        match self.find_entity_mut(id) {
+            Some((entity, _)) => {
+                entity.render_color = color;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Renumbers the entities from 1, keeping their order, and returns the new id of each old one.
+    // Ids are then only unique within this tree: the entities of other trees, or of other
+    // compacted trees, may hold the same low ids. Entities must not be moved between such trees
+    // without new ids, as extract_prefab does. The global id counter is left as is, so the
+    // entities created afterwards still get ids above the renumbered ones.
+    pub fn compact_ids(&mut self) -> HashMap<u64, u64> {
+        let mut ids = vec![];
+        self.for_each_entity(|entity, _| ids.push(entity.id));
+        ids.sort_unstable();
+        let mapping: HashMap<_, _> = ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (*id, i as u64 + 1))
+            .collect();
+        self.for_each_entity_mut(|entity, _| entity.id = mapping[&entity.id]);
+        mapping
+    }
+
+    // Pushes the entities within radius away from center, and breaks the voxels around it. The
+    // energy, given at center, decreases linearly down to 0 at radius. It is used as an impulse
+    // for the entities. The blast hits a voxel entity at its voxel closest to center, and spreads
+    // through the grid from there with the energy and radius left.
+    pub fn apply_explosion(&mut self, center: Vec3, energy: f64, radius: i64) {
+        let Self {
+            tree,
+            center: tree_center,
+            materials,
+            ..
+        } = self;
+        tree.for_each_entity_mut(*tree_center, &mut |entity, pos| {
+            let to_entity = pos.sub(&center);
+            let distance = to_entity.length_f64();
+            let surface_distance = f64::max(0.0, distance - entity.bounding_sphere.radius as f64);
+            if surface_distance >= radius as f64 {
+                return;
+            }
+
+            if let Some(grid_origin) = entity.grid_origin() {
+                let local_center = center.sub(&pos.add(&grid_origin));
+                if let EntityData::Voxels(grid) = &mut entity.entity {
+                    let impact = grid.closest_solid_voxel(&local_center).and_then(|impact| {
+                        let gap = impact.sub(&local_center).length_f64();
+                        let radius_left = radius - gap.round() as i64;
+                        (radius_left > 0).then_some((impact, radius_left))
+                    });
+                    if let Some((impact, radius_left)) = impact {
+                        let energy_left = energy * radius_left as f64 / radius as f64;
+                        let nb_broken =
+                            grid.apply_impact(&impact, energy_left, radius_left, materials);
+                        // TODO Split the grids that the broken voxels disconnected
+                        if nb_broken > 0 {
+                            entity.refresh_voxel_grid(materials);
+                        }
+                    }
+                }
+            }
+
+            let inverse_mass = entity.inverse_mass();
+            if inverse_mass != 0.0 && distance != 0.0 {
+                let impulse = energy * (1.0 - surface_distance / radius as f64);
+                entity.speed = entity
+                    .speed
+                    .add(&to_entity.mul_float(impulse * inverse_mass / distance));
+                entity.sleeping = false;
+            }
+        });
+    }
+
+    // Gives the same acceleration to every entity, whatever its mass
+    pub fn apply_uniform_field(&mut self, acceleration: Vec3) {
+        if acceleration == Vec3::ZERO {
+            return;
+        }
+        self.for_each_entity_mut(|entity, _| {
+            let force = acceleration.mul_float(entity.mass);
+            entity.accumulate_force(&force);
+        });
+    }
+
+    // Entities that moved, changed speed or mass, or exist in only one of the worlds, by id.
+    // Deltas are the other world values minus this world ones.
+    pub fn diff_state(&self, other: &Self) -> Vec<EntityDelta> {
+        let mut states = HashMap::new();
+        self.for_each_entity(|entity, pos| {
+            states.insert(entity.id, (pos, entity.speed, entity.mass));
+        });
+        let mut ret = vec![];
+        other.for_each_entity(|entity, pos| match states.remove(&entity.id) {
+            Some((self_pos, self_speed, self_mass)) => {
+                let position = pos.sub(&self_pos);
+                let speed = entity.speed.sub(&self_speed);
+                let mass = entity.mass - self_mass;
+                if position != Vec3::ZERO || speed != Vec3::ZERO || mass != 0.0 {
+                    ret.push(EntityDelta::Changed {
+                        id: entity.id,
+                        position,
+                        speed,
+                        mass,
+                    });
+                }
+            }
+            None => ret.push(EntityDelta::OnlyInOther(entity.id)),
+        });
+        ret.extend(states.keys().map(|id| EntityDelta::OnlyInSelf(*id)));
+        ret.sort_by_key(|delta| match delta {
+            EntityDelta::Changed { id, .. } => *id,
+            EntityDelta::OnlyInSelf(id) => *id,
+            EntityDelta::OnlyInOther(id) => *id,
+        });
+        ret
+    }
+
+    // Starts recording the last capacity world positions of the entity, after each refresh
+    pub fn record_trajectory(&mut self, id: u64, capacity: usize) -> bool {
+        match self.tree.find_entity_mut(self.center, id) {
+            Some((entity, _)) => {
+                entity.trajectory = Some(entity::Trajectory::new(capacity));
+                self.recording_trajectories = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn entity_trajectory(&self, id: u64) -> Option<&[Vec3]> {
+        self.find_entity(id)
+            .and_then(|(entity, _)| entity.trajectory.as_ref())
+            .map(|trajectory| trajectory.positions())
+    }
+
+    // Entities that changed cell more than threshold times without really moving
+    pub fn detect_oscillating(&self, threshold: u32) -> Vec<u64> {
+        let mut ret = vec![];
+        self.for_each_entity(|entity, _| {
+            if entity.nb_migrations > threshold {
+                ret.push(entity.id);
+            }
+        });
+        ret
+    }
+
+    // Pairs of entities (lowest id first, sorted) whose bounding spheres overlap at some point of
+    // the next dt ticks, given their current speeds. Nothing is applied.
+    pub fn predict_collisions(&self, dt: f64) -> Vec<(u64, u64)> {
+        let mut matters = vec![];
+        self.tree.for_each_matter(self.center, &mut |matter, area| {
+            matters.push((matter, area))
+        });
+        // Farthest move and largest radius of any entity, by which the candidate entities may
+        // reach beyond the swept sphere of an entity and beyond their matter node
+        let mut max_move = 0;
+        let mut max_radius = 0;
+        self.for_each_entity(|entity, _| {
+            max_move = max_move.max(entity.speed.mul_float(dt).length_f64().ceil() as i64);
+            max_radius = max_radius.max(entity.bounding_sphere.radius);
+        });
+
+        let mut ret = vec![];
+        for (matter, area) in matters.iter() {
+            let center = area.center();
+            matter.for_each_entity(&mut |a| {
+                let a_move = a.speed.mul_float(dt);
+                let swept = Sphere {
+                    center: center.add(&a.bounding_sphere.center),
+                    radius: a.bounding_sphere.radius + a_move.length_f64().ceil() as i64 + max_move,
+                };
+                let reach = Sphere {
+                    center: swept.center,
+                    radius: swept.radius + max_radius,
+                };
+                // The candidates may be in any matter node the swept sphere reaches
+                for (other_matter, other_area) in matters.iter() {
+                    if !other_area.intersects_sphere(&reach) {
+                        continue;
+                    }
+                    let other_center = other_area.center();
+                    let query = Sphere {
+                        center: swept.center.sub(&other_center),
+                        radius: swept.radius,
+                    };
+                    for b in other_matter.entities_in_sphere(&query) {
+                        if b.id <= a.id {
+                            continue;
+                        }
+                        // Closest approach of the relative movement during dt
+                        let gap = other_center
+                            .add(&b.bounding_sphere.center)
+                            .sub(&swept.center);
+                        let relative_move = b.speed.mul_float(dt).sub(&a_move);
+                        let relative_move_sq = relative_move.dot_f64(&relative_move);
+                        let t = if relative_move_sq == 0.0 {
+                            0.0
+                        } else {
+                            (-gap.dot_f64(&relative_move) / relative_move_sq).clamp(0.0, 1.0)
+                        };
+                        let closest_gap = gap.add(&relative_move.mul_float(t));
+                        let radii = a.bounding_sphere.radius + b.bounding_sphere.radius;
+                        if closest_gap.length_f64() < radii as f64 {
+                            ret.push((a.id, b.id));
+                        }
+                    }
+                }
+            });
+        }
+        ret.sort_unstable();
+        ret
+    }
+
+    pub fn nearest_of_type(&self, point: Vec3, pred: impl Fn(&EntityData) -> bool) -> Option<u64> {
+        let mut best = None;
+        self.tree.nearest_matching(
+            self.center,
+            &point,
+            &|entity: &Entity| pred(&entity.entity),
+            &mut best,
+        );
+        best.map(|(entity, _)| entity.id)
+    }
+
+    // Every entity crossed by the ray within max_dist, with its entry distance, nearest first
+    pub fn ray_entities(&self, origin: Vec3, dir: Vec3, max_dist: i64) -> Vec<(u64, f64)> {
+        let mut ret = vec![];
+        self.for_each_entity(|entity, pos| {
+            let sphere = Sphere {
+                center: pos,
+                radius: entity.bounding_sphere.radius,
+            };
+            if let Some(dist) = sphere.ray_entry_distance(&origin, &dir) {
+                if dist <= max_dist as f64 {
+                    ret.push((entity.id, dist));
+                }
+            }
+        });
+        ret.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap());
+        ret
+    }
+
+    // Nearest entity accepted by pred whose bounding sphere is crossed by the ray within max_dist,
+    // with the distance at which the ray enters it. pred is given the entity world position.
+    pub fn raycast(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: i64,
+        pred: impl Fn(&Entity, Vec3) -> bool,
+    ) -> Option<(u64, f64)> {
+        let mut best = None;
+        self.tree.raycast_matching(
+            self.center,
+            &origin,
+            &dir,
+            max_dist as f64,
+            &pred,
+            &mut best,
+        );
+        best.map(|(entity, dist)| (entity.id, dist))
+    }
+
+    // False if another entity's bounding sphere stands between the two entities centers
+    pub fn has_line_of_sight(&self, from: u64, to: u64) -> bool {
+        let (from_pos, to_pos) = match (
+            self.entity_world_position(from),
+            self.entity_world_position(to),
+        ) {
+            (Some(from_pos), Some(to_pos)) => (from_pos, to_pos),
+            _ => return false,
+        };
+        let dir = to_pos.sub(&from_pos);
+        let dist = dir.length_f64();
+        self.raycast(from_pos, dir, dist as i64, |entity, _| {
+            entity.id != from && entity.id != to
+        })
+        .is_none()
+    }
+
+    // Copies the entities centered in region into a new tree, rebased on the region center. The
+    // copies get new ids. Players are left out, as a copy would share the original controls.
+    // Inserting the copies fails with OutOfUniverse when the region is too large for its rebased
+    // entities to fit in a world, the entity being handed back.
+    pub fn extract_prefab(&self, region: &Cube) -> Result<Self, InsertError> {
+        let region_center = region.center();
+        let mut entities = vec![];
+        self.for_each_entity(|entity, pos| {
+            if region.contains(&pos) && !matches!(entity.entity, EntityData::Player(_)) {
+                let mut entity = Box::new(entity.clone());
+                entity.id = entity::next_entity_id();
+                entity.bounding_sphere.center = pos.sub(&region_center);
+                entities.push(entity);
+            }
+        });
+        let mut prefab = Self::new();
+        for entity in entities {
+            prefab.insert_entity(entity)?;
+        }
+        Ok(prefab)
+    }
+
+    pub fn pick_expansion_quadrant(
+        expansion_dirs: &mut [usize; NB_DIRECTIONS as usize],
+    ) -> (Quadrant, usize) {
+        let mut i_direction = 0;
+        let mut dirs_consumed = 0;
+        if expansion_dirs[Direction::Xp as usize] != 0 {
+            expansion_dirs[Direction::Xp as usize] = 0;
+            i_direction += 1 << 2;
+            dirs_consumed += 1;
+        } else if expansion_dirs[Direction::Xn as usize] != 0 {
+            expansion_dirs[Direction::Xn as usize] = 0;
+            dirs_consumed += 1;
+        }
+
+        if expansion_dirs[Direction::Yp as usize] != 0 {
+            expansion_dirs[Direction::Yp as usize] = 0;
+            i_direction += 1 << 1;
+            dirs_consumed += 1;
+        } else if expansion_dirs[Direction::Yn as usize] != 0 {
+            expansion_dirs[Direction::Yn as usize] = 0;
+            dirs_consumed += 1;
+        }
+
+        if expansion_dirs[Direction::Yp as usize] != 0 {
+            expansion_dirs[Direction::Yp as usize] = 0;
+            i_direction += 1 << 1;
+            dirs_consumed += 1;
+        } else if expansion_dirs[Direction::Yn as usize] != 0 {
+            expansion_dirs[Direction::Yn as usize] = 0;
+            dirs_consumed += 1;
+        }
+        let opposite_quadrant: Quadrant = num::FromPrimitive::from_usize(i_direction).unwrap();
+        (opposite_quadrant.invert(), dirs_consumed)
+    }
+
+    pub fn run_actions(&mut self) {
+        self.tree.run_actions();
+        self.run_voxel_tools();
+    }
+
+    // Voxel of the entity aimed at by a tool at pos, along with the voxel before it on the ray
+    fn tool_hit(
+        entity: &Entity,
+        entity_pos: Vec3,
+        pos: Vec3,
+        aim: Vec3,
+    ) -> Option<(Vec3, Option<Vec3>)> {
+        match (&entity.entity, entity.grid_origin()) {
+            (EntityData::Voxels(grid), Some(grid_origin)) => {
+                let grid_origin = entity_pos.add(&grid_origin);
+                grid.raycast_with_previous(pos.sub(&grid_origin), aim, player::TOOL_REACH)
+                    .map(|(hit, _, previous)| (hit, previous))
+            }
+            _ => None,
+        }
+    }
+
+    // Mines or places the voxel aimed at by the players using their tools
+    fn run_voxel_tools(&mut self) {
+        let mut tools = vec![];
+        self.for_each_entity(|entity, pos| {
+            if let EntityData::Player(player) = &entity.entity {
+                let player = player.borrow();
+                if player.mine_voxel || player.place_voxel {
+                    tools.push((entity.id, pos, player.aim(), player.mine_voxel));
+                }
+            }
+        });
+        for (player_id, pos, aim, mine) in tools.into_iter() {
+            let target = self
+                .raycast(pos, aim, player::TOOL_REACH, |entity, entity_pos| {
+                    entity.id != player_id && Self::tool_hit(entity, entity_pos, pos, aim).is_some()
+                })
+                .and_then(|(id, _)| {
+                    let (entity, entity_pos) = self.find_entity(id)?;
+                    let (hit, previous) = Self::tool_hit(entity, entity_pos, pos, aim)?;
+                    Some((id, hit, previous))
+                });
+            let (id, hit, previous) = match target {
+                Some(target) => target,
+                None => continue,
+            };
+            // raycast only returns voxels inside the grid
+            let (coord, voxel) = match (mine, previous) {
+                (true, _) => (hit, VoxelType::Empty),
+                (false, Some(previous)) => (previous, VoxelType::Rock),
+                (false, None) => continue,
+            };
+            self.with_voxel_grid_mut(id, |grid| {
+                grid.set(
+                    (coord.x as usize, coord.y as usize, coord.z as usize),
+                    voxel,
+                )
+            });
+        }
+    }
+
+    pub fn apply_force_fields(&mut self) {
+        let mut emitters = vec![];
+        self.for_each_entity(|entity, pos| {
+            if let Some(field) = entity.force_field {
+                emitters.push((entity.id, pos, field));
+            }
+        });
+        if emitters.is_empty() {
+            return;
+        }
+
+        self.for_each_entity_mut(|entity, pos| {
+            for (id, emitter_pos, field) in emitters.iter() {
+                if *id == entity.id {
+                    continue;
+                }
+                let offset = pos.sub(emitter_pos);
+                let dist = offset.length_f64();
+                if dist == 0.0 || dist > field.radius as f64 {
+                    continue;
+                }
+                // Linear falloff from the emitter to the edge of the field
+                let magnitude = field.strength * (1.0 - dist / field.radius as f64);
+                entity.accumulate_force(&offset.mul_float(magnitude / dist));
+            }
+        });
+    }
+
+    // Adds the gravity pull of every matter node to each entity forces, each node being walked with
+    // the Barnes-Hut approximation of MatterTree::apply_gravity
+    pub fn apply_gravity(&mut self, theta: f64, g: f64) {
+        let mut forces = HashMap::new();
+        {
+            let mut matters = vec![];
+            self.tree.for_each_matter(self.center, &mut |matter, area| {
+                matters.push((matter, area.center()))
+            });
+            self.for_each_entity(|entity, pos| {
+                if entity.mass <= 0.0 || !entity.mass.is_finite() {
+                    return;
+                }
+                let mut acceleration = [0.0; 3];
+                for (matter, center) in matters.iter() {
+                    let pull = matter.gravity_acceleration(&pos.sub(center), entity.id, theta, g);
+                    for (a, pull) in acceleration.iter_mut().zip(pull.iter()) {
+                        *a += pull;
+                    }
+                }
+                let force = Vec3 {
+                    x: (acceleration[0] * entity.mass).round() as i64,
+                    y: (acceleration[1] * entity.mass).round() as i64,
+                    z: (acceleration[2] * entity.mass).round() as i64,
+                };
+                forces.insert(entity.id, force);
+            });
+        }
+        self.for_each_entity_mut(|entity, _| {
+            if let Some(force) = forces.get(&entity.id) {
+                entity.accumulate_force(force);
+            }
+        });
+    }
+
+    // Collisions within each matter node, then between neighbour matter nodes. Returns the pairs
+    // of entities found in contact, each as (lowest id, highest id), sorted.
+    pub fn apply_collisions(&mut self) -> Vec<(u64, u64)> {
+        let mut contacts = vec![];
+        self.tree.apply_neighbourhood_collisions(&mut contacts);
+        self.tree
+            .apply_inter_neighbourhood_collisions(&mut contacts);
+        let mut contacts: Vec<_> = contacts
+            .into_iter()
+            .map(|(a, b)| (u64::min(a, b), u64::max(a, b)))
+            .collect();
+        contacts.sort_unstable();
+        contacts.dedup();
+        contacts
+    }
+
+    // dt is the duration of the movement, in ticks
+    pub fn run_movements(&mut self, dt: f64) {
+        self.apply_force_fields();
+        self.tree.run_movements(dt);
+    }
+
+    pub fn refresh(&mut self) {
+        self.apply_axis_limits();
+
+        let mut outsiders = self
+            .tree
+            .refresh(self.empty_node_ttl, &mut self.migration_logger);
+
+        // Check in which directions the ousiders are
+        let mut expansion_dirs = [0; NB_DIRECTIONS as usize];
+        let mut nb_expansion_dirs = 0;
+        for outsider in outsiders.iter() {
+            let dirs = outsider.direction.direction_components();
+            for dir in dirs.into_iter() {
+                if expansion_dirs[dir as usize] == 0 {
+                    nb_expansion_dirs += 1;
+                }
+                expansion_dirs[dir as usize] += 1;
+            }
+        }
+
+        // While some outsiders are outside
         if outsiders.len() > nb_expansion_dirs {
             panic!("{} | {:?}", nb_expansion_dirs, outsiders);
         }
@@ -423,11 +1617,7 @@ impl GrowableSpaceTree {
             nb_expansion_dirs -= dirs_consumed;
 
             // Create new parent cell
-            let parent = self.tree.new_parent();
-            let child = std::mem::replace(&mut self.tree, Box::new(parent));
-            if let SpaceTree::Parent(parent) = self.tree.as_mut() {
-                parent.sub_trees[child_quadrant as usize] = Some(child);
-            }
+            self.grow(child_quadrant);
 
             // Update outsiders path
             for outsider in outsiders.iter_mut() {
@@ -449,37 +1639,119 @@ impl GrowableSpaceTree {
         }
 
         // Cleanup useless children levels
-        self.tree.clean_empty_children();
+        self.tree.clean_empty_children(self.empty_node_ttl);
 
         // Cleanup useless parent levels
+        self.collapse_redundant_parents();
+
+        self.check_boundaries();
+
+        if self.recording_trajectories {
+            self.for_each_entity_mut(|entity, pos| {
+                if let Some(trajectory) = entity.trajectory.as_mut() {
+                    trajectory.record(pos);
+                }
+            });
+        }
+
+        if cfg!(debug_assertions) {
+            self.check_precision();
+        }
+    }
+
+    // Ids of the entities beyond the precision warning threshold, which get logged as warnings
+    pub fn check_precision(&self) -> Vec<u64> {
+        let limit = Self::MAX_WORLD_COORDINATE as f64 * self.precision_warning_ratio;
+        let mut ret = vec![];
+        self.for_each_entity(|entity, pos| {
+            if [pos.x, pos.y, pos.z]
+                .iter()
+                .any(|coordinate| coordinate.abs() as f64 > limit)
+            {
+                log::warn!(
+                    "Entity {} at {:?} is close to the world coordinates limit, its precision degrades",
+                    entity.id,
+                    pos
+                );
+                ret.push(entity.id);
+            }
+        });
+        ret
+    }
+
+    // callback is called with the id of each entity leaving area, during the refresh following its
+    // exit
+    pub fn on_boundary_exit(&mut self, area: Cube, callback: impl FnMut(u64) + 'static) {
+        let mut boundary = Boundary {
+            area,
+            callback: Rc::new(RefCell::new(callback)),
+            inside: HashSet::new(),
+        };
+        boundary.check(&self.entity_positions());
+        self.boundaries.push(boundary);
+    }
+
+    fn entity_positions(&self) -> Vec<(u64, Vec3)> {
+        let mut positions = vec![];
+        self.for_each_entity(|entity, pos| positions.push((entity.id, pos)));
+        positions
+    }
+
+    fn check_boundaries(&mut self) {
+        if self.boundaries.is_empty() {
+            return;
+        }
+        let positions = self.entity_positions();
+        for boundary in self.boundaries.iter_mut() {
+            boundary.check(&positions);
+        }
+    }
+
+    // Removes every entity, leaving a single matter node
+    pub fn clear(&mut self) {
+        *self.tree = SpaceTree::new_matter(self.matter_config());
+    }
+
+    // Removes the top parent levels until the root has several non empty children
+    pub fn collapse_redundant_parents(&mut self) {
         loop {
             let child = match self.tree.as_mut() {
                 SpaceTree::Matter(_) => break,
                 SpaceTree::Parent(parent) => {
+                    // Empty children kept alive by the empty node TTL do not count
                     if parent
                         .sub_trees
                         .iter()
-                        .map(|tree| tree.is_some() as usize)
-                        .sum::<usize>()
+                        .flatten()
+                        .filter(|tree| !tree.is_empty())
+                        .count()
                         > 1
                     {
                         break;
                     } else {
                         let mut child = None;
-                        for tree in parent.sub_trees.iter_mut() {
-                            if let Some(tree) = tree.take() {
-                                child = Some(tree);
+                        for (i, tree) in parent.sub_trees.iter_mut().enumerate() {
+                            if tree.as_ref().is_some_and(|tree| !tree.is_empty()) {
+                                let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                                child =
+                                    Some((tree.take().unwrap(), parent.sub_tree_shift(quadrant)));
                                 break;
                             }
                         }
                         match child {
                             Some(child) => child,
-                            None => break,
+                            None => {
+                                // Nothing left in the world
+                                self.clear();
+                                break;
+                            }
                         }
                     }
                 }
             };
+            let (child, shift) = child;
             self.tree = child;
+            self.center = self.center.add(&shift);
         }
     }
 
@@ -495,3 +1767,1350 @@ impl GrowableSpaceTree {
         self.tree.nb_entities()
     }
 }
+
+// Serialization
+impl SpaceTree {
+    fn encode(&self, out: &mut Encoder) {
+        match self {
+            Self::Parent(parent) => {
+                out.u8(0);
+                out.u32(parent.scale);
+                out.u32(parent.empty_ticks);
+                out.presence_mask(&parent.sub_trees);
+                for sub_tree in parent.sub_trees.iter().flatten() {
+                    sub_tree.encode(out);
+                }
+            }
+            Self::Matter(matter) => {
+                out.u8(1);
+                matter.encode(out);
+            }
+        }
+    }
+
+    // Every matter tree must share the config of the whole space tree, which the node sizes are
+    // built on
+    fn decode(input: &mut Decoder, config: MatterTreeConfig) -> Result<Self, DeserializeError> {
+        match input.u8()? {
+            0 => {
+                let scale = input.u32()?;
+                if scale > GrowableSpaceTree::max_scale_for(config) {
+                    return Err(DeserializeError::InvalidValue("space tree scale"));
+                }
+                let mut parent = SpaceTreeParent {
+                    scale,
+                    config,
+                    sub_trees: [Self::NONE_SPACE_CELL; NB_QUADRANTS],
+                    empty_ticks: input.u32()?,
+                };
+                let presence = input.presence_mask()?;
+                for (sub_tree, present) in parent.sub_trees.iter_mut().zip(presence.iter()) {
+                    if *present {
+                        let sub = Self::decode(input, config)?;
+                        if sub.scale().map_or(0, |scale| scale + 1) != parent.scale {
+                            return Err(DeserializeError::InvalidValue("space tree scale"));
+                        }
+                        *sub_tree = Some(Box::new(sub));
+                    }
+                }
+                Ok(Self::Parent(parent))
+            }
+            1 => {
+                let matter = MatterTree::decode(input)?;
+                if matter.config != config {
+                    return Err(DeserializeError::InvalidValue("matter tree config"));
+                }
+                Ok(Self::Matter(matter))
+            }
+            tag => Err(DeserializeError::InvalidTag {
+                what: "space tree",
+                tag,
+            }),
+        }
+    }
+}
+
+impl GrowableSpaceTree {
+    // Boundaries hold callbacks, which cannot be saved: they are left out
+    pub fn encode(&self, out: &mut Encoder) {
+        let config = self.matter_config();
+        out.u32(config.min_size_pow);
+        out.u32(config.max_scale);
+        self.tree.encode(out);
+        out.vec3(&self.center);
+        out.u32(self.empty_node_ttl);
+        self.materials.encode(out);
+        out.u32(self.migration_logger.max_per_second);
+        out.f64(self.precision_warning_ratio);
+        for limit in self.axis_limits.iter() {
+            match limit {
+                Some(limit) => {
+                    out.bool(true);
+                    out.i64(*limit);
+                }
+                None => out.bool(false),
+            }
+        }
+        out.bool(self.recording_trajectories);
+    }
+
+    pub fn decode(input: &mut Decoder) -> Result<Self, DeserializeError> {
+        let config = MatterTreeConfig {
+            min_size_pow: input.u32()?,
+            max_scale: input.u32()?,
+        };
+        let mut tree = Self::new_with_config(config)
+            .map_err(|_| DeserializeError::InvalidValue("matter tree config"))?;
+        tree.tree = Box::new(SpaceTree::decode(input, config)?);
+        tree.center = input.vec3()?;
+        tree.empty_node_ttl = input.u32()?;
+        tree.materials = MaterialTable::decode(input)?;
+        tree.migration_logger = MigrationLogger::new(input.u32()?);
+        tree.precision_warning_ratio = input.f64()?;
+        for limit in tree.axis_limits.iter_mut() {
+            *limit = if input.bool()? {
+                Some(input.i64()?)
+            } else {
+                None
+            };
+        }
+        tree.recording_trajectories = input.bool()?;
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::ForceField;
+    use crate::geometry::Sphere;
+    use crate::player::Player;
+    use crate::voxel_grid::{chunk_index, VoxelTree, VoxelType, VOXEL_SCHEMA_VERSION, VOXEL_SIZE};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn entity_at(x: i64, y: i64, z: i64) -> Box<Entity> {
+        Box::new(Entity::new(
+            Sphere {
+                center: Vec3 { x, y, z },
+                radius: 10,
+            },
+            EntityData::Voxels(Box::default()),
+        ))
+    }
+
+    fn sorted_positions(tree: &GrowableSpaceTree) -> Vec<Vec3> {
+        let mut positions = vec![];
+        tree.for_each_entity(|_, pos| positions.push(pos));
+        positions.sort_by_key(|pos| (pos.x, pos.y, pos.z));
+        positions
+    }
+
+    #[test]
+    fn extract_prefab_rebases_the_region_entities() {
+        let mut tree = GrowableSpaceTree::new();
+        tree.insert_entity(entity_at(1000, 0, 0)).unwrap();
+        tree.insert_entity(entity_at(150_000, -5000, 3)).unwrap();
+        tree.insert_entity(entity_at(-300_000, 70_000, -90_000))
+            .unwrap();
+        let player = Rc::new(RefCell::new(Player::new()));
+        tree.insert_entity(Box::new(Entity::new_player(
+            Vec3 {
+                x: 20_000,
+                y: 0,
+                z: 0,
+            },
+            player,
+        )))
+        .unwrap();
+        tree.refresh();
+        let before = sorted_positions(&tree);
+
+        let region = Cube {
+            origin: Vec3 {
+                x: 0,
+                y: -100_000,
+                z: -100_000,
+            },
+            size: 200_000,
+        };
+        let prefab = tree.extract_prefab(&region).unwrap();
+        // The player inside the region is not copied
+        assert_eq!(
+            sorted_positions(&prefab),
+            vec![
+                Vec3 {
+                    x: -99_000,
+                    y: 0,
+                    z: 0,
+                },
+                Vec3 {
+                    x: 50_000,
+                    y: -5000,
+                    z: 3,
+                },
+            ]
+        );
+        assert_eq!(sorted_positions(&tree), before);
+        assert_eq!(tree.nb_entities(), 4);
+    }
+
+    #[test]
+    fn nearest_of_type_skips_closer_entities_of_other_types() {
+        let mut tree = GrowableSpaceTree::new();
+        let rock = entity_at(90_000, 0, 0);
+        let rock_id = rock.id;
+        tree.insert_entity(rock).unwrap();
+        tree.insert_entity(entity_at(-200_000, 0, 0)).unwrap();
+        let player = Entity::new_player(
+            Vec3 {
+                x: 1000,
+                y: 0,
+                z: 0,
+            },
+            Rc::new(RefCell::new(Player::new())),
+        );
+        let player_id = player.id;
+        tree.insert_entity(Box::new(player)).unwrap();
+        tree.refresh();
+
+        let is_voxels = |data: &EntityData| matches!(data, EntityData::Voxels(_));
+        assert_eq!(tree.nearest_of_type(Vec3::ZERO, is_voxels), Some(rock_id));
+        assert_eq!(tree.nearest_of_type(Vec3::ZERO, |_| true), Some(player_id));
+        assert_eq!(
+            GrowableSpaceTree::new().nearest_of_type(Vec3::ZERO, is_voxels),
+            None
+        );
+    }
+
+    fn nb_matter_nodes(tree: &SpaceTree) -> usize {
+        match tree {
+            SpaceTree::Matter(_) => 1,
+            SpaceTree::Parent(parent) => parent
+                .sub_trees
+                .iter()
+                .flatten()
+                .map(|t| nb_matter_nodes(t))
+                .sum(),
+        }
+    }
+
+    // Number of matter nodes left once an entity has crossed a node border
+    fn matter_nodes_after_crossing(empty_node_ttl: u32) -> usize {
+        let mut tree = GrowableSpaceTree::new();
+        tree.empty_node_ttl = empty_node_ttl;
+        tree.insert_entity(entity_at(-30_000, -30_000, -30_000))
+            .unwrap();
+        // Matter nodes border each other at z = 16384
+        let mut hopper = entity_at(30_000, 30_000, 16_084);
+        hopper.speed = Vec3 { x: 0, y: 0, z: 100 };
+        tree.insert_entity(hopper).unwrap();
+        tree.refresh();
+        let before = nb_matter_nodes(&tree.tree);
+
+        for _ in 0..6 {
+            tree.run_movements(1.0);
+            tree.refresh();
+        }
+        nb_matter_nodes(&tree.tree) - before
+    }
+
+    #[test]
+    fn empty_node_ttl_keeps_the_left_node() {
+        // The node the entity left is dropped right away, unless its TTL keeps it alive
+        assert_eq!(matter_nodes_after_crossing(0), 0);
+        assert_eq!(matter_nodes_after_crossing(100), 1);
+    }
+
+    #[test]
+    fn uniform_field_accelerates_every_mass_the_same() {
+        let mut tree = GrowableSpaceTree::new();
+        for (i, mass) in [1.0, 8.0, 1000.0].iter().enumerate() {
+            let mut entity = entity_at(i as i64 * 1000, 0, 0);
+            entity.mass = *mass;
+            tree.insert_entity(entity).unwrap();
+        }
+
+        let field = Vec3 { x: 0, y: -3, z: 2 };
+        for tick in 1..=3 {
+            tree.apply_uniform_field(field);
+            tree.run_movements(1.0);
+            tree.for_each_entity(|entity, _| assert_eq!(entity.speed, field.mul_scalar(tick)));
+        }
+
+        let before = tree.clone();
+        tree.apply_uniform_field(Vec3::ZERO);
+        tree.for_each_entity(|entity, _| assert_eq!(entity.external_forces, Vec3::ZERO));
+        assert_eq!(tree, before);
+    }
+
+    #[test]
+    fn drain_filter_entities_pulls_out_the_matching_ones() {
+        let mut tree = GrowableSpaceTree::new();
+        tree.insert_entity(entity_at(1000, 0, 0)).unwrap();
+        tree.insert_entity(entity_at(100_000, -5000, 3)).unwrap();
+        // Players stand for the projectiles to pull out
+        let mut player_ids = vec![];
+        for x in [-300_000, 5000].iter() {
+            let player = Entity::new_player(
+                Vec3 { x: *x, y: 0, z: 0 },
+                Rc::new(RefCell::new(Player::new())),
+            );
+            player_ids.push(player.id);
+            tree.insert_entity(Box::new(player)).unwrap();
+        }
+        tree.refresh();
+
+        let drained =
+            tree.drain_filter_entities(|entity, _| matches!(entity.entity, EntityData::Player(_)));
+        let mut drained: Vec<_> = drained
+            .iter()
+            .map(|entity| (entity.id, entity.bounding_sphere.center.x))
+            .collect();
+        drained.sort_unstable();
+        assert_eq!(
+            drained,
+            vec![(player_ids[0], -300_000), (player_ids[1], 5000)]
+        );
+        assert_eq!(tree.nb_entities(), 2);
+        tree.for_each_entity(|entity, _| assert!(!player_ids.contains(&entity.id)));
+    }
+
+    #[test]
+    fn detect_oscillating_flags_an_entity_bouncing_on_a_border() {
+        let mut tree = GrowableSpaceTree::new();
+        let anchor = entity_at(-30_000, -30_000, -30_000);
+        let anchor_id = anchor.id;
+        tree.insert_entity(anchor).unwrap();
+        // Matter nodes border each other at z = 16384
+        let mut hopper = entity_at(30_000, 30_000, 16_380);
+        hopper.bounding_sphere.radius = 1;
+        hopper.speed = Vec3 { x: 0, y: 0, z: 8 };
+        let hopper_id = hopper.id;
+        tree.insert_entity(hopper).unwrap();
+        tree.refresh();
+
+        for _ in 0..10 {
+            tree.run_movements(1.0);
+            tree.refresh();
+            tree.for_each_entity_mut(|entity, _| {
+                if entity.id == hopper_id {
+                    entity.speed = entity.speed.mul_scalar(-1);
+                }
+            });
+        }
+        assert_eq!(tree.detect_oscillating(5), vec![hopper_id]);
+        assert!(!tree.detect_oscillating(5).contains(&anchor_id));
+    }
+
+    #[test]
+    fn center_on_follows_an_entity_across_cells() {
+        let mut tree = GrowableSpaceTree::new();
+        let speed = Vec3 {
+            x: 3000,
+            y: -1000,
+            z: 500,
+        };
+        let start = Vec3 {
+            x: -20_000,
+            y: 1000,
+            z: 0,
+        };
+        let mut entity = entity_at(start.x, start.y, start.z);
+        entity.speed = speed;
+        let id = entity.id;
+        tree.insert_entity(entity).unwrap();
+        for tick in 1..=30 {
+            tree.run_movements(1.0);
+            tree.refresh();
+            let expected = start.add(&speed.mul_scalar(tick));
+            assert_eq!(tree.center_on(id), Some((expected, speed)));
+        }
+        // The world moved along with its only entity
+        assert_ne!(tree.center, Vec3::ZERO);
+        assert_eq!(tree.center_on(u64::MAX), None);
+    }
+
+    // Position of a particle after some ticks next to a force field emitter
+    fn particle_x_near_field(strength: f64) -> i64 {
+        let mut tree = GrowableSpaceTree::new();
+        let mut emitter = entity_at(0, 0, 0);
+        emitter.mass = 1.0;
+        emitter.force_field = Some(ForceField {
+            radius: 10_000,
+            strength,
+        });
+        tree.insert_entity(emitter).unwrap();
+        let mut particle = entity_at(2000, 0, 0);
+        particle.mass = 1.0;
+        let id = particle.id;
+        tree.insert_entity(particle).unwrap();
+        for _ in 0..5 {
+            tree.run_movements(1.0);
+            tree.refresh();
+        }
+        let (pos, speed) = tree.center_on(id).unwrap();
+        assert_eq!((pos.y, pos.z), (0, 0));
+        assert_eq!((speed.y, speed.z), (0, 0));
+        pos.x
+    }
+
+    #[test]
+    fn force_fields_push_and_pull() {
+        assert!(particle_x_near_field(100.0) > 2000);
+        assert!(particle_x_near_field(-100.0) < 2000);
+        assert_eq!(particle_x_near_field(0.0), 2000);
+    }
+
+    fn set_rock(grid: &mut VoxelGridSpace, x: usize) {
+        if let VoxelTree::Chunk(voxels) = &mut grid.voxels {
+            voxels[chunk_index(x, 0, 0)] = VoxelType::Rock;
+        }
+    }
+
+    // World position of the voxel grid origin of an entity at pos
+    fn grid_origin(entity: &Entity, pos: Vec3) -> Vec3 {
+        match &entity.entity {
+            EntityData::Voxels(grid) => pos.sub(&grid.bounding_sphere().center),
+            _ => panic!("not a voxel entity"),
+        }
+    }
+
+    #[test]
+    fn with_voxel_grid_mut_keeps_the_entity_in_sync() {
+        let mut tree = GrowableSpaceTree::new();
+        let mut grid = VoxelGridSpace::new();
+        set_rock(&mut grid, 0);
+        let origin = Vec3 {
+            x: 5000,
+            y: -3000,
+            z: 700,
+        };
+        let mut entity = Entity::new(
+            grid.bounding_sphere().add_to_center(&origin),
+            EntityData::Voxels(Box::new(grid)),
+        );
+        entity.mass = 1.0;
+        let id = entity.id;
+        let radius = entity.bounding_sphere.radius;
+        tree.insert_entity(Box::new(entity)).unwrap();
+        tree.refresh();
+
+        assert!(tree.with_voxel_grid_mut(id, |grid| set_rock(grid, 9)));
+        let (entity, pos) = tree.find_entity(id).unwrap();
+        assert_eq!(entity.mass, 2.0);
+        assert!(entity.bounding_sphere.radius > radius * 4);
+        assert_eq!(grid_origin(entity, pos), origin);
+        // Both voxel centers are in the bounding sphere
+        for x in [0, 9].iter() {
+            let voxel_center = Vec3 {
+                x: x * VOXEL_SIZE + VOXEL_SIZE / 2,
+                y: VOXEL_SIZE / 2,
+                z: VOXEL_SIZE / 2,
+            };
+            let distance = origin.add(&voxel_center).sub(&pos).length_f64();
+            assert!(distance <= entity.bounding_sphere.radius as f64);
+        }
+
+        for _ in 0..3 {
+            tree.run_movements(1.0);
+            tree.refresh();
+        }
+        let (entity, pos) = tree.find_entity(id).unwrap();
+        assert_eq!(grid_origin(entity, pos), origin);
+        assert_eq!(entity.mass, 2.0);
+        assert!(!tree.with_voxel_grid_mut(u64::MAX, |_| ()));
+    }
+
+    // A root parent with less than two non empty children could be collapsed
+    fn has_redundant_parent(tree: &GrowableSpaceTree) -> bool {
+        match tree.tree.as_ref() {
+            SpaceTree::Matter(_) => false,
+            SpaceTree::Parent(parent) => {
+                parent
+                    .sub_trees
+                    .iter()
+                    .flatten()
+                    .filter(|sub_tree| !sub_tree.is_empty())
+                    .count()
+                    < 2
+            }
+        }
+    }
+
+    #[test]
+    fn merged_worlds_keep_no_redundant_parent() {
+        let mut world = GrowableSpaceTree::new();
+        world.insert_entity(entity_at(100, 0, 0)).unwrap();
+        // Grows the world far away
+        let far = entity_at(5_000_000, 5_000_000, 5_000_000);
+        let far_id = far.id;
+        world.insert_entity(far).unwrap();
+        world.refresh();
+        assert!(matches!(*world.tree, SpaceTree::Parent(_)));
+
+        let mut other = GrowableSpaceTree::new();
+        other.insert_entity(entity_at(-100, 50, 0)).unwrap();
+        other.insert_entity(entity_at(0, -200, 30)).unwrap();
+        for entity in other.drain_filter_entities(|_, _| true) {
+            world.insert_entity(entity).unwrap();
+        }
+        world.drain_filter_entities(|entity, _| entity.id == far_id);
+        world.refresh();
+
+        assert_eq!(world.nb_entities(), 3);
+        assert!(!has_redundant_parent(&world));
+        assert_eq!(world.nb_nodes(), 1);
+    }
+
+    fn distance(tree: &GrowableSpaceTree, a: u64, b: u64) -> f64 {
+        let a = tree.find_entity(a).unwrap().1;
+        let b = tree.find_entity(b).unwrap().1;
+        a.sub(&b).length_f64()
+    }
+
+    #[test]
+    fn predict_collisions_warns_the_tick_before_the_overlap() {
+        let mut tree = GrowableSpaceTree::new();
+        let a = Entity::builder()
+            .radius(10)
+            .speed(Vec3 { x: 15, y: 0, z: 0 })
+            .build();
+        let b = Entity::builder()
+            .at(Vec3 { x: 100, y: 0, z: 0 })
+            .radius(10)
+            .speed(Vec3 { x: -15, y: 0, z: 0 })
+            .build();
+        let pair = (a.id, b.id);
+        tree.insert_entity(Box::new(a)).unwrap();
+        tree.insert_entity(Box::new(b)).unwrap();
+        tree.insert_entity(entity_at(0, 5000, 0)).unwrap();
+
+        // The gap goes 100, 70, 40, then 10 after the third tick
+        for _ in 0..2 {
+            assert_eq!(tree.predict_collisions(1.0), vec![]);
+            tree.run_movements(1.0);
+            tree.refresh();
+        }
+        assert_eq!(tree.predict_collisions(1.0), vec![pair]);
+        assert!(distance(&tree, pair.0, pair.1) > 20.0);
+        tree.run_movements(1.0);
+        assert!(distance(&tree, pair.0, pair.1) < 20.0);
+    }
+
+    #[test]
+    fn predict_collisions_across_matter_nodes() {
+        let mut tree = GrowableSpaceTree::new();
+        tree.insert_entity(entity_at(200_000, 0, 0)).unwrap();
+        let border = MatterTree::MAX_SIZE / 2;
+        let a = Entity::builder()
+            .at(Vec3 {
+                x: border - 15,
+                y: 0,
+                z: 0,
+            })
+            .radius(10)
+            .speed(Vec3 { x: 10, y: 0, z: 0 })
+            .build();
+        let b = Entity::builder()
+            .at(Vec3 {
+                x: border + 15,
+                y: 0,
+                z: 0,
+            })
+            .radius(10)
+            .speed(Vec3 { x: -10, y: 0, z: 0 })
+            .build();
+        let pair = (a.id, b.id);
+        tree.insert_entity(Box::new(a)).unwrap();
+        tree.insert_entity(Box::new(b)).unwrap();
+        tree.refresh();
+        let cells = tree.entity_cells();
+        assert_ne!(cells[&pair.0], cells[&pair.1]);
+
+        // The gap of 30 closes by 20 per tick
+        assert_eq!(tree.predict_collisions(0.5), vec![]);
+        assert_eq!(tree.predict_collisions(1.0), vec![pair]);
+    }
+
+    #[test]
+    fn emptied_world_shrinks_to_a_single_matter_node() {
+        let mut tree = GrowableSpaceTree::new();
+        for i in 0..6 {
+            let far = 1 << (20 + 3 * i);
+            tree.insert_entity(entity_at(far, -far, far / 2)).unwrap();
+        }
+        tree.refresh();
+        assert!(matches!(&*tree.tree, SpaceTree::Parent(parent) if parent.scale > 10));
+        assert!(tree.nb_nodes() > 1);
+
+        tree.drain_filter_entities(|_, _| true);
+        tree.refresh();
+        assert!(matches!(tree.tree.as_ref(), SpaceTree::Matter(_)));
+        assert_eq!(tree.nb_nodes(), 1);
+        assert_eq!(tree.nb_entities(), 0);
+    }
+
+    #[test]
+    fn teleport_across_the_world() {
+        let mut tree = GrowableSpaceTree::new();
+        let stayer = entity_at(0, 0, 0);
+        let stayer_id = stayer.id;
+        tree.insert_entity(stayer).unwrap();
+        let traveller = entity_at(1000, 1000, 1000);
+        let id = traveller.id;
+        tree.insert_entity(traveller).unwrap();
+        tree.refresh();
+
+        let destination = Vec3 {
+            x: -40_000_000,
+            y: 3_000_000,
+            z: 123,
+        };
+        assert!(tree.teleport(id, destination));
+        assert!(matches!(tree.tree.as_ref(), SpaceTree::Parent(_)));
+        assert_eq!(tree.find_entity(id).unwrap().1, destination);
+        tree.refresh();
+        assert_eq!(tree.find_entity(id).unwrap().1, destination);
+        assert_eq!(tree.find_entity(stayer_id).unwrap().1, Vec3::ZERO);
+        assert_eq!(tree.nb_entities(), 2);
+
+        assert!(!tree.teleport(u64::MAX, destination));
+    }
+
+    #[test]
+    fn extreme_insert_is_refused() {
+        let mut tree = GrowableSpaceTree::new();
+        for x in &[i64::MAX, i64::MIN] {
+            let entity = entity_at(*x, 0, 0);
+            let id = entity.id;
+            match tree.insert_entity(entity) {
+                Err(InsertError::OutOfUniverse(entity)) => assert_eq!(entity.id, id),
+                Ok(()) => panic!("Inserted an entity out of the universe"),
+            }
+        }
+        assert_eq!(tree.tree.scale(), Some(tree.max_scale()));
+        assert!(sorted_positions(&tree).is_empty());
+
+        let far = 1 << 60;
+        tree.insert_entity(entity_at(far, 0, 0)).unwrap();
+        assert_eq!(sorted_positions(&tree), vec![Vec3 { x: far, y: 0, z: 0 }]);
+    }
+
+    #[test]
+    fn frozen_region_stays_put() {
+        let mut tree = GrowableSpaceTree::new();
+        let speed = Vec3 { x: 5, y: -3, z: 1 };
+        let mut ids = vec![];
+        for x in &[-1000, 1000, 5000] {
+            let mut entity = entity_at(*x, 0, 0);
+            entity.speed = speed;
+            ids.push(entity.id);
+            tree.insert_entity(entity).unwrap();
+        }
+        let region = Cube {
+            origin: Vec3 {
+                x: -2000,
+                y: -2000,
+                z: -2000,
+            },
+            size: 4000,
+        };
+        assert_eq!(tree.set_frozen_in_region(&region, true), 2);
+
+        for _ in 0..10 {
+            tree.run_movements(1.0);
+            tree.refresh();
+        }
+        let pos = |tree: &GrowableSpaceTree, id| tree.entity_world_position(id).unwrap();
+        assert_eq!(
+            pos(&tree, ids[0]),
+            Vec3 {
+                x: -1000,
+                y: 0,
+                z: 0
+            }
+        );
+        assert_eq!(
+            pos(&tree, ids[1]),
+            Vec3 {
+                x: 1000,
+                y: 0,
+                z: 0
+            }
+        );
+        assert_eq!(
+            pos(&tree, ids[2]),
+            Vec3 {
+                x: 5050,
+                y: -30,
+                z: 10
+            }
+        );
+
+        assert_eq!(tree.set_frozen_in_region(&region, false), 2);
+        tree.run_movements(1.0);
+        assert_eq!(
+            pos(&tree, ids[0]),
+            Vec3 {
+                x: -995,
+                y: -3,
+                z: 1
+            }
+        );
+    }
+
+    #[test]
+    fn forces_of_several_subsystems_add_up_once() {
+        let mut tree = GrowableSpaceTree::new();
+        let player = Rc::new(RefCell::new(Player::new()));
+        player.borrow_mut().control_forces = Vec3 {
+            x: 1000,
+            y: 0,
+            z: 0,
+        };
+        let entity = Entity::new_player(Vec3::ZERO, player);
+        let id = entity.id;
+        tree.insert_entity(Box::new(entity)).unwrap();
+
+        tree.apply_uniform_field(Vec3 { x: 0, y: 0, z: -2 });
+        tree.run_movements(1.0);
+        let speed = |tree: &GrowableSpaceTree| tree.find_entity(id).unwrap().0.speed;
+        assert_eq!(speed(&tree), Vec3 { x: 10, y: 0, z: -2 });
+
+        // The field force was consumed by the previous movement step
+        tree.run_movements(1.0);
+        assert_eq!(speed(&tree), Vec3 { x: 20, y: 0, z: -2 });
+    }
+
+    #[test]
+    fn ray_entities_in_distance_order() {
+        let mut tree = GrowableSpaceTree::new();
+        let mut ids = vec![];
+        for x in &[3000, 1000, 2000] {
+            let entity = Box::new(
+                Entity::builder()
+                    .at(Vec3 { x: *x, y: 0, z: 0 })
+                    .radius(100)
+                    .build(),
+            );
+            ids.push(entity.id);
+            tree.insert_entity(entity).unwrap();
+        }
+        let off_ray = Box::new(
+            Entity::builder()
+                .at(Vec3 {
+                    x: 1500,
+                    y: 900,
+                    z: 0,
+                })
+                .radius(100)
+                .build(),
+        );
+        tree.insert_entity(off_ray).unwrap();
+
+        let hits = tree.ray_entities(Vec3::ZERO, Vec3 { x: 1, y: 0, z: 0 }, 10_000);
+        let hit_ids: Vec<_> = hits.iter().map(|(id, _)| *id).collect();
+        assert_eq!(hit_ids, vec![ids[1], ids[2], ids[0]]);
+        assert!((hits[0].1 - 900.0).abs() < 1e-6);
+
+        let short_hits = tree.ray_entities(Vec3::ZERO, Vec3 { x: 1, y: 0, z: 0 }, 2500);
+        assert_eq!(short_hits.len(), 2);
+    }
+
+    #[test]
+    fn line_of_sight_is_blocked_by_an_entity_in_between() {
+        let mut tree = GrowableSpaceTree::new();
+        let entity = |x: i64| {
+            Box::new(
+                Entity::builder()
+                    .at(Vec3 { x, y: 0, z: 0 })
+                    .radius(100)
+                    .build(),
+            )
+        };
+        let (a, b, blocker) = (entity(0), entity(5000), entity(2500));
+        let (a_id, b_id, blocker_id) = (a.id, b.id, blocker.id);
+        for entity in [a, b, blocker] {
+            tree.insert_entity(entity).unwrap();
+        }
+        assert!(!tree.has_line_of_sight(a_id, b_id));
+        assert!(!tree.has_line_of_sight(b_id, a_id));
+
+        assert!(tree.teleport(
+            blocker_id,
+            Vec3 {
+                x: 2500,
+                y: 1000,
+                z: 0
+            }
+        ));
+        assert!(tree.has_line_of_sight(a_id, b_id));
+    }
+
+    fn cube_contains(cube: &Cube, point: &Vec3) -> bool {
+        let axis_contains = |pos: i64, min: i64| pos >= min && pos < min + cube.size;
+        axis_contains(point.x, cube.origin.x)
+            && axis_contains(point.y, cube.origin.y)
+            && axis_contains(point.z, cube.origin.z)
+    }
+
+    #[test]
+    fn leaves_tile_the_occupied_space() {
+        let mut tree = GrowableSpaceTree::new();
+        for (x, y) in &[
+            (0, 5),
+            (100_000, 5),
+            (-70_000, 5),
+            (-70_000, 40_000),
+            (120, 80),
+        ] {
+            tree.insert_entity(entity_at(*x, *y, 5)).unwrap();
+        }
+        let leaves: Vec<_> = tree.iter_leaves().collect();
+        assert_eq!(
+            leaves
+                .iter()
+                .map(|(_, nb_entities)| nb_entities)
+                .sum::<usize>(),
+            tree.nb_entities()
+        );
+
+        // Leaves do not overlap
+        for (i, (a, _)) in leaves.iter().enumerate() {
+            for (b, _) in leaves[i + 1..].iter() {
+                let (small, big) = if a.size <= b.size { (a, b) } else { (b, a) };
+                assert!(!cube_contains(big, &small.origin));
+            }
+        }
+        // Each entity is in a single leaf, an occupied one
+        tree.for_each_entity(|_, pos| {
+            let containing: Vec<_> = leaves
+                .iter()
+                .filter(|(area, _)| cube_contains(area, &pos))
+                .collect();
+            assert_eq!(containing.len(), 1);
+            assert!(containing[0].1 > 0);
+        });
+    }
+
+    #[test]
+    fn boundary_exit_fires_once_on_the_crossing_tick() {
+        let mut tree = GrowableSpaceTree::new();
+        let mut leaving = entity_at(0, 0, 0);
+        leaving.speed = Vec3 { x: 300, y: 0, z: 0 };
+        let leaving_id = leaving.id;
+        tree.insert_entity(leaving).unwrap();
+        tree.insert_entity(entity_at(0, 500, 0)).unwrap();
+
+        let exits = Rc::new(RefCell::new(vec![]));
+        let recorded_exits = exits.clone();
+        let area = Cube {
+            origin: Vec3 {
+                x: -1000,
+                y: -1000,
+                z: -1000,
+            },
+            size: 2000,
+        };
+        tree.on_boundary_exit(area, move |id| recorded_exits.borrow_mut().push(id));
+
+        for tick in 1..=10 {
+            tree.run_movements(1.0);
+            tree.refresh();
+            // The entity is at x = 900 after the third tick, and at 1200 after the fourth
+            if tick < 4 {
+                assert!(exits.borrow().is_empty());
+            } else {
+                assert_eq!(*exits.borrow(), vec![leaving_id]);
+            }
+        }
+    }
+
+    #[test]
+    fn find_player_entity_by_its_player() {
+        let mut tree = GrowableSpaceTree::new();
+        let player = Rc::new(RefCell::new(Player::new()));
+        let other = Rc::new(RefCell::new(Player::new()));
+        let entity = Entity::new_player(
+            Vec3 {
+                x: 40_000,
+                y: 0,
+                z: 0,
+            },
+            player.clone(),
+        );
+        let id = entity.id;
+        tree.insert_entity(Box::new(entity)).unwrap();
+        tree.insert_entity(Box::new(Entity::new_player(Vec3::ZERO, other)))
+            .unwrap();
+        tree.insert_entity(entity_at(40_000, 0, 0)).unwrap();
+
+        assert_eq!(tree.find_player_entity(&player), Some(id));
+        let absent = Rc::new(RefCell::new(Player::new()));
+        assert_eq!(tree.find_player_entity(&absent), None);
+    }
+
+    #[test]
+    fn precision_warning_threshold() {
+        let mut tree = GrowableSpaceTree::new();
+        let far_x = GrowableSpaceTree::MAX_WORLD_COORDINATE / 4 * 3;
+        let far = entity_at(far_x, 0, 0);
+        let far_id = far.id;
+        tree.insert_entity(far).unwrap();
+        tree.insert_entity(entity_at(0, 1000, 0)).unwrap();
+        assert_eq!(tree.check_precision(), vec![far_id]);
+
+        tree.precision_warning_ratio = 0.9;
+        assert!(tree.check_precision().is_empty());
+    }
+
+    #[test]
+    fn explosion_pushes_entities_and_breaks_voxels() {
+        let mut tree = GrowableSpaceTree::new();
+        // Wall one voxel thick, in the x = 0 plane of its grid
+        let mut grid = VoxelGridSpace::new();
+        if let VoxelTree::Chunk(voxels) = &mut grid.voxels {
+            for y in 0..10 {
+                for z in 0..10 {
+                    voxels[chunk_index(0, y, z)] = VoxelType::Rock;
+                }
+            }
+        }
+        // The grid origin is at the position given to new_voxels
+        let grid_origin = Vec3::ZERO;
+        let wall = Entity::new_voxels(grid_origin, grid, &tree.materials);
+        let (wall_id, wall_mass) = (wall.id, wall.mass);
+        tree.insert_entity(Box::new(wall)).unwrap();
+
+        let at_grid = |x: i64, y: i64, z: i64| grid_origin.add(&Vec3 { x, y, z });
+        let loose = Entity::builder()
+            .at(at_grid(-300, 800, 450))
+            .radius(20)
+            .mass(1.0)
+            .build();
+        let loose_id = loose.id;
+        tree.insert_entity(Box::new(loose)).unwrap();
+        let far = Entity::builder()
+            .at(at_grid(-3000, 450, 450))
+            .radius(20)
+            .mass(1.0)
+            .build();
+        let far_id = far.id;
+        tree.insert_entity(Box::new(far)).unwrap();
+
+        // The blast hits the voxel (0, 4, 4), 150 away, with 1500 of energy and 450 of radius
+        // left. Voxels break within 150 of it, where the energy left is VOXEL_BREAK_ENERGY: the 9
+        // voxels whose (y, z) are within one voxel of (4, 4) on each axis.
+        tree.apply_explosion(at_grid(-100, 450, 450), 2000.0, 600);
+        let (wall, _) = tree.find_entity(wall_id).unwrap();
+        assert_eq!(wall.mass, wall_mass - 9.0);
+        let (loose, _) = tree.find_entity(loose_id).unwrap();
+        assert!(loose.speed.x < 0);
+        assert!(loose.speed.y > 0);
+        assert_eq!(loose.speed.z, 0);
+        assert_eq!(tree.find_entity(far_id).unwrap().0.speed, Vec3::ZERO);
+    }
+
+    #[test]
+    fn compact_ids_renumbers_the_survivors() {
+        let mut tree = GrowableSpaceTree::new();
+        let mut positions = HashMap::new();
+        for i in 0..20 {
+            let entity = entity_at(i * 1000, 0, 0);
+            positions.insert(entity.id, entity.bounding_sphere.center);
+            tree.insert_entity(entity).unwrap();
+        }
+        let removed = tree.drain_filter_entities(|_, pos| pos.x % 2000 != 0);
+        assert_eq!(removed.len(), 10);
+
+        let mapping = tree.compact_ids();
+        let mut old_ids: Vec<_> = mapping.keys().copied().collect();
+        old_ids.sort_unstable();
+        let new_ids: Vec<_> = old_ids.iter().map(|id| mapping[id]).collect();
+        assert_eq!(new_ids, (1..=10).collect::<Vec<_>>());
+        for (old_id, new_id) in mapping.iter() {
+            assert_eq!(tree.entity_world_position(*new_id), Some(positions[old_id]));
+        }
+        assert!(removed
+            .iter()
+            .all(|entity| !mapping.contains_key(&entity.id)));
+    }
+
+    #[test]
+    fn densest_leaf_holds_the_cluster() {
+        let mut tree = GrowableSpaceTree::new();
+        assert_eq!(tree.densest_leaf(), None);
+
+        for i in 0..6 {
+            tree.insert_entity(entity_at(-30_000 + i * 50, -30_000, -30_000))
+                .unwrap();
+        }
+        tree.insert_entity(entity_at(30_000, 30_000, 30_000))
+            .unwrap();
+        tree.insert_entity(entity_at(30_000, -30_000, 30_000))
+            .unwrap();
+        tree.refresh();
+
+        let (leaf, nb_entities) = tree.densest_leaf().unwrap();
+        assert_eq!(nb_entities, 6);
+        assert!(leaf.contains(&Vec3 {
+            x: -30_000,
+            y: -30_000,
+            z: -30_000,
+        }));
+        assert!(!leaf.contains(&Vec3 {
+            x: 30_000,
+            y: 30_000,
+            z: 30_000,
+        }));
+    }
+
+    #[test]
+    fn entities_straddling_a_matter_border_collide() {
+        let mut tree = GrowableSpaceTree::new();
+        tree.insert_entity(entity_at(-30_000, -30_000, -30_000))
+            .unwrap();
+        // Matter nodes border each other at z = 16384
+        let ball = |z: i64, speed: i64| {
+            Box::new(
+                Entity::builder()
+                    .at(Vec3 {
+                        x: 30_000,
+                        y: 30_000,
+                        z,
+                    })
+                    .radius(100)
+                    .mass(1.0)
+                    .speed(Vec3 {
+                        x: 0,
+                        y: 0,
+                        z: speed,
+                    })
+                    .build(),
+            )
+        };
+        let (below, above) = (ball(16_300, 10), ball(16_460, -10));
+        let (below_id, above_id) = (below.id, above.id);
+        tree.insert_entity(below).unwrap();
+        tree.insert_entity(above).unwrap();
+        tree.refresh();
+        let cells = tree.entity_cells();
+        assert_ne!(cells[&below_id], cells[&above_id]);
+
+        tree.apply_collisions();
+        assert_eq!(
+            tree.find_entity(below_id).unwrap().0.speed,
+            Vec3 { x: 0, y: 0, z: -10 }
+        );
+        assert_eq!(
+            tree.find_entity(above_id).unwrap().0.speed,
+            Vec3 { x: 0, y: 0, z: 10 }
+        );
+    }
+
+    // Launches an entity along +x for 30 ticks. Returns the largest x reached by the leaves and by
+    // the entity, and whether the entity got reflected.
+    fn launch_along_x(limit: Option<i64>) -> (i64, i64, bool) {
+        let mut tree = GrowableSpaceTree::new();
+        if let Some(limit) = limit {
+            tree.set_axis_limit(Axis::X, limit);
+        }
+        let mut entity = entity_at(0, 5, 5);
+        entity.mass = 1.0;
+        entity.speed = Vec3 {
+            x: 1000,
+            y: 0,
+            z: 0,
+        };
+        let id = entity.id;
+        tree.insert_entity(entity).unwrap();
+
+        let (mut max_leaf_x, mut max_x, mut reflected) = (0, 0, false);
+        for _ in 0..30 {
+            tree.run_movements(1.0);
+            tree.refresh();
+            let leaf_x = tree
+                .iter_leaves()
+                .map(|(area, _)| area.origin.x + area.size)
+                .max()
+                .unwrap();
+            max_leaf_x = i64::max(max_leaf_x, leaf_x);
+            let (entity, pos) = tree.find_entity(id).unwrap();
+            max_x = i64::max(max_x, pos.x);
+            reflected |= entity.speed.x == -1000;
+        }
+        (max_leaf_x, max_x, reflected)
+    }
+
+    #[test]
+    fn axis_limit_caps_the_growth() {
+        let (free_leaf_x, free_x, free_reflected) = launch_along_x(None);
+        assert!(free_x > 25_000);
+        assert!(!free_reflected);
+
+        let (capped_leaf_x, capped_x, reflected) = launch_along_x(Some(10_000));
+        assert!(capped_leaf_x < free_leaf_x);
+        assert!(capped_x <= 10_000);
+        assert!(reflected);
+    }
+
+    #[test]
+    fn diff_state_reports_the_perturbed_entity() {
+        let mut tree = GrowableSpaceTree::new();
+        let mut ids = vec![];
+        for i in 0..5 {
+            let mut entity = entity_at(i * 3000, 5, 5);
+            entity.mass = 1.0;
+            entity.speed = Vec3 { x: 100, y: 0, z: 0 };
+            ids.push(entity.id);
+            tree.insert_entity(entity).unwrap();
+        }
+        for _ in 0..3 {
+            tree.run_movements(1.0);
+            tree.refresh();
+        }
+
+        let mut other = tree.clone();
+        assert!(tree.diff_state(&other).is_empty());
+        let pos = other.entity_world_position(ids[2]).unwrap();
+        other.teleport(ids[2], pos.add(&Vec3 { x: 7, y: 0, z: 0 }));
+        other.find_entity_mut(ids[2]).unwrap().0.speed.y = 3;
+        assert_eq!(
+            tree.diff_state(&other),
+            vec![EntityDelta::Changed {
+                id: ids[2],
+                position: Vec3 { x: 7, y: 0, z: 0 },
+                speed: Vec3 { x: 0, y: 3, z: 0 },
+                mass: 0.0,
+            }]
+        );
+    }
+
+    #[test]
+    fn trajectory_holds_the_last_refreshed_positions() {
+        let mut tree = GrowableSpaceTree::new();
+        let mover = Entity::builder()
+            .at(Vec3 {
+                x: 16000,
+                y: 5,
+                z: 5,
+            })
+            .mass(1.0)
+            .speed(Vec3 {
+                x: 150,
+                y: 20,
+                z: 0,
+            })
+            .build();
+        let other = Entity::builder().at(Vec3::ZERO).build();
+        let (id, other_id) = (mover.id, other.id);
+        tree.insert_entity(Box::new(mover)).unwrap();
+        tree.insert_entity(Box::new(other)).unwrap();
+
+        assert!(tree.record_trajectory(id, 3));
+        assert!(tree.entity_trajectory(id).unwrap().is_empty());
+        assert!(tree.entity_trajectory(other_id).is_none());
+
+        let mut positions = vec![];
+        for _ in 0..5 {
+            tree.run_movements(1.0);
+            tree.refresh();
+            positions.push(tree.find_entity(id).unwrap().1);
+        }
+        // Only the last 3 positions are kept, oldest first
+        assert_eq!(tree.entity_trajectory(id).unwrap(), &positions[2..]);
+        assert_eq!(
+            positions[4],
+            Vec3 {
+                x: 16750,
+                y: 105,
+                z: 5
+            }
+        );
+    }
+
+    #[test]
+    fn ids_stay_findable_after_moving() {
+        let mut tree = GrowableSpaceTree::new();
+        let mut ids = vec![];
+        for i in 0..6 {
+            let entity = Entity::builder()
+                .at(Vec3 {
+                    x: i * 5000 - 12000,
+                    y: 16000 - i * 300,
+                    z: 0,
+                })
+                .mass(1.0)
+                .speed(Vec3 {
+                    x: 300 - i * 100,
+                    y: 200,
+                    z: -i * 50,
+                })
+                .build();
+            ids.push(entity.id);
+            tree.insert_entity(Box::new(entity)).unwrap();
+        }
+        let mut unique_ids = ids.clone();
+        unique_ids.sort_unstable();
+        unique_ids.dedup();
+        assert_eq!(unique_ids.len(), ids.len());
+
+        for _ in 0..10 {
+            tree.run_movements(1.0);
+            tree.refresh();
+        }
+        for id in ids {
+            let (entity, _) = tree.find_entity(id).unwrap();
+            assert_eq!(entity.id, id);
+        }
+        assert!(tree.find_entity(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn player_mines_and_places_the_aimed_voxel() {
+        let mut grid = VoxelGridSpace::new();
+        for x in 0..3 {
+            for y in 0..3 {
+                for z in 0..3 {
+                    grid.set((x, y, z), VoxelType::Rock);
+                }
+            }
+        }
+        let mut tree = GrowableSpaceTree::new();
+        let wall = Entity::new_voxels(
+            Vec3 {
+                x: 0,
+                y: 1000,
+                z: 0,
+            },
+            grid,
+            &tree.materials,
+        );
+        let (wall_id, full_mass) = (wall.id, wall.mass);
+        tree.insert_entity(Box::new(wall)).unwrap();
+        // Looking along y at the middle column of the wall
+        let player = Rc::new(RefCell::new(Player::new()));
+        let pos = Vec3 {
+            x: 150,
+            y: 0,
+            z: 150,
+        };
+        tree.insert_entity(Box::new(Entity::new_player(pos, player.clone())))
+            .unwrap();
+        let aimed_voxel =
+            |tree: &GrowableSpaceTree| match &tree.find_entity(wall_id).unwrap().0.entity {
+                EntityData::Voxels(grid) => (
+                    grid.get(&Vec3 { x: 1, y: 0, z: 1 }),
+                    grid.iter_solid().count(),
+                ),
+                _ => unreachable!(),
+            };
+
+        player.borrow_mut().mine_voxel = true;
+        tree.run_actions();
+        assert_eq!(aimed_voxel(&tree), (VoxelType::Empty, 26));
+        assert!(tree.find_entity(wall_id).unwrap().0.mass < full_mass);
+
+        player.borrow_mut().mine_voxel = false;
+        player.borrow_mut().place_voxel = true;
+        tree.run_actions();
+        assert_eq!(aimed_voxel(&tree), (VoxelType::Rock, 27));
+    }
+
+    #[test]
+    fn distance_between_entities_levels_apart() {
+        let mut tree = GrowableSpaceTree::new();
+        let near = entity_at(100, 200, -300);
+        let far = entity_at(3 << 40, -(1 << 38), 5);
+        let farther = entity_at(-(3 << 50), 1 << 50, 7 << 49);
+        let (near_id, far_id, farther_id) = (near.id, far.id, farther.id);
+        tree.insert_entity(near).unwrap();
+        tree.insert_entity(far).unwrap();
+        tree.insert_entity(farther).unwrap();
+        tree.refresh();
+
+        // Computed by hand from the inserted positions
+        let manual = ((100f64 - (3u64 << 40) as f64).powi(2)
+            + (200f64 + (1u64 << 38) as f64).powi(2)
+            + (-305f64).powi(2))
+        .sqrt();
+        assert!((tree.distance_between(near_id, far_id).unwrap() - manual).abs() < 1.0);
+
+        let pos = |id| tree.entity_world_position(id).unwrap();
+        let (far_pos, farther_pos) = (pos(far_id), pos(farther_id));
+        let expected = ((far_pos.x as f64 - farther_pos.x as f64).powi(2)
+            + (far_pos.y as f64 - farther_pos.y as f64).powi(2)
+            + (far_pos.z as f64 - farther_pos.z as f64).powi(2))
+        .sqrt();
+        assert_eq!(tree.distance_between(far_id, farther_id), Some(expected));
+        assert_eq!(tree.distance_between(near_id, near_id), Some(0.0));
+        assert!(tree.distance_between(near_id, u64::MAX).is_none());
+    }
+
+    fn run_config(config: MatterTreeConfig) {
+        let mut tree = GrowableSpaceTree::new_with_config(config).unwrap();
+        assert_eq!(tree.tree.size(), config.size());
+
+        // Far enough for the tree to grow several parent levels
+        let far = config.size() * 5;
+        let near = Entity::builder().at(Vec3::ZERO).radius(1).build();
+        let far = Entity::builder()
+            .at(Vec3 {
+                x: far,
+                y: -far,
+                z: far,
+            })
+            .radius(1)
+            .build();
+        let (near_id, far_id) = (near.id, far.id);
+        tree.insert_entity(Box::new(near)).unwrap();
+        tree.insert_entity(Box::new(far)).unwrap();
+        tree.refresh();
+        assert_eq!(tree.tree.scale(), Some(2));
+        assert_eq!(tree.tree.size(), config.size() << 3);
+        assert_eq!(tree.find_entity(near_id).unwrap().1, Vec3::ZERO);
+        assert_eq!(
+            tree.find_entity(far_id).unwrap().1,
+            Vec3 {
+                x: config.size() * 5,
+                y: -config.size() * 5,
+                z: config.size() * 5,
+            }
+        );
+        tree.tree.for_each_matter(Vec3::ZERO, &mut |matter, _| {
+            assert_eq!(matter.config, config)
+        });
+
+        let mut out = Encoder::new();
+        tree.encode(&mut out);
+        let bytes = out.into_bytes();
+        let decoded =
+            GrowableSpaceTree::decode(&mut Decoder::new(&bytes, VOXEL_SCHEMA_VERSION)).unwrap();
+        assert_eq!(decoded.matter_config(), config);
+        assert_eq!(decoded.tree, tree.tree);
+    }
+
+    #[test]
+    fn tiny_config() {
+        run_config(MatterTreeConfig {
+            min_size_pow: 5,
+            max_scale: 2,
+        });
+    }
+
+    #[test]
+    fn large_config() {
+        run_config(MatterTreeConfig {
+            min_size_pow: 5,
+            max_scale: 50,
+        });
+    }
+
+    #[test]
+    fn max_scale_keeps_the_world_size() {
+        let tree = GrowableSpaceTree::new();
+        let max_size = MatterTree::MAX_SIZE << (tree.max_scale() + 1);
+        assert_eq!(max_size, GrowableSpaceTree::MAX_WORLD_COORDINATE * 2);
+    }
+}
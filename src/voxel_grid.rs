@@ -1,15 +1,111 @@
 use crate::{
-    geometry::{Mat3, NB_QUADRANTS},
+    geometry::{Mat3, OrientedBox, Quadrant, Sphere, Vec3, NB_QUADRANTS},
     matter_tree::MatterTree,
+    serialization::{Decoder, DeserializeError, Encoder},
 };
+use std::collections::{HashSet, VecDeque};
 
+// Side length of a voxel, in space units
+pub const VOXEL_SIZE: i64 = 100;
 pub const CHUNK_SIZE: usize = 32;
 pub const NB_VOXELS_PER_CHUNK: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+// Energy needed to break a voxel of density 1
+pub const VOXEL_BREAK_ENERGY: f64 = 1000.0;
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum VoxelType {
     Empty,
     Rock,
 }
+pub const NB_VOXEL_TYPES: usize = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Material {
+    // Mass of a single voxel
+    pub density: f64,
+    pub friction: f64,
+    pub solid: bool,
+}
+
+// Physical properties of each voxel type, so that games can tune them
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaterialTable {
+    materials: [Material; NB_VOXEL_TYPES],
+}
+
+impl Default for MaterialTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MaterialTable {
+    pub fn new() -> Self {
+        Self {
+            materials: [
+                // Empty
+                Material {
+                    density: 0.0,
+                    friction: 0.0,
+                    solid: false,
+                },
+                // Rock
+                Material {
+                    density: 1.0,
+                    friction: 0.6,
+                    solid: true,
+                },
+            ],
+        }
+    }
+
+    pub fn get(&self, voxel: VoxelType) -> &Material {
+        &self.materials[voxel as usize]
+    }
+
+    pub fn set(&mut self, voxel: VoxelType, material: Material) {
+        self.materials[voxel as usize] = material;
+    }
+}
+
+// Saved voxels are tagged with the schema version they were written with, and each voxel is stored
+// as its index in the voxel type layout of that version
+pub const VOXEL_SCHEMA_VERSION: u8 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VoxelDecodeError {
+    UnknownSchemaVersion(u8),
+    UnknownVoxelType { version: u8, index: u8 },
+}
+
+impl VoxelType {
+    // Voxel type layout of every schema version, starting from version 1. New types must get a new
+    // layout instead of changing a released one.
+    const SCHEMA_LAYOUTS: [&'static [Self]; VOXEL_SCHEMA_VERSION as usize] =
+        [&[Self::Empty, Self::Rock]];
+
+    fn schema_layout(version: u8) -> Result<&'static [Self], VoxelDecodeError> {
+        if version == 0 || version > VOXEL_SCHEMA_VERSION {
+            return Err(VoxelDecodeError::UnknownSchemaVersion(version));
+        }
+        Ok(Self::SCHEMA_LAYOUTS[version as usize - 1])
+    }
+
+    // Index of the voxel type in the current schema version
+    pub fn to_index(self) -> u8 {
+        Self::SCHEMA_LAYOUTS[VOXEL_SCHEMA_VERSION as usize - 1]
+            .iter()
+            .position(|voxel| *voxel == self)
+            .unwrap() as u8
+    }
+
+    // Reads a voxel type saved under any known schema version
+    pub fn from_index(version: u8, index: u8) -> Result<Self, VoxelDecodeError> {
+        Self::schema_layout(version)?
+            .get(index as usize)
+            .copied()
+            .ok_or(VoxelDecodeError::UnknownVoxelType { version, index })
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum VoxelTree {
@@ -17,16 +113,163 @@ pub enum VoxelTree {
     Chunk(Box<[VoxelType; NB_VOXELS_PER_CHUNK]>),
 }
 
+// Voxels of a chunk are stored x major, then y, then z
+pub fn chunk_index(x: usize, y: usize, z: usize) -> usize {
+    (x * CHUNK_SIZE + y) * CHUNK_SIZE + z
+}
+
+pub type VoxelCoord = (usize, usize, usize);
+
+const FACE_NEIGHBOURS: [Vec3; 6] = [
+    Vec3 { x: 1, y: 0, z: 0 },
+    Vec3 { x: -1, y: 0, z: 0 },
+    Vec3 { x: 0, y: 1, z: 0 },
+    Vec3 { x: 0, y: -1, z: 0 },
+    Vec3 { x: 0, y: 0, z: 1 },
+    Vec3 { x: 0, y: 0, z: -1 },
+];
+
 impl VoxelTree {
     pub fn new_chunk() -> Self {
         Self::Chunk(Box::new([VoxelType::Empty; NB_VOXELS_PER_CHUNK]))
     }
+
+    // Side length, in voxels
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Chunk(_) => CHUNK_SIZE,
+            Self::Parent(parent) => parent.size(),
+        }
+    }
+
+    // Voxels out of the tree are empty
+    pub fn get(&self, (x, y, z): VoxelCoord) -> VoxelType {
+        let size = self.size();
+        if x >= size || y >= size || z >= size {
+            return VoxelType::Empty;
+        }
+        match self {
+            Self::Chunk(voxels) => voxels[chunk_index(x, y, z)],
+            Self::Parent(parent) => {
+                let sub_size = parent.size() / 2;
+                let quadrant = (x >= sub_size) as usize * (1 << 2)
+                    + (y >= sub_size) as usize * (1 << 1)
+                    + (z >= sub_size) as usize;
+                match &parent.sub_cells[quadrant] {
+                    Some(cell) => cell.get((x % sub_size, y % sub_size, z % sub_size)),
+                    None => VoxelType::Empty,
+                }
+            }
+        }
+    }
+
+    pub fn set(&mut self, (x, y, z): VoxelCoord, voxel: VoxelType) {
+        let size = self.size();
+        debug_assert!(
+            x < size && y < size && z < size,
+            "Voxel {:?} is out of a tree of size {}",
+            (x, y, z),
+            size
+        );
+        match self {
+            Self::Chunk(voxels) => voxels[chunk_index(x, y, z)] = voxel,
+            Self::Parent(parent) => {
+                let sub_size = parent.size() / 2;
+                let quadrant = (x >= sub_size) as usize * (1 << 2)
+                    + (y >= sub_size) as usize * (1 << 1)
+                    + (z >= sub_size) as usize;
+                if parent.sub_cells[quadrant].is_none() {
+                    if voxel == VoxelType::Empty {
+                        return;
+                    }
+                    parent.sub_cells[quadrant] = Some(Box::new(if parent.scale == 0 {
+                        Self::new_chunk()
+                    } else {
+                        Self::Parent(VoxelTreeParent::new(parent.scale - 1))
+                    }));
+                }
+                parent.sub_cells[quadrant]
+                    .as_mut()
+                    .unwrap()
+                    .set((x % sub_size, y % sub_size, z % sub_size), voxel);
+            }
+        }
+    }
+
+    fn for_each_solid<F: FnMut(VoxelCoord, VoxelType)>(&self, origin: VoxelCoord, f: &mut F) {
+        match self {
+            Self::Chunk(voxels) => {
+                for x in 0..CHUNK_SIZE {
+                    for y in 0..CHUNK_SIZE {
+                        for z in 0..CHUNK_SIZE {
+                            let voxel = voxels[chunk_index(x, y, z)];
+                            if voxel != VoxelType::Empty {
+                                f((origin.0 + x, origin.1 + y, origin.2 + z), voxel);
+                            }
+                        }
+                    }
+                }
+            }
+            Self::Parent(parent) => {
+                let sub_size = parent.size() / 2;
+                for (i, sub_cell) in parent.sub_cells.iter().enumerate() {
+                    if let Some(cell) = sub_cell {
+                        let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                        let sub_origin = (
+                            origin.0 + quadrant.x_p() as usize * sub_size,
+                            origin.1 + quadrant.y_p() as usize * sub_size,
+                            origin.2 + quadrant.z_p() as usize * sub_size,
+                        );
+                        cell.for_each_solid(sub_origin, f);
+                    }
+                }
+            }
+        }
+    }
 }
 
+// Sub cells of a scale 0 parent are chunks
 #[derive(Debug, Clone, PartialEq)]
 pub struct VoxelTreeParent {
     pub scale: u32,
-    pub sub_cells: [Option<Box<Self>>; NB_QUADRANTS],
+    pub sub_cells: [Option<Box<VoxelTree>>; NB_QUADRANTS],
+}
+
+impl VoxelTreeParent {
+    const NONE_SUB_CELL: Option<Box<VoxelTree>> = None;
+
+    pub fn new(scale: u32) -> Self {
+        Self {
+            scale,
+            sub_cells: [Self::NONE_SUB_CELL; NB_QUADRANTS],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        CHUNK_SIZE << (self.scale + 1)
+    }
+}
+
+// Extent of the non empty voxels of a grid, in space units, in the grid axes (before its
+// orientation) and relative to its origin
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GridShape {
+    // Center of the tight bounding sphere, which voxel entities are centered on
+    pub center: Vec3,
+    // Lowest and highest corners of the box enclosing the voxels, None for empty grids
+    pub bounds: Option<(Vec3, Vec3)>,
+}
+
+impl GridShape {
+    // Box enclosing the voxels, relative to the grid origin
+    pub fn oriented_box(&self, orientation: &Mat3) -> Option<OrientedBox> {
+        let (min, max) = self.bounds?;
+        Some(OrientedBox {
+            center: orientation.mul_vec(&min.add(&max).div_scalar(2)),
+            half_extents: max.sub(&min).div_scalar(2),
+            orientation: *orientation,
+        })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -34,6 +277,14 @@ pub struct VoxelGridSpace {
     pub voxels: VoxelTree,
     pub local_space: MatterTree,
     pub orientation: Mat3,
+    // Set when voxels change, until the owning entity mass is refreshed
+    pub mass_dirty: bool,
+}
+
+impl Default for VoxelGridSpace {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VoxelGridSpace {
@@ -42,6 +293,799 @@ impl VoxelGridSpace {
             voxels: VoxelTree::new_chunk(),
             local_space: MatterTree::new(),
             orientation: Mat3::IDENTITY,
+            mass_dirty: false,
+        }
+    }
+
+    pub fn set(&mut self, coord: VoxelCoord, voxel: VoxelType) {
+        self.voxels.set(coord, voxel);
+        self.mass_dirty = true;
+    }
+
+    // Every non empty voxel, with its coordinates relative to the grid origin
+    pub fn iter_solid(&self) -> impl Iterator<Item = (VoxelCoord, VoxelType)> {
+        let mut solid = vec![];
+        self.voxels
+            .for_each_solid((0, 0, 0), &mut |coord, voxel| solid.push((coord, voxel)));
+        solid.into_iter()
+    }
+
+    // Lowest and highest corners of the box enclosing the non empty voxels, in space units, in the
+    // grid axes
+    fn solid_bounds(&self) -> Option<(Vec3, Vec3)> {
+        let mut bounds: Option<(Vec3, Vec3)> = None;
+        for ((x, y, z), _) in self.iter_solid() {
+            let voxel = Vec3 {
+                x: x as i64,
+                y: y as i64,
+                z: z as i64,
+            };
+            bounds = Some(match bounds {
+                None => (voxel, voxel),
+                Some((min, max)) => (
+                    Vec3 {
+                        x: i64::min(min.x, voxel.x),
+                        y: i64::min(min.y, voxel.y),
+                        z: i64::min(min.z, voxel.z),
+                    },
+                    Vec3 {
+                        x: i64::max(max.x, voxel.x),
+                        y: i64::max(max.y, voxel.y),
+                        z: i64::max(max.z, voxel.z),
+                    },
+                ),
+            });
         }
+        bounds.map(|(min, max)| {
+            (
+                min.mul_scalar(VOXEL_SIZE),
+                max.add(&Vec3 { x: 1, y: 1, z: 1 }).mul_scalar(VOXEL_SIZE),
+            )
+        })
+    }
+
+    // Corners of the non empty voxels, in space units, in the grid axes
+    fn solid_corners(&self) -> Vec<Vec3> {
+        let mut corners = vec![];
+        for ((x, y, z), _) in self.iter_solid() {
+            for i in 0..NB_QUADRANTS {
+                let quadrant: Quadrant = num::FromPrimitive::from_usize(i).unwrap();
+                let corner = Vec3 {
+                    x: (x + quadrant.x_p() as usize) as i64,
+                    y: (y + quadrant.y_p() as usize) as i64,
+                    z: (z + quadrant.z_p() as usize) as i64,
+                };
+                corners.push(corner.mul_scalar(VOXEL_SIZE));
+            }
+        }
+        corners
+    }
+
+    // Sphere enclosing the non empty voxels, in space units, relative to the grid origin and
+    // rotated by the grid orientation
+    pub fn bounding_sphere(&self) -> Sphere {
+        let (min, max) = match self.solid_bounds() {
+            Some(bounds) => bounds,
+            None => {
+                return Sphere {
+                    center: Vec3::ZERO,
+                    radius: 0,
+                }
+            }
+        };
+
+        let center = min.add(&max).div_scalar(2);
+        Sphere {
+            center: self.orientation.mul_vec(&center),
+            radius: f64::ceil(max.sub(&min).length_f64() / 2.0) as i64,
+        }
+    }
+
+    // What the entities keep of the grid, so that collisions do not go through the voxels
+    pub fn shape(&self) -> GridShape {
+        GridShape {
+            center: Self::tight_center(&self.solid_corners()),
+            bounds: self.solid_bounds(),
+        }
+    }
+
+    // Close to minimal sphere center for the corners (Ritter's algorithm)
+    fn tight_center(corners: &[Vec3]) -> Vec3 {
+        if corners.is_empty() {
+            return Vec3::ZERO;
+        }
+
+        let farthest = |from: &Vec3| {
+            *corners
+                .iter()
+                .max_by_key(|corner| corner.sub(from).length_f64() as i64)
+                .unwrap()
+        };
+        let a = farthest(&corners[0]);
+        let b = farthest(&a);
+        let mut center = (
+            (a.x + b.x) as f64 / 2.0,
+            (a.y + b.y) as f64 / 2.0,
+            (a.z + b.z) as f64 / 2.0,
+        );
+        let mut radius = b.sub(&a).length_f64() / 2.0;
+        for corner in corners.iter() {
+            let offset = (
+                corner.x as f64 - center.0,
+                corner.y as f64 - center.1,
+                corner.z as f64 - center.2,
+            );
+            let dist = f64::sqrt(offset.0 * offset.0 + offset.1 * offset.1 + offset.2 * offset.2);
+            if dist > radius {
+                // Grow the sphere just enough to touch the corner, on the opposite side
+                let new_radius = (radius + dist) / 2.0;
+                let shift = (new_radius - radius) / dist;
+                center.0 += offset.0 * shift;
+                center.1 += offset.1 * shift;
+                center.2 += offset.2 * shift;
+                radius = new_radius;
+            }
+        }
+        Vec3 {
+            x: center.0.round() as i64,
+            y: center.1.round() as i64,
+            z: center.2.round() as i64,
+        }
+    }
+
+    // Close to minimal sphere enclosing the corners of the non empty voxels, in space units,
+    // relative to the grid origin and rotated by the grid orientation. Its center is the rotated
+    // GridShape center.
+    pub fn tight_bounding_sphere(&self) -> Sphere {
+        let corners = self.solid_corners();
+        let center = self.orientation.mul_vec(&Self::tight_center(&corners));
+        // Make sure rounding the center and the rotation does not leave any corner out
+        let radius = corners
+            .iter()
+            .map(|corner| self.orientation.mul_vec(corner).sub(&center).length_f64())
+            .fold(0.0, f64::max);
+        Sphere {
+            center,
+            radius: f64::ceil(radius) as i64,
+        }
+    }
+
+    // Coordinates of the voxel containing a position relative to the grid origin. They can be out of
+    // the grid.
+    pub fn voxel_coord_at(&self, pos: &Vec3) -> Vec3 {
+        // Rotations are orthogonal: the transposed orientation reverts it
+        let m = &self.orientation.values;
+        let local = Vec3 {
+            x: (pos.x * m[0] + pos.y * m[3] + pos.z * m[6]) / self.orientation.divider,
+            y: (pos.x * m[1] + pos.y * m[4] + pos.z * m[7]) / self.orientation.divider,
+            z: (pos.x * m[2] + pos.y * m[5] + pos.z * m[8]) / self.orientation.divider,
+        };
+        Vec3 {
+            x: local.x.div_euclid(VOXEL_SIZE),
+            y: local.y.div_euclid(VOXEL_SIZE),
+            z: local.z.div_euclid(VOXEL_SIZE),
+        }
+    }
+
+    // Center of a voxel, relative to the grid origin
+    pub fn voxel_center(&self, coord: &Vec3) -> Vec3 {
+        let half_voxel = Vec3 {
+            x: VOXEL_SIZE / 2,
+            y: VOXEL_SIZE / 2,
+            z: VOXEL_SIZE / 2,
+        };
+        self.orientation
+            .mul_vec(&coord.mul_scalar(VOXEL_SIZE).add(&half_voxel))
+    }
+
+    // Voxels out of the grid are empty
+    pub fn get(&self, coord: &Vec3) -> VoxelType {
+        let size = self.voxels.size() as i64;
+        let in_grid = |v: i64| v >= 0 && v < size;
+        if in_grid(coord.x) && in_grid(coord.y) && in_grid(coord.z) {
+            self.voxels
+                .get((coord.x as usize, coord.y as usize, coord.z as usize))
+        } else {
+            VoxelType::Empty
+        }
+    }
+
+    // Closest empty voxel (possibly out of the grid) reachable through face neighbours
+    pub fn nearest_empty_voxel(&self, from: &Vec3) -> Vec3 {
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        visited.insert(*from);
+        queue.push_back(*from);
+        while let Some(coord) = queue.pop_front() {
+            if self.get(&coord) == VoxelType::Empty {
+                return coord;
+            }
+            for shift in FACE_NEIGHBOURS.iter() {
+                let neighbour = coord.add(shift);
+                if visited.insert(neighbour) {
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+        // Out of the grid voxels are empty, so the search always ends in the loop
+        *from
+    }
+
+    // First solid voxel along the ray, which starts at origin (relative to the grid origin). Walks
+    // the voxels crossed by the ray one by one (Amanatides-Woo traversal).
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: i64) -> Option<(Vec3, VoxelType)> {
+        self.raycast_with_previous(origin, dir, max_dist)
+            .map(|(coord, voxel_type, _)| (coord, voxel_type))
+    }
+
+    // Same as raycast, also returning the voxel the ray crossed right before the hit one, if it is
+    // inside the grid. That is the voxel touching the hit face.
+    pub fn raycast_with_previous(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: i64,
+    ) -> Option<(Vec3, VoxelType, Option<Vec3>)> {
+        let dir_length = dir.length_f64();
+        if dir_length == 0.0 {
+            return None;
+        }
+        // Move the ray to the grid axes, in voxel units
+        let m = &self.orientation.values;
+        let divider = self.orientation.divider as f64;
+        let to_local = |v: &Vec3, scale: f64| {
+            let mut local = [0.0; 3];
+            for (i, component) in local.iter_mut().enumerate() {
+                *component = (v.x * m[i] + v.y * m[3 + i] + v.z * m[6 + i]) as f64 / scale;
+            }
+            local
+        };
+        let origin = to_local(&origin, divider * VOXEL_SIZE as f64);
+        let dir = to_local(&dir, divider * dir_length);
+        let max_dist = max_dist as f64 / VOXEL_SIZE as f64;
+
+        // Part of the ray inside the grid
+        let size = self.voxels.size() as f64;
+        let mut enter = 0.0;
+        let mut exit = max_dist;
+        for axis in 0..3 {
+            if dir[axis] == 0.0 {
+                if origin[axis] < 0.0 || origin[axis] >= size {
+                    return None;
+                }
+            } else {
+                let a = -origin[axis] / dir[axis];
+                let b = (size - origin[axis]) / dir[axis];
+                enter = f64::max(enter, f64::min(a, b));
+                exit = f64::min(exit, f64::max(a, b));
+            }
+        }
+        if enter > exit {
+            return None;
+        }
+
+        let mut voxel = [0; 3];
+        let mut step = [0; 3];
+        let mut next_boundary = [f64::INFINITY; 3];
+        let mut boundary_gap = [f64::INFINITY; 3];
+        for axis in 0..3 {
+            let pos = origin[axis] + dir[axis] * enter;
+            voxel[axis] = (pos.floor() as i64).clamp(0, size as i64 - 1);
+            if dir[axis] > 0.0 {
+                step[axis] = 1;
+                next_boundary[axis] = ((voxel[axis] + 1) as f64 - origin[axis]) / dir[axis];
+                boundary_gap[axis] = 1.0 / dir[axis];
+            } else if dir[axis] < 0.0 {
+                step[axis] = -1;
+                next_boundary[axis] = (voxel[axis] as f64 - origin[axis]) / dir[axis];
+                boundary_gap[axis] = -1.0 / dir[axis];
+            }
+        }
+        let mut previous = None;
+        loop {
+            let coord = Vec3 {
+                x: voxel[0],
+                y: voxel[1],
+                z: voxel[2],
+            };
+            let voxel_type = self.get(&coord);
+            if voxel_type != VoxelType::Empty {
+                return Some((coord, voxel_type, previous));
+            }
+            previous = Some(coord);
+            let axis = (0..3)
+                .min_by(|a, b| next_boundary[*a].partial_cmp(&next_boundary[*b]).unwrap())
+                .unwrap();
+            if next_boundary[axis] > exit {
+                return None;
+            }
+            voxel[axis] += step[axis];
+            next_boundary[axis] += boundary_gap[axis];
+        }
+    }
+
+    // Center of the solid voxel closest to point, both relative to the grid origin
+    pub fn closest_solid_voxel(&self, point: &Vec3) -> Option<Vec3> {
+        self.iter_solid()
+            .map(|((x, y, z), _)| {
+                self.voxel_center(&Vec3 {
+                    x: x as i64,
+                    y: y as i64,
+                    z: z as i64,
+                })
+            })
+            .min_by_key(|center| center.sub(point).length_sq())
+    }
+
+    // Destroys the voxels around an impact point (relative to the grid origin). A voxel breaks if the
+    // energy reaching it, decreasing linearly down to 0 at radius, exceeds its density times
+    // VOXEL_BREAK_ENERGY. Returns the number of destroyed voxels.
+    pub fn apply_impact(
+        &mut self,
+        point: &Vec3,
+        energy: f64,
+        radius: i64,
+        materials: &MaterialTable,
+    ) -> usize {
+        let mut broken = vec![];
+        for ((x, y, z), voxel) in self.iter_solid() {
+            let coord = Vec3 {
+                x: x as i64,
+                y: y as i64,
+                z: z as i64,
+            };
+            let distance = self.voxel_center(&coord).sub(point).length_f64();
+            if distance >= radius as f64 {
+                continue;
+            }
+            let received = energy * (1.0 - distance / radius as f64);
+            if received >= materials.get(voxel).density * VOXEL_BREAK_ENERGY {
+                broken.push((x, y, z));
+            }
+        }
+        for coord in broken.iter() {
+            self.set(*coord, VoxelType::Empty);
+        }
+        broken.len()
+    }
+
+    pub fn mass(&self, materials: &MaterialTable) -> f64 {
+        self.iter_solid()
+            .map(|(_, voxel)| materials.get(voxel).density)
+            .sum()
+    }
+
+    // In voxels, relative to the grid origin
+    pub fn center_of_mass(&self, materials: &MaterialTable) -> (f64, f64, f64) {
+        let mut mass = 0.0;
+        let mut moment = (0.0, 0.0, 0.0);
+        for ((x, y, z), voxel) in self.iter_solid() {
+            let m = materials.get(voxel).density;
+            mass += m;
+            moment.0 += m * (x as f64 + 0.5);
+            moment.1 += m * (y as f64 + 0.5);
+            moment.2 += m * (z as f64 + 0.5);
+        }
+        if mass == 0.0 {
+            return (0.0, 0.0, 0.0);
+        }
+        (moment.0 / mass, moment.1 / mass, moment.2 / mass)
+    }
+
+    // Inertia tensor about the center of mass, with distances in voxels
+    pub fn inertia_tensor(&self, materials: &MaterialTable) -> Mat3 {
+        let center = self.center_of_mass(materials);
+        let mut tensor = [0.0; 9];
+        for ((x, y, z), voxel) in self.iter_solid() {
+            let m = materials.get(voxel).density;
+            let x = x as f64 + 0.5 - center.0;
+            let y = y as f64 + 0.5 - center.1;
+            let z = z as f64 + 0.5 - center.2;
+            // A voxel is a unit cube: it has its own m/6 moment around each of its axes
+            let own = m / 6.0;
+            tensor[0] += m * (y * y + z * z) + own;
+            tensor[4] += m * (x * x + z * z) + own;
+            tensor[8] += m * (x * x + y * y) + own;
+            tensor[1] -= m * x * y;
+            tensor[2] -= m * x * z;
+            tensor[5] -= m * y * z;
+        }
+        tensor[3] = tensor[1];
+        tensor[6] = tensor[2];
+        tensor[7] = tensor[5];
+
+        let mut values = [0; 9];
+        for (value, t) in values.iter_mut().zip(tensor.iter()) {
+            *value = t.round() as i64;
+        }
+        Mat3 { divider: 1, values }
+    }
+}
+
+// Serialization
+impl MaterialTable {
+    pub fn encode(&self, out: &mut Encoder) {
+        out.len(self.materials.len());
+        for material in self.materials.iter() {
+            out.f64(material.density);
+            out.f64(material.friction);
+            out.bool(material.solid);
+        }
+    }
+
+    pub fn decode(input: &mut Decoder) -> Result<Self, DeserializeError> {
+        if input.len()? != NB_VOXEL_TYPES {
+            return Err(DeserializeError::InvalidValue("number of materials"));
+        }
+        let mut table = Self::new();
+        for material in table.materials.iter_mut() {
+            *material = Material {
+                density: input.f64()?,
+                friction: input.f64()?,
+                solid: input.bool()?,
+            };
+        }
+        Ok(table)
+    }
+}
+
+impl VoxelTree {
+    // Bigger grids would overflow their size
+    const MAX_DECODED_SCALE: u32 = 32;
+
+    // Chunks are stored as runs of identical voxels, so that empty chunks take a few bytes
+    pub fn encode(&self, out: &mut Encoder) {
+        match self {
+            Self::Parent(parent) => {
+                out.u8(0);
+                out.u32(parent.scale);
+                out.presence_mask(&parent.sub_cells);
+                for cell in parent.sub_cells.iter().flatten() {
+                    cell.encode(out);
+                }
+            }
+            Self::Chunk(voxels) => {
+                out.u8(1);
+                let mut runs = vec![];
+                for voxel in voxels.iter() {
+                    match runs.last_mut() {
+                        Some((run_voxel, run_len)) if run_voxel == voxel => *run_len += 1,
+                        _ => runs.push((*voxel, 1)),
+                    }
+                }
+                out.len(runs.len());
+                for (voxel, len) in runs.into_iter() {
+                    out.u32(len);
+                    out.u8(voxel.to_index());
+                }
+            }
+        }
+    }
+
+    pub fn decode(input: &mut Decoder) -> Result<Self, DeserializeError> {
+        match input.u8()? {
+            0 => {
+                let scale = input.u32()?;
+                if scale > Self::MAX_DECODED_SCALE {
+                    return Err(DeserializeError::InvalidValue("voxel tree scale"));
+                }
+                let mut parent = VoxelTreeParent::new(scale);
+                let sub_size = parent.size() / 2;
+                let presence = input.presence_mask()?;
+                for (cell, present) in parent.sub_cells.iter_mut().zip(presence.iter()) {
+                    if *present {
+                        let sub_cell = Self::decode(input)?;
+                        if sub_cell.size() != sub_size {
+                            return Err(DeserializeError::InvalidValue("voxel tree scale"));
+                        }
+                        *cell = Some(Box::new(sub_cell));
+                    }
+                }
+                Ok(Self::Parent(parent))
+            }
+            1 => {
+                let mut voxels = Box::new([VoxelType::Empty; NB_VOXELS_PER_CHUNK]);
+                let mut pos = 0;
+                for _ in 0..input.len()? {
+                    let len = input.u32()? as usize;
+                    let voxel = VoxelType::from_index(input.voxel_schema_version, input.u8()?)?;
+                    if pos + len > NB_VOXELS_PER_CHUNK {
+                        return Err(DeserializeError::InvalidValue("chunk size"));
+                    }
+                    for slot in voxels[pos..pos + len].iter_mut() {
+                        *slot = voxel;
+                    }
+                    pos += len;
+                }
+                if pos != NB_VOXELS_PER_CHUNK {
+                    return Err(DeserializeError::InvalidValue("chunk size"));
+                }
+                Ok(Self::Chunk(voxels))
+            }
+            tag => Err(DeserializeError::InvalidTag {
+                what: "voxel tree",
+                tag,
+            }),
+        }
+    }
+}
+
+impl VoxelGridSpace {
+    pub fn encode(&self, out: &mut Encoder) {
+        self.voxels.encode(out);
+        self.local_space.encode(out);
+        out.mat3(&self.orientation);
+        out.bool(self.mass_dirty);
+    }
+
+    pub fn decode(input: &mut Decoder) -> Result<Self, DeserializeError> {
+        let voxels = VoxelTree::decode(input)?;
+        let local_space = MatterTree::decode(input)?;
+        Ok(Self {
+            voxels,
+            local_space,
+            orientation: input.mat3()?,
+            mass_dirty: input.bool()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rock box spanning two chunks along x
+    fn box_grid() -> (VoxelGridSpace, Vec<VoxelCoord>) {
+        let mut chunks = [
+            Box::new([VoxelType::Empty; NB_VOXELS_PER_CHUNK]),
+            Box::new([VoxelType::Empty; NB_VOXELS_PER_CHUNK]),
+        ];
+        let mut coords = vec![];
+        for x in CHUNK_SIZE - 2..CHUNK_SIZE + 2 {
+            for y in 1..3 {
+                for z in 5..7 {
+                    chunks[x / CHUNK_SIZE][chunk_index(x % CHUNK_SIZE, y, z)] = VoxelType::Rock;
+                    coords.push((x, y, z));
+                }
+            }
+        }
+        let [low, high] = chunks;
+        let mut sub_cells = [None, None, None, None, None, None, None, None];
+        sub_cells[Quadrant::XnYnZn as usize] = Some(Box::new(VoxelTree::Chunk(low)));
+        sub_cells[Quadrant::XpYnZn as usize] = Some(Box::new(VoxelTree::Chunk(high)));
+        let mut grid = VoxelGridSpace::new();
+        grid.voxels = VoxelTree::Parent(VoxelTreeParent {
+            scale: 0,
+            sub_cells,
+        });
+        (grid, coords)
+    }
+
+    #[test]
+    fn iter_solid_visits_the_solid_voxels() {
+        let (grid, coords) = box_grid();
+        let mut solid: Vec<_> = grid.iter_solid().collect();
+        solid.sort_unstable_by_key(|(coord, _)| *coord);
+        let expected: Vec<_> = coords
+            .into_iter()
+            .map(|coord| (coord, VoxelType::Rock))
+            .collect();
+        assert_eq!(solid, expected);
+        assert_eq!(VoxelGridSpace::new().iter_solid().count(), 0);
+    }
+
+    fn rock_block(size: (usize, usize, usize)) -> VoxelGridSpace {
+        let mut voxels = Box::new([VoxelType::Empty; NB_VOXELS_PER_CHUNK]);
+        for x in 0..size.0 {
+            for y in 0..size.1 {
+                for z in 0..size.2 {
+                    voxels[chunk_index(x + 3, y + 3, z + 3)] = VoxelType::Rock;
+                }
+            }
+        }
+        let mut grid = VoxelGridSpace::new();
+        grid.voxels = VoxelTree::Chunk(voxels);
+        grid
+    }
+
+    #[test]
+    fn inertia_tensor_of_a_cube_and_a_bar() {
+        let materials = MaterialTable::new();
+        let cube = rock_block((4, 4, 4)).inertia_tensor(&materials);
+        let diagonal = cube.values[0];
+        assert!(diagonal > 0);
+        assert_eq!(
+            cube.values,
+            [diagonal, 0, 0, 0, diagonal, 0, 0, 0, diagonal]
+        );
+
+        let bar = rock_block((8, 2, 2)).inertia_tensor(&materials);
+        assert_eq!(bar.values[4], bar.values[8]);
+        assert!(bar.values[0] < bar.values[4]);
+        assert_eq!([bar.values[1], bar.values[2], bar.values[5]], [0, 0, 0]);
+    }
+
+    #[test]
+    fn tight_bounding_sphere_of_a_rotated_hollow_grid() {
+        // Hollow cube of 6 voxels per side, far from the grid origin
+        let mut voxels = Box::new([VoxelType::Empty; NB_VOXELS_PER_CHUNK]);
+        let (min, side) = (20, 6);
+        for x in min..min + side {
+            for y in min..min + side {
+                for z in min..min + side {
+                    let on_border = |v| v == min || v == min + side - 1;
+                    if on_border(x) || on_border(y) || on_border(z) {
+                        voxels[chunk_index(x, y, z)] = VoxelType::Rock;
+                    }
+                }
+            }
+        }
+        let mut grid = VoxelGridSpace::new();
+        grid.voxels = VoxelTree::Chunk(voxels);
+        // 30 degrees around z, after 45 degrees around x
+        let (sin_z, cos_z) = 30f64.to_radians().sin_cos();
+        let (sin_x, cos_x) = 45f64.to_radians().sin_cos();
+        let rotation = [
+            cos_z,
+            -sin_z * cos_x,
+            sin_z * sin_x,
+            sin_z,
+            cos_z * cos_x,
+            -cos_z * sin_x,
+            0.0,
+            sin_x,
+            cos_x,
+        ];
+        let divider = 1 << 16;
+        let mut values = [0; 9];
+        for (value, r) in values.iter_mut().zip(rotation.iter()) {
+            *value = (r * divider as f64).round() as i64;
+        }
+        grid.orientation = Mat3 { divider, values };
+
+        let sphere = grid.tight_bounding_sphere();
+        for (coord, _) in grid.iter_solid() {
+            for corner in 0..NB_QUADRANTS {
+                let quadrant: Quadrant = num::FromPrimitive::from_usize(corner).unwrap();
+                let corner = Vec3 {
+                    x: (coord.0 + quadrant.x_p() as usize) as i64,
+                    y: (coord.1 + quadrant.y_p() as usize) as i64,
+                    z: (coord.2 + quadrant.z_p() as usize) as i64,
+                };
+                let corner = grid.orientation.mul_vec(&corner.mul_scalar(VOXEL_SIZE));
+                assert!(corner.sub(&sphere.center).length_f64() <= sphere.radius as f64);
+            }
+        }
+        let ideal_radius = (side as i64 * VOXEL_SIZE) as f64 * 3f64.sqrt() / 2.0;
+        assert!((sphere.radius as f64) < ideal_radius * 1.01);
+    }
+
+    #[test]
+    fn first_layout_voxels_decode() {
+        // Under the first schema version, Empty is 0 and Rock is 1
+        assert_eq!(VoxelType::from_index(1, 0), Ok(VoxelType::Empty));
+        assert_eq!(VoxelType::from_index(1, 1), Ok(VoxelType::Rock));
+        for voxel in [VoxelType::Empty, VoxelType::Rock].iter() {
+            assert_eq!(
+                VoxelType::from_index(VOXEL_SCHEMA_VERSION, voxel.to_index()),
+                Ok(*voxel)
+            );
+        }
+
+        assert_eq!(
+            VoxelType::from_index(1, 2),
+            Err(VoxelDecodeError::UnknownVoxelType {
+                version: 1,
+                index: 2
+            })
+        );
+        assert_eq!(
+            VoxelType::from_index(VOXEL_SCHEMA_VERSION + 1, 1),
+            Err(VoxelDecodeError::UnknownSchemaVersion(
+                VOXEL_SCHEMA_VERSION + 1
+            ))
+        );
+        assert_eq!(
+            VoxelType::from_index(0, 1),
+            Err(VoxelDecodeError::UnknownSchemaVersion(0))
+        );
+    }
+
+    #[test]
+    fn material_density_scales_the_mass() {
+        let grid = rock_block((2, 3, 4));
+        let mut materials = MaterialTable::new();
+        assert_eq!(grid.mass(&materials), 24.0);
+
+        let rock = Material {
+            density: 2.5,
+            ..*materials.get(VoxelType::Rock)
+        };
+        materials.set(VoxelType::Rock, rock);
+        assert_eq!(grid.mass(&materials), 60.0);
+        assert_eq!(MaterialTable::new().get(VoxelType::Rock).density, 1.0);
+    }
+
+    #[test]
+    fn voxel_tree_reads_back_what_was_set() {
+        // 4 chunks per side
+        let mut tree = VoxelTree::Parent(VoxelTreeParent::new(1));
+        let coords = [
+            (0, 0, 0),
+            (CHUNK_SIZE - 1, CHUNK_SIZE - 1, CHUNK_SIZE - 1),
+            (CHUNK_SIZE, CHUNK_SIZE - 1, 0),
+            (2 * CHUNK_SIZE - 1, 2 * CHUNK_SIZE, 5),
+            (4 * CHUNK_SIZE - 1, 4 * CHUNK_SIZE - 1, 4 * CHUNK_SIZE - 1),
+            (CHUNK_SIZE + 1, 100, 2 * CHUNK_SIZE),
+        ];
+        for coord in coords.iter() {
+            tree.set(*coord, VoxelType::Rock);
+        }
+        for coord in coords.iter() {
+            assert_eq!(tree.get(*coord), VoxelType::Rock);
+        }
+        assert_eq!(
+            tree.get((CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE)),
+            VoxelType::Empty
+        );
+        // Out of the tree
+        assert_eq!(tree.get((4 * CHUNK_SIZE, 0, 0)), VoxelType::Empty);
+
+        tree.set(coords[2], VoxelType::Empty);
+        assert_eq!(tree.get(coords[2]), VoxelType::Empty);
+        assert_eq!(tree.get(coords[1]), VoxelType::Rock);
+    }
+
+    #[test]
+    fn raycast_along_the_axes() {
+        let mut grid = VoxelGridSpace::new();
+        grid.set((10, 5, 5), VoxelType::Rock);
+        // Center of a voxel
+        let at = |x: i64, y: i64, z: i64| {
+            Vec3 { x, y, z }.mul_scalar(VOXEL_SIZE).add(&Vec3 {
+                x: VOXEL_SIZE / 2,
+                y: VOXEL_SIZE / 2,
+                z: VOXEL_SIZE / 2,
+            })
+        };
+        let hit = Some((Vec3 { x: 10, y: 5, z: 5 }, VoxelType::Rock));
+        assert_eq!(
+            grid.raycast(at(0, 5, 5), Vec3 { x: 1, y: 0, z: 0 }, 2000),
+            hit
+        );
+        assert_eq!(
+            grid.raycast(at(-20, 5, 5), Vec3 { x: 1, y: 0, z: 0 }, 5000),
+            hit
+        );
+        assert_eq!(
+            grid.raycast(at(30, 5, 5), Vec3 { x: -3, y: 0, z: 0 }, 5000),
+            hit
+        );
+        assert_eq!(
+            grid.raycast(at(10, 5, 20), Vec3 { x: 0, y: 0, z: -1 }, 5000),
+            hit
+        );
+        assert_eq!(
+            grid.raycast(at(10, -4, 5), Vec3 { x: 0, y: 1, z: 0 }, 5000),
+            hit
+        );
+
+        // Too short, beside the voxel, or away from it
+        assert_eq!(
+            grid.raycast(at(0, 5, 5), Vec3 { x: 1, y: 0, z: 0 }, 500),
+            None
+        );
+        assert_eq!(
+            grid.raycast(at(0, 6, 5), Vec3 { x: 1, y: 0, z: 0 }, 5000),
+            None
+        );
+        assert_eq!(
+            grid.raycast(at(0, 5, 5), Vec3 { x: -1, y: 0, z: 0 }, 5000),
+            None
+        );
+        assert_eq!(
+            VoxelGridSpace::new().raycast(at(0, 5, 5), Vec3 { x: 1, y: 0, z: 0 }, 5000),
+            None
+        );
     }
 }